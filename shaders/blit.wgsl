@@ -6,8 +6,32 @@ struct CameraUBO {
   img_size   : vec2<u32>,
   frame_index: u32,
   max_bounce : u32,
+  spp        : u32,
+  sampler_kind: u32,
+  rr_depth   : u32,
+  clamp      : f32,
+  spectral   : u32, // 1 if the accumulation holds CIE XYZ
+  aperture_radius: f32,
+  focus_distance : f32,
+  aperture_kind  : u32,
+  aperture_blades: u32,
+  aperture_rotation: f32,
+  focus_pixel: vec2<u32>,
+  fov_y      : f32,
+  exposure   : f32,
+  projection : u32,
+  ortho_height: f32,
+  eye_separation: f32,
+  _pad4 : u32, _pad5 : u32, _pad6 : u32,
 };
 
+// CIE XYZ to linear sRGB, equal-energy white adapted to D65 (Bradford)
+const XYZ_TO_RGB = mat3x3<f32>(
+  vec3<f32>(3.147809, -0.994747, 0.063516),
+  vec3<f32>(-1.662846, 1.953571, -0.214511),
+  vec3<f32>(-0.480574, 0.039740, 1.151595)
+);
+
 @group(0) @binding(0) var<uniform> cam : CameraUBO;
 @group(0) @binding(3) var accum_tex : texture_2d<f32>;
 @group(0) @binding(4) var samp : sampler;
@@ -36,6 +60,7 @@ fn aces_tonemap(x: vec3<f32>) -> vec3<f32> {
 fn fs_main(@builtin(position) pos: vec4<f32>) -> @location(0) vec4<f32> {
   let uv = pos.xy / vec2<f32>(f32(cam.img_size.x), f32(cam.img_size.y));
   var color = textureSampleLevel(accum_tex, samp, uv, 0.0).rgb;
+  if (cam.spectral != 0u) { color = max(XYZ_TO_RGB * color, vec3<f32>(0.0)); }
   color = aces_tonemap(color);
   color = pow(color, vec3<f32>(1.0/2.2));
   return vec4<f32>(color, 1.0);
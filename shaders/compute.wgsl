@@ -7,58 +7,479 @@ struct CameraUBO {
   img_size   : vec2<u32>,
   frame_index: u32,
   max_bounce : u32,
+  spp        : u32,
+  sampler_kind: u32, // SAMPLER_*
+  rr_depth   : u32, // bounces before Russian roulette may end a path
+  clamp      : f32, // maximum throughput component, 0 for none
+  spectral   : u32, // 1 to trace wavelengths and accumulate CIE XYZ
+  aperture_radius: f32, // lens radius, 0 for a pinhole
+  focus_distance : f32, // of the plane in focus, along `dir`
+  aperture_kind  : u32, // APERTURE_*
+  aperture_blades: u32, // corners of APERTURE_POLYGON
+  aperture_rotation: f32,
+  focus_pixel: vec2<u32>, // pixel cs_focus looks through
+  fov_y      : f32, // vertical field of view in radians
+  exposure   : f32, // scales the radiance reaching the film
+  projection : u32, // PROJECTION_*
+  ortho_height: f32, // of the orthographic view in world units
+  eye_separation: f32, // of a stereo panorama, 0 for a single one
+  _pad4 : u32, _pad5 : u32, _pad6 : u32,
 };
 
 @group(0) @binding(0) var<uniform> cam : CameraUBO;
 @group(0) @binding(1) var accum_in  : texture_storage_2d<rgba16float, read>;
 @group(0) @binding(2) var accum_out : texture_storage_2d<rgba16float, write>;
+@group(0) @binding(3) var blue_noise_tile : texture_2d<u32>; // two tiles of ranks as fractions of 2^32
+@group(0) @binding(4) var focus_out : texture_storage_2d<r32float, write>; // 1x1, written by cs_focus
 
-// === Utils ===
-fn rand(hash: vec2<u32>) -> f32 {
-  var x = hash.x * 1664525u + 1013904223u + hash.y * 747796405u;
-  x = (x ^ (x >> 16u)) * 2246822519u;
-  x ^= (x >> 13u);
-  let f = f32(x & 0x00FFFFFFu) / f32(0x01000000u);
-  return clamp(f, 0.0, 0.999999);
+// === Sampler ===
+// Every random number of a path is dimension `dim` of sample number
+// frame_index * spp + s of the pixel. Dimensions come in pairs, each pair
+// drawn from its own shuffled, Owen-scrambled 2D Sobol sequence (Burley,
+// "Practical Hash-based Owen Scrambling", 2020), from PCG, or from the R2
+// rank-1 lattice shifted by blue noise.
+const SAMPLER_INDEPENDENT: u32 = 0u;
+const SAMPLER_SOBOL: u32 = 1u;
+const SAMPLER_BLUE_NOISE: u32 = 2u;
+
+// dimension pairs: the pixel position, the wavelengths, the lens
+// position, then DIMS_PER_BOUNCE per path segment
+const DIM_PIXEL: u32 = 0u;
+const DIM_WAVELENGTH: u32 = 1u;
+const DIM_LENS: u32 = 2u;
+const DIM_BOUNCE: u32 = 3u;
+const DIMS_PER_BOUNCE: u32 = 6u;
+const DIM_LIGHT_PICK: u32 = 0u;
+const DIM_LIGHT: u32 = 1u;
+const DIM_BSDF_LOBE: u32 = 2u;
+const DIM_BSDF: u32 = 3u;
+const DIM_ROULETTE: u32 = 4u;
+const DIM_MEDIUM: u32 = 5u;
+
+var<private> pixel_seed: u32;
+var<private> pixel: vec2<u32>;
+var<private> sample_index: u32;
+
+fn pcg_hash(v: u32) -> u32 {
+  let state = v * 747796405u + 2891336453u;
+  let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+  return (word >> 22u) ^ word;
+}
+
+fn hash_combine(seed: u32, v: u32) -> u32 {
+  return seed ^ (v + (seed << 6u) + (seed >> 2u));
+}
+
+fn sampler_start(px: vec2<u32>, index: u32) {
+  pixel = px;
+  pixel_seed = pcg_hash(px.x ^ pcg_hash(px.y));
+  sample_index = index;
+}
+
+fn laine_karras_permutation(x_in: u32, seed: u32) -> u32 {
+  var x = x_in + seed;
+  x ^= x * 0x6c50b47cu;
+  x ^= x * 0xb82f1e52u;
+  x ^= x * 0xc7afe638u;
+  x ^= x * 0x8d22f6e6u;
+  return x;
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+  return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+// Second Sobol dimension; the first is reverseBits(i).
+fn sobol_dim1(i_in: u32) -> u32 {
+  var i = i_in;
+  var v = 0x80000000u;
+  var r = 0u;
+  while (i != 0u) {
+    if ((i & 1u) != 0u) { r ^= v; }
+    i = i >> 1u;
+    v ^= v >> 1u;
+  }
+  return r;
+}
+
+fn to_unit_float(x: u32) -> f32 {
+  return f32(x >> 8u) * (1.0 / 16777216.0);
+}
+
+// Every pixel walks the same R2 sequence (Roberts 2018), rotated by a
+// blue-noise value: neighbouring pixels get far-apart points of each
+// dimension, so the error of a few samples is blue noise. The tile is
+// shifted differently for every dimension pair to decorrelate them.
+fn blue_noise(dim: u32) -> vec2<f32> {
+  let offset = pcg_hash(dim * 0x9e3779b9u + 1u);
+  let size = textureDimensions(blue_noise_tile);
+  let texel = (pixel + vec2<u32>(offset, offset >> 16u)) % size;
+  let shift = textureLoad(blue_noise_tile, vec2<i32>(texel), 0).xy;
+  // 2^32 / plastic number and its square, so the sum wraps exactly
+  let r2 = vec2<u32>(sample_index * 3242174889u, sample_index * 2447445414u);
+  return vec2<f32>(to_unit_float(r2.x + shift.x + offset), to_unit_float(r2.y + shift.y + pcg_hash(offset)));
+}
+
+fn sample_2d(dim: u32) -> vec2<f32> {
+  if (cam.sampler_kind == SAMPLER_BLUE_NOISE) { return blue_noise(dim); }
+  let seed = pcg_hash(hash_combine(pixel_seed, dim));
+  if (cam.sampler_kind == SAMPLER_INDEPENDENT) {
+    let a = pcg_hash(seed ^ pcg_hash(sample_index));
+    return vec2<f32>(to_unit_float(a), to_unit_float(pcg_hash(a)));
+  }
+  let i = nested_uniform_scramble(sample_index, seed);
+  return vec2<f32>(
+    to_unit_float(nested_uniform_scramble(reverseBits(i), hash_combine(seed, 1u))),
+    to_unit_float(nested_uniform_scramble(sobol_dim1(i), hash_combine(seed, 2u)))
+  );
+}
+
+fn sample_1d(dim: u32) -> f32 {
+  return sample_2d(dim).x;
 }
 
-struct Hit { dist: f32, n: vec3<f32>, albedo: vec3<f32>, emissive: vec3<f32>, mat: f32 }
+// Seed of a PCG stream for loops that need an open-ended number of
+// random numbers, such as delta tracking.
+fn sample_stream(dim: u32) -> u32 {
+  return pcg_hash(hash_combine(pixel_seed, dim) ^ pcg_hash(sample_index));
+}
+
+fn stream_next(state: ptr<function, u32>) -> f32 {
+  *state = pcg_hash(*state);
+  return to_unit_float(*state);
+}
+
+// === Spectra ===
+// In spectral mode the three channels of every colour are a path's three
+// wavelengths (`lambdas`, nm) rather than red, green and blue. Scene colours
+// are upsampled with Smits' method ("An RGB-to-spectrum conversion for
+// reflectances", 1999) where they enter the path, and each sample is
+// converted to CIE XYZ at the end.
+var<private> lambdas: vec3<f32>;
+
+// Smits' basis spectra in ten bins from 380 to 720 nm: (white, cyan,
+// magenta, yellow) and (red, green, blue, -); private so they can be
+// indexed at run time.
+var<private> smits_wcmy: array<vec4<f32>, 10> = array<vec4<f32>, 10>(
+  vec4<f32>(1.0000, 0.9710, 1.0000, 0.0001),
+  vec4<f32>(1.0000, 0.9426, 1.0000, 0.0000),
+  vec4<f32>(0.9999, 1.0007, 0.9685, 0.1088),
+  vec4<f32>(0.9993, 1.0007, 0.2229, 0.6651),
+  vec4<f32>(0.9992, 1.0007, 0.0000, 1.0000),
+  vec4<f32>(0.9998, 1.0007, 0.0458, 1.0000),
+  vec4<f32>(1.0000, 0.1564, 0.8369, 0.9996),
+  vec4<f32>(1.0000, 0.0000, 1.0000, 0.9586),
+  vec4<f32>(1.0000, 0.0000, 1.0000, 0.9685),
+  vec4<f32>(1.0000, 0.0000, 0.9959, 0.9840)
+);
+var<private> smits_rgb: array<vec4<f32>, 10> = array<vec4<f32>, 10>(
+  vec4<f32>(0.1012, 0.0000, 1.0000, 0.0),
+  vec4<f32>(0.0515, 0.0000, 1.0000, 0.0),
+  vec4<f32>(0.0000, 0.0273, 0.8916, 0.0),
+  vec4<f32>(0.0000, 0.7937, 0.3323, 0.0),
+  vec4<f32>(0.0000, 1.0000, 0.0000, 0.0),
+  vec4<f32>(0.0000, 0.9418, 0.0000, 0.0),
+  vec4<f32>(0.8325, 0.1719, 0.0003, 0.0),
+  vec4<f32>(1.0149, 0.0000, 0.0369, 0.0),
+  vec4<f32>(1.0149, 0.0000, 0.0483, 0.0),
+  vec4<f32>(1.0149, 0.0025, 0.0496, 0.0)
+);
+
+fn smits(c: vec3<f32>, lambda: f32) -> f32 {
+  let bin = u32(clamp((lambda - 380.0) / 34.0, 0.0, 9.0));
+  let wcmy = smits_wcmy[bin];
+  let rgb = smits_rgb[bin];
+  if (c.r <= c.g && c.r <= c.b) {
+    if (c.g <= c.b) { return c.r * wcmy.x + (c.g - c.r) * wcmy.y + (c.b - c.g) * rgb.z; }
+    return c.r * wcmy.x + (c.b - c.r) * wcmy.y + (c.g - c.b) * rgb.y;
+  }
+  if (c.g <= c.r && c.g <= c.b) {
+    if (c.r <= c.b) { return c.g * wcmy.x + (c.r - c.g) * wcmy.z + (c.b - c.r) * rgb.z; }
+    return c.g * wcmy.x + (c.b - c.g) * wcmy.z + (c.r - c.b) * rgb.x;
+  }
+  if (c.r <= c.g) { return c.b * wcmy.x + (c.r - c.b) * wcmy.w + (c.g - c.r) * rgb.y; }
+  return c.b * wcmy.x + (c.g - c.b) * wcmy.w + (c.r - c.g) * rgb.x;
+}
 
-fn sphere_hit(ro: vec3<f32>, rd: vec3<f32>, c: vec3<f32>, r: f32,
-              albedo: vec3<f32>, emissive: vec3<f32>, mat: f32) -> Hit {
+// A scene colour as the path carries it: unchanged in RGB mode, its
+// spectrum at `lambdas` in spectral mode.
+fn spectrum(c: vec3<f32>) -> vec3<f32> {
+  if (cam.spectral == 0u) { return c; }
+  return max(vec3<f32>(smits(c, lambdas.x), smits(c, lambdas.y), smits(c, lambdas.z)), vec3<f32>(0.0));
+}
+
+// Wavelengths from 360 to 830 nm, importance sampled towards the visible
+// (pbrt-v4's SampleVisibleWavelengths); three strata per path.
+fn sample_wavelengths(u: f32) -> vec3<f32> {
+  let v = fract(vec3<f32>(u, u + 1.0 / 3.0, u + 2.0 / 3.0));
+  return 538.0 - 138.888889 * atanh(0.85691062 - 1.82750197 * v);
+}
+
+fn wavelength_pdf(lambda: vec3<f32>) -> vec3<f32> {
+  let c = cosh(0.0072 * (lambda - 538.0));
+  return 0.0039398042 / (c * c);
+}
+
+// CIE 1931 colour matching functions, multi-lobe fit of Wyman, Sloan and
+// Shirley (2013).
+fn cie_lobe(lambda: f32, mu: f32, s1: f32, s2: f32) -> f32 {
+  let t = (lambda - mu) / select(s2, s1, lambda < mu);
+  return exp(-0.5 * t * t);
+}
+
+fn cie_xyz(lambda: f32) -> vec3<f32> {
+  return vec3<f32>(
+    1.056 * cie_lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * cie_lobe(lambda, 442.0, 16.0, 26.7)
+      - 0.065 * cie_lobe(lambda, 501.1, 20.4, 26.2),
+    0.821 * cie_lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * cie_lobe(lambda, 530.9, 16.3, 31.1),
+    1.217 * cie_lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * cie_lobe(lambda, 459.0, 26.0, 13.8)
+  );
+}
+
+// Integral of the fitted y over 360-830 nm: a flat spectrum of one has Y = 1.
+const CIE_Y_INTEGRAL: f32 = 106.922;
+
+// XYZ of a path sample from its radiance at `lambdas`.
+fn spectral_to_xyz(l: vec3<f32>) -> vec3<f32> {
+  let w = l / wavelength_pdf(lambdas);
+  return (cie_xyz(lambdas.x) * w.x + cie_xyz(lambdas.y) * w.y + cie_xyz(lambdas.z) * w.z) / (3.0 * CIE_Y_INTEGRAL);
+}
+
+// IOR of dispersive glass at `lambda` nm; params as in `Material`.
+fn glass_ior(params: array<vec4<f32>, 3>, lambda: f32) -> f32 {
+  let l2 = lambda * lambda * 1e-6; // µm²
+  if (params[0].z == 1.0) { return params[1].x + params[1].y / l2; }
+  let b = params[1].xyz;
+  let c = params[2].xyz;
+  return sqrt(1.0 + dot(b * l2, 1.0 / (vec3<f32>(l2) - c)));
+}
+
+// Surface at the nearest hit; albedo and emission are already textured.
+struct Hit {
+  dist    : f32,
+  n       : vec3<f32>,
+  albedo  : vec3<f32>,
+  emissive: vec3<f32>,
+  kind    : u32,
+  params  : array<vec4<f32>, 3>,
+  light   : u32, // index into `lights`, NO_LIGHT if not an emitter
+  medium  : u32, // medium inside the surface, NO_MEDIUM if none
+};
+
+// === Scene ===
+const PRIM_SPHERE: u32 = 0u;
+const PRIM_QUAD: u32 = 1u;
+const PRIM_DISK: u32 = 2u;
+
+struct SceneUBO {
+  num_prims: u32,
+  num_tris : u32,
+  num_instances: u32,
+  num_lights: u32,
+  num_infinite_lights: u32, // environment and directional lights, first in `lights`
+  atmosphere: u32,          // medium filling all space, NO_MEDIUM for vacuum
+  aperture_mask: vec2<u32>, // size of the aperture mask, zero without one
+  env: vec4<f32>,           // intensity, cos and sin of the rotation about +Y, mean sampling weight
+  media: array<Medium, MAX_MEDIA>,
+};
+
+const MAX_MEDIA: u32 = 16u;
+const NO_MEDIUM: u32 = 0xffffffffu;
+
+// Coefficients per unit length, scaled by the density grid if there is one.
+struct Medium {
+  sigma_a: vec4<f32>, // absorption + Henyey-Greenstein g
+  sigma_s: vec4<f32>, // scattering
+  grid_min: vec4<f32>,
+  grid_max: vec4<f32>,
+  grid: vec4<u32>,    // resolution, first slice in `density_grids`; all zero if homogeneous
+};
+
+struct Prim {
+  kind    : u32,
+  material: u32,
+  light   : u32, // NO_LIGHT unless emissive
+  _pad0   : u32,
+  a: vec4<f32>, // sphere: center + radius | quad: corner | disk: center + radius
+  b: vec4<f32>, // quad: edge u | disk: normal
+  c: vec4<f32>, // quad: edge v
+};
+
+const MAT_DIFFUSE  : u32 = 0u;
+const MAT_MIRROR   : u32 = 1u;
+const MAT_GLASS    : u32 = 2u;
+const MAT_CONDUCTOR: u32 = 3u;
+const MAT_PRINCIPLED: u32 = 4u;
+const MAT_INTERFACE: u32 = 5u; // invisible boundary of `medium`
+
+struct Material {
+  albedo  : vec3<f32>,
+  kind    : u32, // MAT_*
+  emissive: vec3<f32>,
+  normal_scale: f32,
+  // per kind
+  //   glass:     [0] = (IOR, GGX alpha, dispersion: 0 none, 1 Cauchy, 2 Sellmeier, -),
+  //              [1] = Cauchy (a, b, -, -) or Sellmeier (b1, b2, b3, -), [2] = Sellmeier (c1, c2, c3, -)
+  //   conductor: [0] = (eta.rgb, GGX alpha), [1] = (k.rgb, 1 if eta/k are set, else albedo is F0)
+  //   principled: [0] = (metallic, roughness, specular, specular tint),
+  //               [1] = (clearcoat, clearcoat gloss, sheen, sheen tint),
+  //               [2] = (transmission, subsurface, IOR, -)
+  params  : array<vec4<f32>, 3>,
+
+  textures: vec4<u32>, // layers: albedo, normal, emission, metallic-roughness; NO_TEXTURE if absent
+  medium  : u32,       // inside, NO_MEDIUM if none
+  _pad0: u32, _pad1: u32, _pad2: u32,
+};
+
+const NO_TEXTURE: u32 = 0xffffffffu;
+
+@group(1) @binding(0) var<uniform> scene : SceneUBO;
+@group(1) @binding(1) var<storage, read> prims : array<Prim>;
+struct Vertex {
+  position_u: vec4<f32>, // xyz + uv.x
+  normal_v  : vec4<f32>, // xyz (zero if absent) + uv.y
+};
+
+struct Tri {
+  v       : vec3<u32>,
+  material: u32,
+  // rank among the mesh's emissive triangles, NO_LIGHT if not emissive
+  emitter : u32,
+  _pad0: u32, _pad1: u32, _pad2: u32,
+};
+
+@group(1) @binding(2) var<storage, read> materials : array<Material>;
+@group(1) @binding(3) var<storage, read> vertices : array<Vertex>;
+// Depth-first: the left child follows its parent, `left_or_first` is the
+// right child for interior nodes (count == 0) or the first item of a leaf.
+// Node 0 roots the instance BVH (items are instances); each mesh BVH
+// follows it (items are triangles).
+struct BvhNode {
+  min          : vec3<f32>,
+  left_or_first: u32,
+  max          : vec3<f32>,
+  count        : u32,
+};
+
+@group(1) @binding(4) var<storage, read> tris : array<Tri>;
+@group(1) @binding(5) var<storage, read> bvh_nodes : array<BvhNode>;
+
+struct Instance {
+  world_to_object: mat4x4<f32>,
+  object_to_world: mat4x4<f32>,
+  blas_root: u32,
+  light_base: u32, // light of the instance's first emissive triangle
+  _pad0: u32, _pad1: u32,
+};
+
+@group(1) @binding(6) var<storage, read> instances : array<Instance>;
+@group(1) @binding(7) var textures : texture_2d_array<f32>;
+@group(1) @binding(8) var tex_sampler : sampler;
+
+// Lights for next-event estimation: emissive surfaces in world space, point,
+// spot and directional lights that rays cannot hit, and the environment.
+const LIGHT_SPHERE: u32 = 0u;
+const LIGHT_QUAD: u32 = 1u;
+const LIGHT_TRIANGLE: u32 = 2u;
+const LIGHT_DISK: u32 = 3u;
+const LIGHT_POINT: u32 = 4u;
+const LIGHT_SPOT: u32 = 5u;
+const LIGHT_DIRECTIONAL: u32 = 6u;
+const LIGHT_ENVIRONMENT: u32 = 7u;
+const NO_LIGHT: u32 = 0xffffffffu;
+
+struct Light {
+  kind: u32,
+  node: u32, // leaf in the light BVH
+  _pad0: u32, _pad1: u32,
+  a: vec4<f32>, // sphere, disk: center + radius | quad: corner | triangle: p0 | point, spot: position
+  b: vec4<f32>, // quad: edge u | triangle: p1 - p0 | disk: normal | spot: direction + cos(inner) | directional: direction
+  c: vec4<f32>, // quad: edge v | triangle: p2 - p0 | spot: cos(outer) | directional: (cos, 1 - cos) of the angular radius
+  emission: vec4<f32>, // point, spot, directional; surfaces use their material
+};
+
+@group(1) @binding(9) var<storage, read> lights : array<Light>;
+
+// Light BVH over all but the infinite lights, laid out like BvhNode; leaves
+// hold one light. Each node bounds the position, power and emission
+// directions of its lights.
+const LIGHT_NODE_LEAF: u32 = 1u;
+const LIGHT_NODE_TWO_SIDED: u32 = 2u;
+
+struct LightNode {
+  min           : vec3<f32>,
+  phi           : f32,
+  max           : vec3<f32>,
+  cos_theta_o   : f32,
+  w             : vec3<f32>,
+  cos_theta_e   : f32,
+  child_or_light: u32,
+  flags         : u32,
+  parent        : u32,
+  _pad0         : u32,
+};
+
+@group(1) @binding(10) var<storage, read> light_nodes : array<LightNode>;
+
+// Equirectangular environment: the top row is +Y, the centre column -Z
+// before rotation. env_cdf has a row per image row holding that row's
+// cumulative distribution, then one row with the distribution over rows.
+@group(1) @binding(11) var env_map : texture_2d<f32>;
+@group(1) @binding(12) var env_cdf : texture_2d<f32>;
+// Density grids stacked along z, and per medium a block of MAJORANT_CELLS³
+// upper bounds of its density, in the order of `scene.media`.
+@group(1) @binding(13) var density_grids : texture_3d<f32>;
+@group(1) @binding(14) var majorant_grids : texture_3d<f32>;
+// Sampling distribution of the aperture mask, laid out like env_cdf.
+@group(1) @binding(15) var aperture_cdf : texture_2d<f32>;
+const MAJORANT_CELLS: i32 = 16;
+
+fn sample_texture(layer: u32, uv: vec2<f32>) -> vec4<f32> {
+  return textureSampleLevel(textures, tex_sampler, uv, layer, 0.0);
+}
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+  return select(pow((c + 0.055) / 1.055, vec3<f32>(2.4)), c / 12.92, c <= vec3<f32>(0.04045));
+}
+
+// Disk around c facing n (normalised), radius r; hit from either side.
+fn disk_hit(ro: vec3<f32>, rd: vec3<f32>, c: vec3<f32>, n: vec3<f32>, r: f32) -> f32 {
+  let denom = dot(n, rd);
+  if (abs(denom) < 1e-8) { return 1e30; }
+  let t = dot(n, c - ro) / denom;
+  if (t < 1e-3) { return 1e30; }
+  let d = ro + rd * t - c;
+  if (dot(d, d) > r * r) { return 1e30; }
+  return t;
+}
+
+// Nearest t > 1e-3, also from inside the sphere (glass), or 1e30.
+fn sphere_hit(ro: vec3<f32>, rd: vec3<f32>, c: vec3<f32>, r: f32) -> f32 {
   let oc = ro - c;
   let b = dot(oc, rd);
   let c2 = dot(oc, oc) - r*r;
   let h = b*b - c2;
-  if (h < 0.0) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  let t = -b - sqrt(h);
-  if (t < 1e-3) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  let p = ro + rd * t;
-  let n = normalize(p - c);
-  return Hit(t, n, albedo, emissive, mat);
-}
-
-// Planes: box is x in [-3,3], y in [0,5], z in [-3,3]
-fn plane_hit_y(ro: vec3<f32>, rd: vec3<f32>, y: f32,
-               n: vec3<f32>, albedo: vec3<f32>, emissive: vec3<f32>) -> Hit {
-  if (abs(rd.y) < 1e-4) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  let t = (y - ro.y) / rd.y;
-  if (t < 1e-3) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  return Hit(t, n, albedo, emissive, 0.0);
-}
-fn plane_hit_x(ro: vec3<f32>, rd: vec3<f32>, x: f32,
-               n: vec3<f32>, albedo: vec3<f32>) -> Hit {
-  if (abs(rd.x) < 1e-4) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  let t = (x - ro.x) / rd.x;
-  if (t < 1e-3) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  return Hit(t, n, albedo, vec3<f32>(0.0), 0.0);
-}
-fn plane_hit_z(ro: vec3<f32>, rd: vec3<f32>, z: f32,
-               n: vec3<f32>, albedo: vec3<f32>) -> Hit {
-  if (abs(rd.z) < 1e-4) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  let t = (z - ro.z) / rd.z;
-  if (t < 1e-3) { return Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0); }
-  return Hit(t, n, albedo, vec3<f32>(0.0), 0.0);
+  if (h < 0.0) { return 1e30; }
+  let sq = sqrt(h);
+  var t = -b - sq;
+  if (t < 1e-3) { t = -b + sq; }
+  if (t < 1e-3) { return 1e30; }
+  return t;
+}
+
+// Parallelogram q + a*u + b*v, a,b in [0,1]; hit from either side.
+fn quad_hit(ro: vec3<f32>, rd: vec3<f32>, q: vec3<f32>, u: vec3<f32>, v: vec3<f32>) -> f32 {
+  let n = cross(u, v);
+  let denom = dot(n, rd);
+  if (abs(denom) < 1e-8) { return 1e30; }
+  let t = dot(n, q - ro) / denom;
+  if (t < 1e-3) { return 1e30; }
+  let w = n / dot(n, n);
+  let pq = ro + rd * t - q;
+  let alpha = dot(w, cross(pq, v));
+  let beta = dot(w, cross(u, pq));
+  if (alpha < 0.0 || alpha > 1.0 || beta < 0.0 || beta > 1.0) { return 1e30; }
+  return t;
 }
 
 fn onb(n: vec3<f32>) -> mat3x3<f32> {
@@ -69,72 +490,1390 @@ fn onb(n: vec3<f32>) -> mat3x3<f32> {
 }
 fn cosine_sample_hemisphere(u: f32, v: f32) -> vec3<f32> {
   let r = sqrt(u);
-  let theta = 6.2831853 * v;
+  let theta = 2.0 * PI * v;
   let x = r * cos(theta);
   let y = r * sin(theta);
   let z = sqrt(max(0.0, 1.0 - u));
   return vec3<f32>(x, y, z);
 }
 
-// Refraction + Fresnel (for glass)
+// Refraction + Fresnel (for glass); eta = n_incident / n_transmitted
 fn refract_ray(v: vec3<f32>, n: vec3<f32>, eta: f32) -> vec3<f32> {
   let cosi = clamp(dot(-v, n), -1.0, 1.0);
   let cost2 = 1.0 - eta*eta*(1.0 - cosi*cosi);
   if (cost2 < 0.0) { return reflect(v, n); }
   return normalize(eta*v + (eta*cosi - sqrt(cost2))*n);
 }
-fn schlick_fresnel(cos_theta: f32, ior: f32) -> f32 {
-  let r0 = pow((1.0 - ior) / (1.0 + ior), 2.0);
-  return r0 + (1.0 - r0) * pow(1.0 - cos_theta, 5.0);
+// Unpolarised reflectance; eta = n_transmitted / n_incident, 1 on total
+// internal reflection.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let sin2_t = (1.0 - cos_i*cos_i) / (eta*eta);
+  if (sin2_t >= 1.0) { return 1.0; }
+  let cos_t = sqrt(1.0 - sin2_t);
+  let rs = (cos_i - eta*cos_t) / (cos_i + eta*cos_t);
+  let rp = (eta*cos_i - cos_t) / (eta*cos_i + cos_t);
+  return 0.5 * (rs*rs + rp*rp);
 }
 
-// === Cornell Box config ===
-const BOX_MIN: vec3<f32> = vec3<f32>(-3.0, 0.0, -3.0);
-const BOX_MAX: vec3<f32> = vec3<f32>( 3.0, 5.0,  3.0);
-// emissive rectangle on ceiling (y=BOX_MAX.y), X/Z in [-L,L]
-const L_LIGHT: f32 = 1.0;
+// Watertight ray/triangle test (Woop, Benthin, Wald 2013). The ray is
+// sheared once per query so the test is shared by all triangles.
+struct TriRay { kx: u32, ky: u32, kz: u32, shear: vec3<f32> };
+
+fn tri_ray_setup(rd: vec3<f32>) -> TriRay {
+  let ad = abs(rd);
+  var kz = 0u;
+  if (ad.y > ad.x) { kz = 1u; }
+  if (ad.z > ad[kz]) { kz = 2u; }
+  var kx = (kz + 1u) % 3u;
+  var ky = (kx + 1u) % 3u;
+  // keep the winding direction
+  if (rd[kz] < 0.0) { let tmp = kx; kx = ky; ky = tmp; }
+  return TriRay(kx, ky, kz, vec3<f32>(rd[kx] / rd[kz], rd[ky] / rd[kz], 1.0 / rd[kz]));
+}
+
+// Returns (t, b1, b2) with t = 1e30 on a miss; b1/b2 weight vertices 1 and 2.
+fn tri_hit(ro: vec3<f32>, tr: TriRay, p0: vec3<f32>, p1: vec3<f32>, p2: vec3<f32>) -> vec3<f32> {
+  let miss = vec3<f32>(1e30, 0.0, 0.0);
+  let a = p0 - ro;
+  let b = p1 - ro;
+  let c = p2 - ro;
+  let ax = a[tr.kx] - tr.shear.x * a[tr.kz];
+  let ay = a[tr.ky] - tr.shear.y * a[tr.kz];
+  let bx = b[tr.kx] - tr.shear.x * b[tr.kz];
+  let by = b[tr.ky] - tr.shear.y * b[tr.kz];
+  let cx = c[tr.kx] - tr.shear.x * c[tr.kz];
+  let cy = c[tr.ky] - tr.shear.y * c[tr.kz];
+  let u = cx * by - cy * bx;
+  let v = ax * cy - ay * cx;
+  let w = bx * ay - by * ax;
+  if ((u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0)) { return miss; }
+  let det = u + v + w;
+  if (det == 0.0) { return miss; }
+  let az = tr.shear.z * a[tr.kz];
+  let bz = tr.shear.z * b[tr.kz];
+  let cz = tr.shear.z * c[tr.kz];
+  let t = (u * az + v * bz + w * cz) / det;
+  if (t < 1e-3) { return miss; }
+  return vec3<f32>(t, v / det, w / det);
+}
+
+// Entry distance into the box (0 if inside), or 1e30 on a miss or beyond t_max.
+fn aabb_hit(ro: vec3<f32>, inv_rd: vec3<f32>, bmin: vec3<f32>, bmax: vec3<f32>, t_max: f32) -> f32 {
+  let t0 = (bmin - ro) * inv_rd;
+  let t1 = (bmax - ro) * inv_rd;
+  let tmin = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+  let tmax = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+  if (tmax >= max(tmin, 0.0) && tmin < t_max) { return max(tmin, 0.0); }
+  return 1e30;
+}
+
+fn safe_inverse(d: vec3<f32>) -> vec3<f32> {
+  return 1.0 / select(d, vec3<f32>(1e-12), abs(d) < vec3<f32>(1e-12));
+}
+
+const BVH_STACK_SIZE: u32 = 64u;
+
+struct TriHit { t: f32, tri: u32, bary: vec2<f32> };
+
+// Nearest triangle closer than t_max in the mesh BVH at `root`, traversed
+// nearest child first. The ray is in the mesh's object space.
+fn hit_blas(ro: vec3<f32>, rd: vec3<f32>, root: u32, t_max: f32) -> TriHit {
+  var best = TriHit(t_max, 0xffffffffu, vec2<f32>(0.0));
+  let inv_rd = safe_inverse(rd);
+  if (aabb_hit(ro, inv_rd, bvh_nodes[root].min, bvh_nodes[root].max, t_max) == 1e30) { return best; }
+  let tr = tri_ray_setup(rd);
+  var stack: array<u32, BVH_STACK_SIZE>;
+  var stack_t: array<f32, BVH_STACK_SIZE>;
+  var sp = 0u;
+  var node_idx = root;
+  loop {
+    let node = bvh_nodes[node_idx];
+    if (node.count > 0u) {
+      for (var i = node.left_or_first; i < node.left_or_first + node.count; i = i + 1u) {
+        let tri = tris[i];
+        let h = tri_hit(ro, tr, vertices[tri.v.x].position_u.xyz,
+                        vertices[tri.v.y].position_u.xyz, vertices[tri.v.z].position_u.xyz);
+        if (h.x < best.t) {
+          best = TriHit(h.x, i, h.yz);
+        }
+      }
+    } else {
+      let l = node_idx + 1u;
+      let r = node.left_or_first;
+      let tl = aabb_hit(ro, inv_rd, bvh_nodes[l].min, bvh_nodes[l].max, best.t);
+      let tr_ = aabb_hit(ro, inv_rd, bvh_nodes[r].min, bvh_nodes[r].max, best.t);
+      if (tl < 1e30 && tr_ < 1e30) {
+        // visit the nearer child now, the other one later
+        if (tl <= tr_) {
+          stack[sp] = r; stack_t[sp] = tr_;
+          node_idx = l;
+        } else {
+          stack[sp] = l; stack_t[sp] = tl;
+          node_idx = r;
+        }
+        sp = min(sp + 1u, BVH_STACK_SIZE - 1u);
+        continue;
+      }
+      if (tl < 1e30) { node_idx = l; continue; }
+      if (tr_ < 1e30) { node_idx = r; continue; }
+    }
+    // pop the next subtree that can still contain a closer hit
+    var found = false;
+    while (sp > 0u) {
+      sp = sp - 1u;
+      if (stack_t[sp] < best.t) {
+        node_idx = stack[sp];
+        found = true;
+        break;
+      }
+    }
+    if (!found) { break; }
+  }
+  return best;
+}
+
+fn linear_part(m: mat4x4<f32>) -> mat3x3<f32> {
+  return mat3x3<f32>(m[0].xyz, m[1].xyz, m[2].xyz);
+}
 
 fn hit_scene(ro: vec3<f32>, rd: vec3<f32>) -> Hit {
-  var best = Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), 0.0);
-
-  // Spheres
-  let s_diff = sphere_hit(ro, rd, vec3<f32>(-1.0, 1.0, 0.0), 1.0,
-                          vec3<f32>(0.9, 0.25, 0.25), vec3<f32>(0.0), 0.0);
-  if (s_diff.dist < best.dist) { best = s_diff; }
-  let s_glass = sphere_hit(ro, rd, vec3<f32>(1.0, 1.0, 0.5), 1.0,
-                           vec3<f32>(1.0), vec3<f32>(0.0), 1.5); // IOR in mat
-  if (s_glass.dist < best.dist) { best = s_glass; }
-
-  // Floor (white)
-  var h = plane_hit_y(ro, rd, BOX_MIN.y, vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.8), vec3<f32>(0.0));
-  if (h.dist < best.dist) { best = h; }
-  // Ceiling (white, but with an emissive rect in the middle)
-  h = plane_hit_y(ro, rd, BOX_MAX.y, vec3<f32>(0.0, -1.0, 0.0), vec3<f32>(0.8), vec3<f32>(0.0));
-  if (h.dist < 1e30) {
-    let p = ro + rd * h.dist;
-    if (abs(p.x) <= L_LIGHT && abs(p.z) <= L_LIGHT) {
-      // emissive panel
-      h.emissive = vec3<f32>(12.0, 11.0, 10.0); // warm-ish light
-      h.albedo = vec3<f32>(0.0);
-    }
-    if (h.dist < best.dist) { best = h; }
-  }
-
-  // Left wall (red) x = -3, normal +X
-  h = plane_hit_x(ro, rd, BOX_MIN.x, vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.75, 0.15, 0.15));
-  if (h.dist < best.dist) { best = h; }
-  // Right wall (green) x = +3, normal -X
-  h = plane_hit_x(ro, rd, BOX_MAX.x, vec3<f32>(-1.0, 0.0, 0.0), vec3<f32>(0.15, 0.75, 0.2));
-  if (h.dist < best.dist) { best = h; }
-  // Back wall (white) z = -3, normal +Z
-  h = plane_hit_z(ro, rd, BOX_MIN.z, vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.8));
-  if (h.dist < best.dist) { best = h; }
-  // Front wall is open (we look into the box)
+  var best = Hit(1e30, vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), MAT_DIFFUSE, array<vec4<f32>, 3>(), NO_LIGHT, NO_MEDIUM);
+  var best_prim = 0u;
 
+  for (var i: u32 = 0u; i < scene.num_prims; i = i + 1u) {
+    let pr = prims[i];
+    var t = 1e30;
+    if (pr.kind == PRIM_SPHERE) {
+      t = sphere_hit(ro, rd, pr.a.xyz, pr.a.w);
+    } else if (pr.kind == PRIM_DISK) {
+      t = disk_hit(ro, rd, pr.a.xyz, pr.b.xyz, pr.a.w);
+    } else {
+      t = quad_hit(ro, rd, pr.a.xyz, pr.b.xyz, pr.c.xyz);
+    }
+    if (t < best.dist) {
+      best.dist = t;
+      best_prim = i;
+    }
+  }
+
+  // Instances: walk the top-level BVH, then each mesh BVH in object space.
+  // The object-space direction is not renormalised so distances stay in
+  // world units.
+  var best_tri = 0xffffffffu;
+  var best_inst = 0u;
+  var best_bary = vec2<f32>(0.0);
+  let inv_rd = safe_inverse(rd);
+  if (scene.num_instances > 0u &&
+      aabb_hit(ro, inv_rd, bvh_nodes[0].min, bvh_nodes[0].max, best.dist) < 1e30) {
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_t: array<f32, BVH_STACK_SIZE>;
+    var sp = 0u;
+    var node_idx = 0u;
+    loop {
+      let node = bvh_nodes[node_idx];
+      if (node.count > 0u) {
+        for (var i = node.left_or_first; i < node.left_or_first + node.count; i = i + 1u) {
+          let inst = instances[i];
+          let ro_obj = (inst.world_to_object * vec4<f32>(ro, 1.0)).xyz;
+          let rd_obj = (inst.world_to_object * vec4<f32>(rd, 0.0)).xyz;
+          let h = hit_blas(ro_obj, rd_obj, inst.blas_root, best.dist);
+          if (h.t < best.dist) {
+            best.dist = h.t;
+            best_tri = h.tri;
+            best_inst = i;
+            best_bary = h.bary;
+          }
+        }
+      } else {
+        let l = node_idx + 1u;
+        let r = node.left_or_first;
+        let tl = aabb_hit(ro, inv_rd, bvh_nodes[l].min, bvh_nodes[l].max, best.dist);
+        let tr_ = aabb_hit(ro, inv_rd, bvh_nodes[r].min, bvh_nodes[r].max, best.dist);
+        if (tl < 1e30 && tr_ < 1e30) {
+          if (tl <= tr_) {
+            stack[sp] = r; stack_t[sp] = tr_;
+            node_idx = l;
+          } else {
+            stack[sp] = l; stack_t[sp] = tl;
+            node_idx = r;
+          }
+          sp = min(sp + 1u, BVH_STACK_SIZE - 1u);
+          continue;
+        }
+        if (tl < 1e30) { node_idx = l; continue; }
+        if (tr_ < 1e30) { node_idx = r; continue; }
+      }
+      var found = false;
+      while (sp > 0u) {
+        sp = sp - 1u;
+        if (stack_t[sp] < best.dist) {
+          node_idx = stack[sp];
+          found = true;
+          break;
+        }
+      }
+      if (!found) { break; }
+    }
+  }
+  if (best.dist == 1e30) { return best; }
+
+  var material = 0u;
+  var n_geo = vec3<f32>(0.0);
+  var uv = vec2<f32>(0.0);
+  // surface derivatives along the texture axes, zero without UVs
+  var dpdu = vec3<f32>(0.0);
+  var dpdv = vec3<f32>(0.0);
+  if (best_tri != 0xffffffffu) {
+    let tri = tris[best_tri];
+    let v0 = vertices[tri.v.x];
+    let v1 = vertices[tri.v.y];
+    let v2 = vertices[tri.v.z];
+    let inst = instances[best_inst];
+    // normals go to world space with the inverse transpose (n * M = M^T n)
+    let w2o = linear_part(inst.world_to_object);
+    let e1 = v1.position_u.xyz - v0.position_u.xyz;
+    let e2 = v2.position_u.xyz - v0.position_u.xyz;
+    n_geo = normalize(cross(e1, e2) * w2o);
+    let w0 = 1.0 - best_bary.x - best_bary.y;
+    let ns = (w0 * v0.normal_v.xyz + best_bary.x * v1.normal_v.xyz + best_bary.y * v2.normal_v.xyz) * w2o;
+    // vertex normals are optional; keep the shading normal on the winding side
+    if (dot(ns, ns) > 1e-12) {
+      best.n = normalize(ns) * select(-1.0, 1.0, dot(ns, n_geo) >= 0.0);
+    } else {
+      best.n = n_geo;
+    }
+
+    let uv0 = vec2<f32>(v0.position_u.w, v0.normal_v.w);
+    let uv1 = vec2<f32>(v1.position_u.w, v1.normal_v.w);
+    let uv2 = vec2<f32>(v2.position_u.w, v2.normal_v.w);
+    uv = w0 * uv0 + best_bary.x * uv1 + best_bary.y * uv2;
+    let duv1 = uv1 - uv0;
+    let duv2 = uv2 - uv0;
+    let det = duv1.x * duv2.y - duv2.x * duv1.y;
+    if (abs(det) > 1e-12) {
+      let o2w = linear_part(inst.object_to_world);
+      dpdu = o2w * ((e1 * duv2.y - e2 * duv1.y) / det);
+      dpdv = o2w * ((e2 * duv1.x - e1 * duv2.x) / det);
+    }
+    material = tri.material;
+  } else {
+    let pr = prims[best_prim];
+    if (pr.kind == PRIM_SPHERE) {
+      best.n = normalize(ro + rd * best.dist - pr.a.xyz);
+    } else if (pr.kind == PRIM_DISK) {
+      best.n = pr.b.xyz;
+    } else {
+      best.n = normalize(cross(pr.b.xyz, pr.c.xyz));
+    }
+    n_geo = best.n;
+    material = pr.material;
+  }
+  let m = materials[material];
+  best.albedo = m.albedo;
+  best.kind = m.kind;
+  best.params = m.params;
+  best.medium = m.medium;
+  if (m.textures.x != NO_TEXTURE) {
+    best.albedo *= srgb_to_linear(sample_texture(m.textures.x, uv).rgb);
+  }
+  best.albedo = spectrum(best.albedo);
+  if (cam.spectral != 0u) {
+    if (m.kind == MAT_CONDUCTOR && m.params[1].w > 0.0) {
+      best.params[0] = vec4<f32>(spectrum(m.params[0].xyz), m.params[0].w);
+      best.params[1] = vec4<f32>(spectrum(m.params[1].xyz), m.params[1].w);
+    }
+    if (m.kind == MAT_GLASS && m.params[0].z > 0.0) { best.params[0].x = glass_ior(m.params, lambdas.x); }
+  }
+  if (m.kind == MAT_PRINCIPLED && m.textures.w != NO_TEXTURE) {
+    // glTF layout: roughness in green, metallic in blue
+    let mr = sample_texture(m.textures.w, uv);
+    best.params[0].x *= mr.b;
+    best.params[0].y *= mr.g;
+  }
+  if (m.textures.y != NO_TEXTURE && dot(dpdu, dpdu) > 0.0) {
+    // tangent space: +x along u, +y towards decreasing v (up in the image)
+    let t = normalize(dpdu - best.n * dot(best.n, dpdu));
+    let b = cross(best.n, t) * select(1.0, -1.0, dot(cross(best.n, t), dpdv) > 0.0);
+    let tn = (sample_texture(m.textures.y, uv).xyz * 2.0 - 1.0) * vec3<f32>(m.normal_scale, m.normal_scale, 1.0);
+    let mapped = t * tn.x + b * tn.y + best.n * tn.z;
+    if (dot(mapped, mapped) > 1e-12) { best.n = normalize(mapped); }
+  }
+  // emitters only radiate from their front face
+  if (dot(n_geo, rd) < 0.0) {
+    best.emissive = m.emissive;
+    if (m.textures.z != NO_TEXTURE) {
+      best.emissive *= srgb_to_linear(sample_texture(m.textures.z, uv).rgb);
+    }
+    best.emissive = spectrum(best.emissive);
+    if (best_tri != 0xffffffffu) {
+      let emitter = tris[best_tri].emitter;
+      if (emitter != NO_LIGHT) { best.light = instances[best_inst].light_base + emitter; }
+    } else {
+      best.light = prims[best_prim].light;
+    }
+  }
   return best;
 }
 
+// === Microfacets (GGX) ===
+// Directions are in the local shading frame, z along the normal.
+const PI: f32 = 3.14159265;
+// smaller GGX alphas are treated as perfectly smooth
+const MIN_ALPHA: f32 = 1e-3;
+
+fn ggx_d(m: vec3<f32>, alpha: f32) -> f32 {
+  let a2 = alpha * alpha;
+  let d = m.z * m.z * (a2 - 1.0) + 1.0;
+  return a2 / (PI * d * d);
+}
+
+fn ggx_lambda(w: vec3<f32>, alpha: f32) -> f32 {
+  let z2 = w.z * w.z;
+  let tan2 = max(0.0, 1.0 - z2) / max(z2, 1e-8);
+  return 0.5 * (sqrt(1.0 + alpha * alpha * tan2) - 1.0);
+}
+
+fn ggx_g1(w: vec3<f32>, alpha: f32) -> f32 {
+  return 1.0 / (1.0 + ggx_lambda(w, alpha));
+}
+
+// Height-correlated masking-shadowing.
+fn ggx_g2(wo: vec3<f32>, wi: vec3<f32>, alpha: f32) -> f32 {
+  return 1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+}
+
+// Visible normal sampling (Heitz 2018); wo.z must be positive.
+fn ggx_sample_vndf(wo: vec3<f32>, alpha: f32, u: vec2<f32>) -> vec3<f32> {
+  let vh = normalize(vec3<f32>(alpha * wo.x, alpha * wo.y, wo.z));
+  let lensq = vh.x * vh.x + vh.y * vh.y;
+  let t1 = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(-vh.y, vh.x, 0.0) / sqrt(lensq), lensq > 0.0);
+  let t2 = cross(vh, t1);
+  let r = sqrt(u.x);
+  let phi = 2.0 * PI * u.y;
+  let p1 = r * cos(phi);
+  let s = 0.5 * (1.0 + vh.z);
+  let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+  let nh = p1 * t1 + p2 * t2 + sqrt(max(0.0, 1.0 - p1 * p1 - p2 * p2)) * vh;
+  return normalize(vec3<f32>(alpha * nh.x, alpha * nh.y, max(1e-6, nh.z)));
+}
+
+// Reflectance of a conductor with complex IOR eta + i k, per channel.
+fn fresnel_conductor(cos_i: f32, eta: vec3<f32>, k: vec3<f32>) -> vec3<f32> {
+  let c2 = cos_i * cos_i;
+  let s2 = 1.0 - c2;
+  let t0 = eta * eta - k * k - s2;
+  let a2b2 = sqrt(t0 * t0 + 4.0 * eta * eta * k * k);
+  let t1 = a2b2 + c2;
+  let a = sqrt(max(vec3<f32>(0.0), 0.5 * (a2b2 + t0)));
+  let t2 = 2.0 * cos_i * a;
+  let rs = (t1 - t2) / (t1 + t2);
+  let t3 = c2 * a2b2 + s2 * s2;
+  let t4 = t2 * s2;
+  let rp = rs * (t3 - t4) / (t3 + t4);
+  return 0.5 * (rp + rs);
+}
+
+fn fresnel_schlick(cos_i: f32, f0: vec3<f32>) -> vec3<f32> {
+  return f0 + (1.0 - f0) * pow(1.0 - clamp(cos_i, 0.0, 1.0), 5.0);
+}
+
+// === BSDFs ===
+struct BsdfSample {
+  wi    : vec3<f32>,
+  weight: vec3<f32>, // f * |cos| / pdf
+  pdf   : f32,       // 0 for delta lobes
+};
+
+// A sample that ends the path (e.g. reflected below the surface).
+fn bsdf_absorbed() -> BsdfSample {
+  return BsdfSample(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0), 0.0);
+}
+
+// Schlick with the albedo as F0, or the complex IOR tinted by the albedo.
+fn conductor_fresnel(hit: Hit, cos_om: f32) -> vec3<f32> {
+  if (hit.params[1].w > 0.0) {
+    return hit.albedo * fresnel_conductor(cos_om, hit.params[0].xyz, hit.params[1].xyz);
+  }
+  return fresnel_schlick(cos_om, hit.albedo);
+}
+
+fn sample_conductor(hit: Hit, wo: vec3<f32>, u: vec2<f32>) -> BsdfSample {
+  let alpha = hit.params[0].w;
+  var m = vec3<f32>(0.0, 0.0, 1.0);
+  if (alpha >= MIN_ALPHA) { m = ggx_sample_vndf(wo, alpha, u); }
+  let wi = reflect(-wo, m);
+  if (wi.z <= 0.0) { return bsdf_absorbed(); }
+  let f = conductor_fresnel(hit, dot(wo, m));
+  if (alpha < MIN_ALPHA) { return BsdfSample(wi, f, 0.0); }
+  let g1 = ggx_g1(wo, alpha);
+  return BsdfSample(wi, f * ggx_g2(wo, wi, alpha) / g1, g1 * ggx_d(m, alpha) / (4.0 * wo.z));
+}
+
+// Dielectric, smooth or rough (Walter et al. 2007); eta = n_t / n_i.
+fn sample_dielectric(hit: Hit, wo: vec3<f32>, eta: f32, u: vec3<f32>) -> BsdfSample {
+  let alpha = hit.params[0].y;
+  var m = vec3<f32>(0.0, 0.0, 1.0);
+  if (alpha >= MIN_ALPHA) { m = ggx_sample_vndf(wo, alpha, u.xy); }
+  let cos_om = dot(wo, m);
+  let f = fresnel_dielectric(cos_om, eta);
+  let is_smooth = alpha < MIN_ALPHA;
+  if (u.z < f) {
+    let wi = reflect(-wo, m);
+    if (is_smooth) { return BsdfSample(wi, vec3<f32>(1.0), 0.0); }
+    if (wi.z <= 0.0) { return bsdf_absorbed(); }
+    let g1 = ggx_g1(wo, alpha);
+    return BsdfSample(wi, vec3<f32>(ggx_g2(wo, wi, alpha) / g1), f * g1 * ggx_d(m, alpha) / (4.0 * wo.z));
+  }
+  let wi = refract_ray(-wo, m, 1.0 / eta);
+  if (is_smooth) { return BsdfSample(wi, hit.albedo, 0.0); }
+  if (wi.z >= 0.0) { return bsdf_absorbed(); }
+  let g1 = ggx_g1(wo, alpha);
+  let cos_im = dot(wi, m);
+  let denom = cos_om + eta * cos_im;
+  let pdf = (1.0 - f) * g1 * cos_om * ggx_d(m, alpha) / wo.z * eta * eta * abs(cos_im) / (denom * denom);
+  return BsdfSample(wi, hit.albedo * ggx_g2(wo, wi, alpha) / g1, pdf);
+}
+
+// The `eval_*` functions return (f * |cos wi|, pdf of the matching
+// `sample_*`) in the local frame; delta lobes evaluate to zero.
+
+fn eval_conductor(hit: Hit, wo: vec3<f32>, wi: vec3<f32>) -> vec4<f32> {
+  let alpha = hit.params[0].w;
+  if (alpha < MIN_ALPHA || wi.z <= 0.0) { return vec4<f32>(0.0); }
+  let h = normalize(wo + wi);
+  let d = ggx_d(h, alpha);
+  let fcos = conductor_fresnel(hit, dot(wo, h)) * d * ggx_g2(wo, wi, alpha) / (4.0 * wo.z);
+  return vec4<f32>(fcos, ggx_g1(wo, alpha) * d / (4.0 * wo.z));
+}
+
+fn eval_dielectric(hit: Hit, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec4<f32> {
+  let alpha = hit.params[0].y;
+  if (alpha < MIN_ALPHA) { return vec4<f32>(0.0); }
+  let g1 = ggx_g1(wo, alpha);
+  if (wi.z > 0.0) {
+    let h = normalize(wo + wi);
+    let f = fresnel_dielectric(dot(wo, h), eta);
+    let d = ggx_d(h, alpha);
+    return vec4<f32>(vec3<f32>(f * d * ggx_g2(wo, wi, alpha) / (4.0 * wo.z)), f * g1 * d / (4.0 * wo.z));
+  }
+  var h = normalize(wo + eta * wi);
+  if (h.z < 0.0) { h = -h; }
+  let cos_oh = dot(wo, h);
+  let cos_ih = dot(wi, h);
+  if (cos_oh <= 0.0 || cos_ih >= 0.0) { return vec4<f32>(0.0); }
+  let f = fresnel_dielectric(cos_oh, eta);
+  let d = ggx_d(h, alpha);
+  let denom = cos_oh + eta * cos_ih;
+  let jac = eta * eta * abs(cos_ih) / (denom * denom);
+  let fcos = hit.albedo * (1.0 - f) * d * ggx_g2(wo, wi, alpha) * cos_oh * jac / wo.z;
+  return vec4<f32>(fcos, (1.0 - f) * g1 * cos_oh * d / wo.z * jac);
+}
+
+// --- Principled (Burley 2012/2015) ---
+// Lobes: diffuse with retro-reflection, subsurface approximation and sheen;
+// GGX specular reflection; GTR1 clearcoat; GGX specular transmission.
+// `eta` is n_t / n_i for the transmission lobe.
+
+fn schlick_weight(c: f32) -> f32 {
+  let m = clamp(1.0 - c, 0.0, 1.0);
+  let m2 = m * m;
+  return m2 * m2 * m;
+}
+
+fn luminance(c: vec3<f32>) -> f32 {
+  return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn gtr1_d(cos_h: f32, a: f32) -> f32 {
+  let a2 = a * a;
+  return (a2 - 1.0) / (PI * log(a2) * (1.0 + (a2 - 1.0) * cos_h * cos_h));
+}
+
+fn principled_alpha(hit: Hit) -> f32 {
+  let r = hit.params[0].y;
+  return max(r * r, MIN_ALPHA);
+}
+
+fn clearcoat_alpha(hit: Hit) -> f32 {
+  return mix(0.1, 0.001, hit.params[1].y);
+}
+
+// Lobe selection probabilities: diffuse, specular, clearcoat, glass.
+fn principled_lobes(hit: Hit) -> vec4<f32> {
+  let metallic = hit.params[0].x;
+  let transmission = hit.params[2].x;
+  let dw = (1.0 - metallic) * (1.0 - transmission);
+  let tw = (1.0 - metallic) * transmission;
+  let w = vec4<f32>(dw, (1.0 - 0.5 * dw) * (1.0 - tw), 0.25 * hit.params[1].x, tw);
+  return w / (w.x + w.y + w.z + w.w);
+}
+
+fn eval_principled(hit: Hit, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec4<f32> {
+  let metallic = hit.params[0].x;
+  let specular = hit.params[0].z;
+  let spec_tint = hit.params[0].w;
+  let clearcoat = hit.params[1].x;
+  let sheen = hit.params[1].z;
+  let sheen_tint = hit.params[1].w;
+  let transmission = hit.params[2].x;
+  let subsurface = hit.params[2].y;
+  let alpha = principled_alpha(hit);
+  let lobes = principled_lobes(hit);
+  let base = hit.albedo;
+  let lum = luminance(base);
+  let tint = select(vec3<f32>(1.0), base / lum, lum > 0.0);
+  // the glass lobe is a full rough dielectric, reflection included
+  let tw = (1.0 - metallic) * transmission;
+  let g1o = ggx_g1(wo, alpha);
+
+  if (wi.z <= 0.0) {
+    // transmission through the generalised half vector
+    var h = normalize(wo + eta * wi);
+    if (h.z < 0.0) { h = -h; }
+    let cos_oh = dot(wo, h);
+    let cos_ih = dot(wi, h);
+    if (tw <= 0.0 || cos_oh <= 0.0 || cos_ih >= 0.0) { return vec4<f32>(0.0); }
+    let f = fresnel_dielectric(cos_oh, eta);
+    let d = ggx_d(h, alpha);
+    let denom = cos_oh + eta * cos_ih;
+    let jac = eta * eta * abs(cos_ih) / (denom * denom);
+    let fcos = tw * base * (1.0 - f) * d * ggx_g2(wo, wi, alpha) * cos_oh * jac / wo.z;
+    let pdf = lobes.w * (1.0 - f) * g1o * cos_oh * d / wo.z * jac;
+    return vec4<f32>(fcos, pdf);
+  }
+
+  let h = normalize(wo + wi);
+  let cos_d = dot(wi, h);
+  let fl = schlick_weight(wi.z);
+  let fv = schlick_weight(wo.z);
+  let fd_w = schlick_weight(cos_d);
+  let r = hit.params[0].y;
+
+  // diffuse, blended towards the subsurface approximation, plus sheen
+  let rr = 2.0 * r * cos_d * cos_d;
+  let fd = (1.0 - 0.5 * fl) * (1.0 - 0.5 * fv) + rr * (fl + fv + fl * fv * (rr - 1.0));
+  let fss90 = cos_d * cos_d * r;
+  let fss = mix(1.0, fss90, fl) * mix(1.0, fss90, fv);
+  let ss = 1.25 * (fss * (1.0 / (wi.z + wo.z) - 0.5) + 0.5);
+  let sheen_c = sheen * mix(vec3<f32>(1.0), tint, sheen_tint) * fd_w;
+  let diffuse = base / PI * mix(fd, ss, subsurface) + sheen_c;
+  var f = (1.0 - metallic) * (1.0 - transmission) * diffuse;
+
+  // specular reflection of the opaque part, and of the glass part
+  let cspec0 = mix(specular * 0.08 * mix(vec3<f32>(1.0), tint, spec_tint), base, metallic);
+  let d = ggx_d(h, alpha);
+  let spec = d * ggx_g2(wo, wi, alpha) / (4.0 * wo.z * wi.z);
+  let f_glass = fresnel_dielectric(dot(wo, h), eta);
+  f += ((1.0 - tw) * mix(cspec0, vec3<f32>(1.0), fd_w) + tw * f_glass) * spec;
+
+  // clearcoat, fixed IOR 1.5 and alpha 0.25 masking
+  let ca = clearcoat_alpha(hit);
+  let dc = gtr1_d(h.z, ca);
+  if (clearcoat > 0.0) {
+    let fc = mix(0.04, 1.0, fd_w);
+    f += vec3<f32>(0.25 * clearcoat * dc * fc * ggx_g1(wo, 0.25) * ggx_g1(wi, 0.25) / (4.0 * wo.z * wi.z));
+  }
+
+  let pdf_refl = g1o * d / (4.0 * wo.z);
+  let pdf = lobes.x * wi.z / PI
+          + (lobes.y + lobes.w * f_glass) * pdf_refl
+          + lobes.z * dc * h.z / (4.0 * dot(wo, h));
+  return vec4<f32>(f * wi.z, pdf);
+}
+
+fn sample_principled(hit: Hit, wo: vec3<f32>, eta: f32, u: vec3<f32>) -> BsdfSample {
+  let lobes = principled_lobes(hit);
+  let alpha = principled_alpha(hit);
+  var wi: vec3<f32>;
+  let c = vec3<f32>(lobes.x, lobes.x + lobes.y, lobes.x + lobes.y + lobes.z);
+  if (u.z < c.x) {
+    wi = cosine_sample_hemisphere(u.x, u.y);
+  } else if (u.z < c.y) {
+    wi = reflect(-wo, ggx_sample_vndf(wo, alpha, u.xy));
+  } else if (u.z < c.z) {
+    let a2 = clearcoat_alpha(hit) * clearcoat_alpha(hit);
+    let cos_h = sqrt(max(0.0, (1.0 - pow(a2, 1.0 - u.x)) / (1.0 - a2)));
+    let sin_h = sqrt(max(0.0, 1.0 - cos_h * cos_h));
+    let phi = 2.0 * PI * u.y;
+    wi = reflect(-wo, vec3<f32>(sin_h * cos(phi), sin_h * sin(phi), cos_h));
+  } else {
+    // reuse the lobe choice number to pick reflection or refraction
+    let h = ggx_sample_vndf(wo, alpha, u.xy);
+    let f = fresnel_dielectric(dot(wo, h), eta);
+    if ((u.z - c.z) / max(lobes.w, 1e-6) < f) {
+      wi = reflect(-wo, h);
+    } else {
+      wi = refract_ray(-wo, h, 1.0 / eta);
+    }
+  }
+  if (wi.z == 0.0) { return bsdf_absorbed(); }
+  let e = eval_principled(hit, wo, wi, eta);
+  if (e.w <= 0.0) { return bsdf_absorbed(); }
+  return BsdfSample(wi, e.rgb / e.w, e.w);
+}
+
+// Samples an outgoing direction for a ray `rd` arriving at `hit`; `u` holds
+// three uniform numbers. Surfaces are two-sided except that glass and
+// principled transmission treat the normal side as outside.
+fn sample_bsdf(hit: Hit, rd: vec3<f32>, u: vec3<f32>) -> BsdfSample {
+  let front = dot(rd, hit.n) < 0.0;
+  let n_face = select(-hit.n, hit.n, front);
+  let tbn = onb(n_face);
+  // shading normals can face away from the ray; keep wo above the surface
+  var wo = -rd * tbn;
+  wo.z = max(wo.z, 1e-5);
+  var bs: BsdfSample;
+  switch hit.kind {
+    case MAT_MIRROR: {
+      bs = BsdfSample(reflect(-wo, vec3<f32>(0.0, 0.0, 1.0)), hit.albedo, 0.0);
+    }
+    case MAT_GLASS: {
+      let ior = hit.params[0].x;
+      bs = sample_dielectric(hit, wo, select(1.0 / ior, ior, front), u);
+    }
+    case MAT_CONDUCTOR: {
+      bs = sample_conductor(hit, wo, u.xy);
+    }
+    case MAT_PRINCIPLED: {
+      let ior = hit.params[2].z;
+      bs = sample_principled(hit, wo, select(1.0 / ior, ior, front), u);
+    }
+    default: {
+      let wi = cosine_sample_hemisphere(u.x, u.y);
+      bs = BsdfSample(wi, hit.albedo, wi.z / PI);
+    }
+  }
+  bs.wi = normalize(tbn * bs.wi);
+  return bs;
+}
+
+// Evaluates the BSDF at `hit` for a ray `rd` leaving towards `wi`, in the
+// same frame as sample_bsdf. Returns (f * |cos wi|, pdf of sample_bsdf).
+fn eval_bsdf(hit: Hit, rd: vec3<f32>, wi: vec3<f32>) -> vec4<f32> {
+  let front = dot(rd, hit.n) < 0.0;
+  let n_face = select(-hit.n, hit.n, front);
+  let tbn = onb(n_face);
+  var wo = -rd * tbn;
+  wo.z = max(wo.z, 1e-5);
+  let wi_local = wi * tbn;
+  switch hit.kind {
+    case MAT_MIRROR: {
+      return vec4<f32>(0.0);
+    }
+    case MAT_GLASS: {
+      let ior = hit.params[0].x;
+      return eval_dielectric(hit, wo, wi_local, select(1.0 / ior, ior, front));
+    }
+    case MAT_CONDUCTOR: {
+      return eval_conductor(hit, wo, wi_local);
+    }
+    case MAT_PRINCIPLED: {
+      let ior = hit.params[2].z;
+      return eval_principled(hit, wo, wi_local, select(1.0 / ior, ior, front));
+    }
+    default: {
+      if (wi_local.z <= 0.0) { return vec4<f32>(0.0); }
+      return vec4<f32>(hit.albedo * wi_local.z / PI, wi_local.z / PI);
+    }
+  }
+}
+
+// True if the BSDF only has delta lobes, so light sampling cannot help.
+fn bsdf_is_delta(hit: Hit) -> bool {
+  switch hit.kind {
+    case MAT_MIRROR: { return true; }
+    case MAT_GLASS: { return hit.params[0].y < MIN_ALPHA; }
+    case MAT_CONDUCTOR: { return hit.params[0].w < MIN_ALPHA; }
+    default: { return false; }
+  }
+}
+
+// === Light sampling ===
+// One light is picked per shading point: an infinite light, or a light from
+// the light BVH, descending by the importance of each child as seen from the
+// shading point (Conty Estevez and Kulla 2018). Spheres are then sampled by
+// the cone they subtend, other surfaces by area; point, spot and directional
+// lights are delta distributions and cannot be hit by BSDF samples.
+
+// cos(max(0, a - b)) and sin(max(0, a - b)) from sines and cosines
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+  if (cos_a > cos_b) { return 1.0; }
+  return cos_a * cos_b + sin_a * sin_b;
+}
+
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+  if (cos_a > cos_b) { return 0.0; }
+  return sin_a * cos_b - cos_a * sin_b;
+}
+
+// Conservative estimate of the light a node sends towards `p` on a surface
+// with normal `n`.
+fn light_importance(node: LightNode, p: vec3<f32>, n: vec3<f32>) -> f32 {
+  let pc = 0.5 * (node.min + node.max);
+  var d2 = dot(p - pc, p - pc);
+  // avoid blowing up for points inside or close to the bounds
+  d2 = max(d2, 0.5 * length(node.max - node.min));
+  let wi = normalize(p - pc);
+
+  var cos_w = dot(node.w, wi);
+  if ((node.flags & LIGHT_NODE_TWO_SIDED) != 0u) { cos_w = abs(cos_w); }
+  let sin_w = sqrt(max(0.0, 1.0 - cos_w * cos_w));
+
+  // cone of directions subtended by the bounds' bounding sphere
+  let r2 = dot(node.max - pc, node.max - pc);
+  var cos_b = -1.0;
+  if (dot(p - pc, p - pc) > r2) {
+    cos_b = sqrt(max(0.0, 1.0 - r2 / dot(p - pc, p - pc)));
+  }
+  let sin_b = sqrt(max(0.0, 1.0 - cos_b * cos_b));
+
+  // smallest angle between wi and an emission direction
+  let sin_o = sqrt(max(0.0, 1.0 - node.cos_theta_o * node.cos_theta_o));
+  let cos_x = cos_sub_clamped(sin_w, cos_w, sin_o, node.cos_theta_o);
+  let sin_x = sin_sub_clamped(sin_w, cos_w, sin_o, node.cos_theta_o);
+  let cos_p = cos_sub_clamped(sin_x, cos_x, sin_b, cos_b);
+  if (cos_p <= node.cos_theta_e) { return 0.0; }
+
+  var importance = node.phi * cos_p / d2;
+  if (dot(n, n) > 0.0) {
+    let cos_i = abs(dot(wi, n));
+    let sin_i = sqrt(max(0.0, 1.0 - cos_i * cos_i));
+    importance *= cos_sub_clamped(sin_i, cos_i, sin_b, cos_b);
+  }
+  return max(importance, 0.0);
+}
+
+// Probability of picking an infinite light rather than the light BVH.
+fn infinite_light_pdf() -> f32 {
+  let n_inf = f32(scene.num_infinite_lights);
+  return n_inf / (n_inf + select(0.0, 1.0, scene.num_lights > scene.num_infinite_lights));
+}
+
+struct LightPick {
+  light: u32, // NO_LIGHT if nothing could be picked
+  pmf  : f32,
+};
+
+fn pick_light(p: vec3<f32>, n: vec3<f32>, u_in: f32) -> LightPick {
+  if (scene.num_lights == 0u) { return LightPick(NO_LIGHT, 0.0); }
+  var u = u_in;
+  let p_inf = infinite_light_pdf();
+  if (u < p_inf) {
+    let count = scene.num_infinite_lights;
+    return LightPick(min(u32(u / p_inf * f32(count)), count - 1u), p_inf / f32(count));
+  }
+  u = min((u - p_inf) / (1.0 - p_inf), 0.99999994);
+
+  // descend, reusing u for every choice
+  var pmf = 1.0 - p_inf;
+  var idx = 0u;
+  if (light_importance(light_nodes[0], p, n) <= 0.0) { return LightPick(NO_LIGHT, 0.0); }
+  loop {
+    let node = light_nodes[idx];
+    if ((node.flags & LIGHT_NODE_LEAF) != 0u) {
+      return LightPick(node.child_or_light, pmf);
+    }
+    let il = light_importance(light_nodes[idx + 1u], p, n);
+    let ir = light_importance(light_nodes[node.child_or_light], p, n);
+    if (il + ir <= 0.0) { return LightPick(NO_LIGHT, 0.0); }
+    let pl = il / (il + ir);
+    if (u < pl) {
+      idx = idx + 1u;
+      u = min(u / pl, 0.99999994);
+      pmf *= pl;
+    } else {
+      idx = node.child_or_light;
+      u = min((u - pl) / (1.0 - pl), 0.99999994);
+      pmf *= 1.0 - pl;
+    }
+  }
+  return LightPick(NO_LIGHT, 0.0);
+}
+
+// Probability that pick_light(p, n, .) returns light `i`.
+fn light_select_pdf(i: u32, p: vec3<f32>, n: vec3<f32>) -> f32 {
+  let p_inf = infinite_light_pdf();
+  if (i < scene.num_infinite_lights) { return p_inf / f32(scene.num_infinite_lights); }
+  var idx = lights[i].node;
+  if (idx == NO_LIGHT) { return 0.0; }
+  var pmf = 1.0 - p_inf;
+  // walk up to the root, multiplying the probability of each step down
+  while (idx != 0u) {
+    let parent_idx = light_nodes[idx].parent;
+    let parent = light_nodes[parent_idx];
+    var sibling = parent_idx + 1u;
+    if (sibling == idx) { sibling = parent.child_or_light; }
+    let ic = light_importance(light_nodes[idx], p, n);
+    let is = light_importance(light_nodes[sibling], p, n);
+    if (ic <= 0.0) { return 0.0; }
+    pmf *= ic / (ic + is);
+    idx = parent_idx;
+  }
+  return pmf;
+}
+
+struct LightSample {
+  wi      : vec3<f32>,
+  pdf     : f32,       // solid angle, 0 if there is no sample
+  // delta lights and the environment: incident radiance (integrated over
+  // delta lights), and the distance the shadow ray must clear (1e30 for
+  // infinite lights). Surfaces leave dist at 0, their radiance is what the
+  // shadow ray hits.
+  radiance: vec3<f32>,
+  dist    : f32,
+  is_delta: bool,
+};
+
+fn no_light_sample() -> LightSample {
+  return LightSample(vec3<f32>(0.0, 0.0, 1.0), 0.0, vec3<f32>(0.0), 0.0, false);
+}
+
+// Samples a direction from `p` towards `l`, e.g. no sample if `p` is inside
+// a sphere light or behind a spot light's cone.
+fn sample_light(l: Light, p: vec3<f32>, u: vec2<f32>) -> LightSample {
+  switch l.kind {
+    case LIGHT_POINT, LIGHT_SPOT: {
+      let to_l = l.a.xyz - p;
+      let d2 = dot(to_l, to_l);
+      if (d2 <= 0.0) { return no_light_sample(); }
+      let dist = sqrt(d2);
+      let wi = to_l / dist;
+      var falloff = 1.0;
+      if (l.kind == LIGHT_SPOT) {
+        let cos_inner = l.b.w;
+        let cos_outer = l.c.x;
+        let x = clamp((dot(-wi, l.b.xyz) - cos_outer) / max(cos_inner - cos_outer, 1e-6), 0.0, 1.0);
+        falloff = x * x * (3.0 - 2.0 * x);
+        if (falloff <= 0.0) { return no_light_sample(); }
+      }
+      return LightSample(wi, 1.0, spectrum(l.emission.rgb) * falloff / d2, dist, true);
+    }
+    case LIGHT_DIRECTIONAL: {
+      let cone = l.c.y;
+      if (cone <= 0.0) { return LightSample(-l.b.xyz, 1.0, spectrum(l.emission.rgb), 1e30, true); }
+      // a disk like the sun: uniform over its cone, irradiance spread evenly
+      let cos_t = 1.0 - u.x * cone;
+      let sin_t = sqrt(max(0.0, 1.0 - cos_t * cos_t));
+      let phi = 2.0 * PI * u.y;
+      let dir = onb(-l.b.xyz) * vec3<f32>(sin_t * cos(phi), sin_t * sin(phi), cos_t);
+      let pdf = 1.0 / (2.0 * PI * cone);
+      return LightSample(normalize(dir), pdf, spectrum(l.emission.rgb) * pdf, 1e30, false);
+    }
+    case LIGHT_ENVIRONMENT: {
+      return sample_environment(u);
+    }
+    case LIGHT_SPHERE: {
+      let to_c = l.a.xyz - p;
+      let d2 = dot(to_c, to_c);
+      let r2 = l.a.w * l.a.w;
+      if (d2 <= r2) { return no_light_sample(); }
+      let sin2_max = r2 / d2;
+      // 1 - cos(theta_max), without cancellation for small lights
+      let cone = sin2_max / (1.0 + sqrt(1.0 - sin2_max));
+      let cos_t = 1.0 - u.x * cone;
+      let sin_t = sqrt(max(0.0, 1.0 - cos_t * cos_t));
+      let phi = 2.0 * PI * u.y;
+      let dir = onb(to_c / sqrt(d2)) * vec3<f32>(sin_t * cos(phi), sin_t * sin(phi), cos_t);
+      return LightSample(normalize(dir), 1.0 / (2.0 * PI * cone), vec3<f32>(0.0), 0.0, false);
+    }
+    default: {
+      var y: vec3<f32>;
+      if (l.kind == LIGHT_QUAD) {
+        y = l.a.xyz + u.x * l.b.xyz + u.y * l.c.xyz;
+      } else if (l.kind == LIGHT_TRIANGLE) {
+        let su = sqrt(u.x);
+        y = l.a.xyz + su * (1.0 - u.y) * l.b.xyz + su * u.y * l.c.xyz;
+      } else {
+        let r = l.a.w * sqrt(u.x);
+        let phi = 2.0 * PI * u.y;
+        y = l.a.xyz + onb(l.b.xyz) * vec3<f32>(r * cos(phi), r * sin(phi), 0.0);
+      }
+      let to_y = y - p;
+      let d2 = dot(to_y, to_y);
+      if (d2 <= 0.0) { return no_light_sample(); }
+      let wi = to_y / sqrt(d2);
+      let pdf = light_pdf(l, p, wi, sqrt(d2));
+      if (pdf <= 0.0) { return no_light_sample(); }
+      return LightSample(wi, pdf, vec3<f32>(0.0), 0.0, false);
+    }
+  }
+}
+
+// Solid angle pdf of sample_light choosing `rd` from `ro`, given that the
+// ray reaches the (area) light at distance `dist`.
+fn light_pdf(l: Light, ro: vec3<f32>, rd: vec3<f32>, dist: f32) -> f32 {
+  var cos_area: f32;
+  switch l.kind {
+    case LIGHT_SPHERE: {
+      let to_c = l.a.xyz - ro;
+      let d2 = dot(to_c, to_c);
+      let r2 = l.a.w * l.a.w;
+      if (d2 <= r2) { return 0.0; }
+      let sin2_max = r2 / d2;
+      return 1.0 / (2.0 * PI * sin2_max / (1.0 + sqrt(1.0 - sin2_max)));
+    }
+    // |u x v| is the quad's area and twice the triangle's
+    case LIGHT_QUAD: {
+      cos_area = abs(dot(cross(l.b.xyz, l.c.xyz), rd));
+    }
+    case LIGHT_TRIANGLE: {
+      cos_area = 0.5 * abs(dot(cross(l.b.xyz, l.c.xyz), rd));
+    }
+    case LIGHT_DISK: {
+      cos_area = PI * l.a.w * l.a.w * abs(dot(l.b.xyz, rd));
+    }
+    default: {
+      return 0.0;
+    }
+  }
+  if (cos_area <= 0.0) { return 0.0; }
+  return dist * dist / cos_area;
+}
+
+// Environment direction in the map's frame, undoing the rotation.
+fn env_local(d: vec3<f32>) -> vec3<f32> {
+  let c = scene.env.y;
+  let s = scene.env.z;
+  return vec3<f32>(c * d.x - s * d.z, d.y, s * d.x + c * d.z);
+}
+
+fn env_texel(d: vec3<f32>) -> vec2<i32> {
+  let size = vec2<i32>(textureDimensions(env_map));
+  let dl = env_local(d);
+  let u = 0.5 + atan2(dl.x, -dl.z) / (2.0 * PI);
+  let v = acos(clamp(dl.y, -1.0, 1.0)) / PI;
+  return clamp(vec2<i32>(vec2<f32>(u, v) * vec2<f32>(size)), vec2<i32>(0), size - 1);
+}
+
+fn env_radiance(d: vec3<f32>) -> vec3<f32> {
+  return spectrum(textureLoad(env_map, env_texel(d), 0).rgb) * scene.env.x;
+}
+
+// Solid angle pdf of sample_environment: the texel's share of the sampling
+// weight (luminance times sin theta at the row centre) over its solid angle.
+fn env_pdf(d: vec3<f32>) -> f32 {
+  if (scene.env.w <= 0.0) { return 0.0; }
+  let dl = env_local(d);
+  let sin_theta = sqrt(max(0.0, 1.0 - dl.y * dl.y));
+  if (sin_theta <= 0.0) { return 0.0; }
+  let t = env_texel(d);
+  let h = f32(textureDimensions(env_map).y);
+  let sin_row = sin(PI * (f32(t.y) + 0.5) / h);
+  let weight = luminance(textureLoad(env_map, t, 0).rgb) * sin_row;
+  return weight / (scene.env.w * 2.0 * PI * PI * sin_theta);
+}
+
+// First of the `n` entries of cumulative distribution row `row` above `u`.
+fn cdf_search(cdf: texture_2d<f32>, row: i32, n: i32, u: f32) -> i32 {
+  var lo = 0;
+  var hi = n - 1;
+  while (lo < hi) {
+    let mid = (lo + hi) / 2;
+    if (textureLoad(cdf, vec2<i32>(mid, row), 0).r > u) { hi = mid; } else { lo = mid + 1; }
+  }
+  return lo;
+}
+
+// Offset of `u` within entry `i` of a cumulative distribution row, in [0, 1).
+fn cdf_offset(cdf: texture_2d<f32>, row: i32, i: i32, u: f32) -> f32 {
+  var c0 = 0.0;
+  if (i > 0) { c0 = textureLoad(cdf, vec2<i32>(i - 1, row), 0).r; }
+  let c1 = textureLoad(cdf, vec2<i32>(i, row), 0).r;
+  return clamp((u - c0) / max(c1 - c0, 1e-20), 0.0, 0.99999994);
+}
+
+// Point in [0, 1)² drawn from the distribution `cdf` over the pixels of a
+// `size` image, y growing downwards.
+fn sample_cdf(cdf: texture_2d<f32>, size: vec2<i32>, u: vec2<f32>) -> vec2<f32> {
+  let y = cdf_search(cdf, size.y, size.y, u.x);
+  let x = cdf_search(cdf, y, size.x, u.y);
+  return vec2<f32>(
+    (f32(x) + cdf_offset(cdf, y, x, u.y)) / f32(size.x),
+    (f32(y) + cdf_offset(cdf, size.y, y, u.x)) / f32(size.y),
+  );
+}
+
+fn sample_environment(u: vec2<f32>) -> LightSample {
+  let uv = sample_cdf(env_cdf, vec2<i32>(textureDimensions(env_map)), u);
+  let theta = uv.y * PI;
+  let phi = (uv.x - 0.5) * 2.0 * PI;
+  let dl = vec3<f32>(sin(theta) * sin(phi), cos(theta), -sin(theta) * cos(phi));
+  // back to world space
+  let c = scene.env.y;
+  let s = scene.env.z;
+  let wi = vec3<f32>(c * dl.x + s * dl.z, dl.y, -s * dl.x + c * dl.z);
+  let pdf = env_pdf(wi);
+  if (pdf <= 0.0) { return no_light_sample(); }
+  return LightSample(wi, pdf, env_radiance(wi), 1e30, false);
+}
+
+// Radiance of infinite light `l` along `rd`, and the solid angle pdf of
+// sample_light choosing `rd`; zero for directional lights without a disk.
+fn infinite_light_radiance(l: Light, rd: vec3<f32>) -> vec4<f32> {
+  if (l.kind == LIGHT_ENVIRONMENT) { return vec4<f32>(env_radiance(rd), env_pdf(rd)); }
+  if (l.c.y > 0.0 && dot(rd, -l.b.xyz) >= l.c.x) {
+    let pdf = 1.0 / (2.0 * PI * l.c.y);
+    return vec4<f32>(spectrum(l.emission.rgb) * pdf, pdf);
+  }
+  return vec4<f32>(0.0);
+}
+
+struct DirectLight {
+  wi      : vec3<f32>,
+  pdf     : f32, // light pick pmf times the sample's pdf, 0 without a sample
+  radiance: vec3<f32>,
+  dist    : f32,
+  is_delta: bool,
+  light   : u32,
+};
+
+// Picks a light for next-event estimation at `p` (normal `n`, zero inside
+// a medium) and samples it.
+fn sample_direct(p: vec3<f32>, n: vec3<f32>, dim: u32) -> DirectLight {
+  let pick = pick_light(p, n, sample_1d(dim + DIM_LIGHT_PICK));
+  if (pick.light == NO_LIGHT) { return DirectLight(vec3<f32>(0.0), 0.0, vec3<f32>(0.0), 0.0, false, NO_LIGHT); }
+  let ls = sample_light(lights[pick.light], p, sample_2d(dim + DIM_LIGHT));
+  return DirectLight(ls.wi, pick.pmf * ls.pdf, ls.radiance, ls.dist, ls.is_delta, pick.light);
+}
+
+// === Participating media ===
+const MAX_CROSSINGS: u32 = 64u; // interfaces a ray may pass through
+
+// Henyey-Greenstein phase function; cos_t is between the direction of
+// travel and the scattered direction.
+fn hg_phase(cos_t: f32, g: f32) -> f32 {
+  let denom = 1.0 + g * g - 2.0 * g * cos_t;
+  return (1.0 - g * g) / (4.0 * PI * denom * sqrt(max(denom, 1e-12)));
+}
+
+fn sample_hg(rd: vec3<f32>, g: f32, u: vec2<f32>) -> vec3<f32> {
+  var cos_t = 1.0 - 2.0 * u.x;
+  if (abs(g) > 1e-3) {
+    let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u.x);
+    cos_t = clamp((1.0 + g * g - s * s) / (2.0 * g), -1.0, 1.0);
+  }
+  let sin_t = sqrt(max(0.0, 1.0 - cos_t * cos_t));
+  let phi = 2.0 * PI * u.y;
+  return normalize(onb(rd) * vec3<f32>(sin_t * cos(phi), sin_t * sin(phi), cos_t));
+}
+
+// Medium on the side of `hit` that `wi` heads into: the material's medium
+// inside, the atmosphere outside. Surfaces without a medium keep `current`.
+fn next_medium(hit: Hit, wi: vec3<f32>, current: u32) -> u32 {
+  if (hit.medium == NO_MEDIUM) { return current; }
+  return select(scene.atmosphere, hit.medium, dot(wi, hit.n) < 0.0);
+}
+
+fn medium_sigma_t(m: Medium) -> vec3<f32> {
+  return spectrum(m.sigma_a.rgb) + spectrum(m.sigma_s.rgb);
+}
+
+// Density at `p`: trilinear between voxel centres, zero outside the grid,
+// one in homogeneous media.
+fn medium_density(m: Medium, p: vec3<f32>) -> f32 {
+  if (m.grid.x == 0u) { return 1.0; }
+  let res = vec3<i32>(m.grid.xyz);
+  let g = (p - m.grid_min.xyz) / (m.grid_max.xyz - m.grid_min.xyz) * vec3<f32>(res) - 0.5;
+  if (any(g < vec3<f32>(-0.5)) || any(g > vec3<f32>(res) - 0.5)) { return 0.0; }
+  let base = floor(g);
+  let f = g - base;
+  let i0 = clamp(vec3<i32>(base), vec3<i32>(0), res - 1);
+  let i1 = clamp(vec3<i32>(base) + 1, vec3<i32>(0), res - 1);
+  let z0 = i0.z + i32(m.grid.w);
+  let z1 = i1.z + i32(m.grid.w);
+  let d00 = mix(textureLoad(density_grids, vec3<i32>(i0.x, i0.y, z0), 0).r,
+                textureLoad(density_grids, vec3<i32>(i1.x, i0.y, z0), 0).r, f.x);
+  let d10 = mix(textureLoad(density_grids, vec3<i32>(i0.x, i1.y, z0), 0).r,
+                textureLoad(density_grids, vec3<i32>(i1.x, i1.y, z0), 0).r, f.x);
+  let d01 = mix(textureLoad(density_grids, vec3<i32>(i0.x, i0.y, z1), 0).r,
+                textureLoad(density_grids, vec3<i32>(i1.x, i0.y, z1), 0).r, f.x);
+  let d11 = mix(textureLoad(density_grids, vec3<i32>(i0.x, i1.y, z1), 0).r,
+                textureLoad(density_grids, vec3<i32>(i1.x, i1.y, z1), 0).r, f.x);
+  return mix(mix(d00, d10, f.y), mix(d01, d11, f.y), f.z);
+}
+
+// Walks a ray through a medium in segments of constant majorant: all of
+// [0, t_max] for homogeneous media, the cells of the majorant grid within
+// the grid bounds otherwise (3D DDA, Amanatides & Woo).
+struct MajorantWalk {
+  medium   : u32,
+  t        : f32, // start of the next segment
+  t_end    : f32,
+  sigma_max: f32, // largest channel of sigma_t at density one
+  cell     : vec3<i32>,
+  step     : vec3<i32>,
+  t_next   : vec3<f32>, // where the ray leaves the cell along each axis
+  t_delta  : vec3<f32>,
+};
+
+struct MajorantSegment {
+  t0      : f32,
+  t1      : f32,
+  majorant: f32,
+  valid   : bool,
+};
+
+fn majorant_walk(medium: u32, ro: vec3<f32>, rd: vec3<f32>, t_max: f32) -> MajorantWalk {
+  let m = scene.media[medium];
+  let sigma_t = medium_sigma_t(m);
+  var w: MajorantWalk;
+  w.medium = medium;
+  w.t = 0.0;
+  w.t_end = t_max;
+  w.sigma_max = max(max(sigma_t.x, sigma_t.y), sigma_t.z);
+  if (m.grid.x == 0u) { return w; }
+
+  // clip to the grid bounds, then step through the cells in grid units
+  let inv_rd = safe_inverse(rd);
+  let ta = (m.grid_min.xyz - ro) * inv_rd;
+  let tb = (m.grid_max.xyz - ro) * inv_rd;
+  w.t = max(max(max(min(ta.x, tb.x), min(ta.y, tb.y)), min(ta.z, tb.z)), 0.0);
+  w.t_end = min(min(min(max(ta.x, tb.x), max(ta.y, tb.y)), max(ta.z, tb.z)), t_max);
+  let scale = f32(MAJORANT_CELLS) / (m.grid_max.xyz - m.grid_min.xyz);
+  let q = (ro + rd * w.t - m.grid_min.xyz) * scale;
+  let d = rd * scale;
+  w.cell = clamp(vec3<i32>(floor(q)), vec3<i32>(0), vec3<i32>(MAJORANT_CELLS - 1));
+  w.step = vec3<i32>(sign(d));
+  let inv_d = safe_inverse(d);
+  let next_edge = vec3<f32>(w.cell) + select(vec3<f32>(0.0), vec3<f32>(1.0), d > vec3<f32>(0.0));
+  w.t_next = select(w.t + (next_edge - q) * inv_d, vec3<f32>(1e30), d == vec3<f32>(0.0));
+  w.t_delta = abs(inv_d);
+  return w;
+}
+
+fn majorant_next(w: ptr<function, MajorantWalk>) -> MajorantSegment {
+  if ((*w).t >= (*w).t_end) { return MajorantSegment(0.0, 0.0, 0.0, false); }
+  let m = scene.media[(*w).medium];
+  let t0 = (*w).t;
+  if (m.grid.x == 0u) {
+    (*w).t = (*w).t_end;
+    return MajorantSegment(t0, (*w).t_end, (*w).sigma_max, true);
+  }
+  let c = (*w).cell;
+  let density = textureLoad(majorant_grids, vec3<i32>(c.x, c.y, c.z + i32((*w).medium) * MAJORANT_CELLS), 0).r;
+  let t_next = (*w).t_next;
+  let t1 = min(min(min(t_next.x, t_next.y), t_next.z), (*w).t_end);
+  if (t_next.x <= t_next.y && t_next.x <= t_next.z) {
+    (*w).cell.x += (*w).step.x;
+    (*w).t_next.x += (*w).t_delta.x;
+  } else if (t_next.y <= t_next.z) {
+    (*w).cell.y += (*w).step.y;
+    (*w).t_next.y += (*w).t_delta.y;
+  } else {
+    (*w).cell.z += (*w).step.z;
+    (*w).t_next.z += (*w).t_delta.z;
+  }
+  let inside = all((*w).cell >= vec3<i32>(0)) && all((*w).cell < vec3<i32>(MAJORANT_CELLS));
+  (*w).t = select((*w).t_end, t1, inside);
+  return MajorantSegment(t0, t1, density * (*w).sigma_max, true);
+}
+
+const MAX_TRACKING_STEPS: u32 = 1024u; // tentative collisions and majorant cells per ray
+
+// Transmittance along `dist` from `ro`: closed form in homogeneous media,
+// ratio tracking against the majorant grid otherwise.
+fn transmittance(medium: u32, ro: vec3<f32>, rd: vec3<f32>, dist: f32, rng: ptr<function, u32>) -> vec3<f32> {
+  if (medium == NO_MEDIUM) { return vec3<f32>(1.0); }
+  let m = scene.media[medium];
+  if (m.grid.x == 0u) { return exp(-medium_sigma_t(m) * dist); }
+
+  var walk = majorant_walk(medium, ro, rd, dist);
+  var seg = majorant_next(&walk);
+  var t = seg.t0;
+  var tr = vec3<f32>(1.0);
+  for (var i = 0u; i < MAX_TRACKING_STEPS && seg.valid; i = i + 1u) {
+    if (seg.majorant > 0.0) { t -= log(1.0 - stream_next(rng)) / seg.majorant; }
+    if (seg.majorant <= 0.0 || t >= seg.t1) {
+      seg = majorant_next(&walk);
+      t = seg.t0;
+      continue;
+    }
+    let sigma_t = medium_sigma_t(m) * medium_density(m, ro + rd * t);
+    tr *= max(1.0 - sigma_t / seg.majorant, vec3<f32>(0.0));
+    if (all(tr == vec3<f32>(0.0))) { break; }
+  }
+  return tr;
+}
+
+struct MediumEvent {
+  t        : f32,
+  weight   : vec3<f32>, // throughput factor, zero if absorbed
+  scattered: bool,
+};
+
+// Delta tracking from `ro` up to t_max against the majorant of each
+// segment: each tentative collision absorbs, scatters or is null, with
+// probabilities from the mean local coefficients and weights that correct
+// the individual channels (spectral tracking, Kutz et al. 2017).
+fn sample_medium(medium: u32, ro: vec3<f32>, rd: vec3<f32>, t_max: f32, dim: u32) -> MediumEvent {
+  let m = scene.media[medium];
+  var walk = majorant_walk(medium, ro, rd, t_max);
+  var seg = majorant_next(&walk);
+  var t = seg.t0;
+  var rng = sample_stream(dim);
+  var u = sample_1d(dim);
+  var weight = vec3<f32>(1.0);
+  for (var i = 0u; i < MAX_TRACKING_STEPS; i = i + 1u) {
+    if (!seg.valid) { return MediumEvent(t_max, weight, false); }
+    if (seg.majorant > 0.0) {
+      t -= log(1.0 - u) / seg.majorant;
+      u = stream_next(&rng);
+    }
+    if (seg.majorant <= 0.0 || t >= seg.t1) {
+      seg = majorant_next(&walk);
+      t = seg.t0;
+      continue;
+    }
+    let density = medium_density(m, ro + rd * t);
+    let sigma_a = spectrum(m.sigma_a.rgb) * density;
+    let sigma_s = spectrum(m.sigma_s.rgb) * density;
+    let sigma_n = max(vec3<f32>(seg.majorant) - sigma_a - sigma_s, vec3<f32>(0.0));
+    let p_a = (sigma_a.x + sigma_a.y + sigma_a.z) / (3.0 * seg.majorant);
+    let p_s = (sigma_s.x + sigma_s.y + sigma_s.z) / (3.0 * seg.majorant);
+    let p_n = max(1.0 - p_a - p_s, 0.0);
+    let e = stream_next(&rng);
+    if (e < p_a) { break; }
+    if (e < p_a + p_s) { return MediumEvent(t, weight * sigma_s / (seg.majorant * p_s), true); }
+    weight *= sigma_n / (seg.majorant * p_n);
+  }
+  return MediumEvent(t, vec3<f32>(0.0), false);
+}
+
+// Light reaching `ro` from light `li` along `rd`: `radiance` if only
+// interfaces lie within `dist`, or for surface lights (dist 0) the emission
+// of the surface hit if it is that light; attenuated by the media passed.
+fn shadow_ray(ro: vec3<f32>, rd: vec3<f32>, dist: f32, li: u32, radiance: vec3<f32>, medium_in: u32, dim: u32) -> vec3<f32> {
+  var o = ro;
+  var medium = medium_in;
+  var tr = vec3<f32>(1.0);
+  var travelled = 0.0;
+  var rng = sample_stream(dim + DIM_LIGHT);
+  for (var i = 0u; i < MAX_CROSSINGS; i = i + 1u) {
+    let hit = hit_scene(o, rd);
+    if (dist > 0.0 && travelled + hit.dist >= dist) {
+      return tr * transmittance(medium, o, rd, dist - travelled, &rng) * radiance;
+    }
+    if (hit.dist == 1e30) { return vec3<f32>(0.0); }
+    tr *= transmittance(medium, o, rd, hit.dist, &rng);
+    if (hit.kind != MAT_INTERFACE) {
+      if (dist == 0.0 && hit.light == li) { return tr * hit.emissive; }
+      return vec3<f32>(0.0);
+    }
+    medium = next_medium(hit, rd, medium);
+    o += rd * (hit.dist + 1e-3);
+    travelled += hit.dist + 1e-3;
+  }
+  return vec3<f32>(0.0);
+}
+
+fn power_heuristic(a: f32, b: f32) -> f32 {
+  let a2 = a * a;
+  let b2 = b * b;
+  if (a2 + b2 <= 0.0) { return 0.0; }
+  return a2 / (a2 + b2);
+}
+
 // === Entry ===
+// === Camera ===
+const PROJECTION_PERSPECTIVE: u32 = 0u;
+const PROJECTION_ORTHOGRAPHIC: u32 = 1u;
+const PROJECTION_FISHEYE: u32 = 2u;
+const PROJECTION_EQUIRECTANGULAR: u32 = 3u;
+
+const APERTURE_CIRCLE: u32 = 0u;
+const APERTURE_POLYGON: u32 = 1u;
+const APERTURE_MASK: u32 = 2u;
+
+struct CameraRay {
+  ro: vec3<f32>,
+  rd: vec3<f32>, // zero where the projection sees nothing
+};
+
+// Primary ray through `uv`, the image from -1 to 1 with y growing
+// downwards, before the thin lens.
+fn camera_ray(uv: vec2<f32>) -> CameraRay {
+  let aspect = f32(cam.img_size.x) / max(1.0, f32(cam.img_size.y));
+  // pixel rows grow downwards, the camera's up vector points upwards
+  let p = vec2<f32>(uv.x * aspect, -uv.y);
+  switch cam.projection {
+    case PROJECTION_ORTHOGRAPHIC: {
+      let offset = (cam.right * p.x + cam.up * p.y) * 0.5 * cam.ortho_height;
+      return CameraRay(cam.origin + offset, cam.dir);
+    }
+    case PROJECTION_FISHEYE: {
+      // equidistant: the angle to the view direction grows linearly
+      let r = length(p);
+      let theta = r * 0.5 * cam.fov_y;
+      if (theta > PI) { return CameraRay(cam.origin, vec3<f32>(0.0)); }
+      var side = vec2<f32>(0.0);
+      if (r > 0.0) { side = p / r; }
+      return CameraRay(cam.origin, cos(theta) * cam.dir + sin(theta) * (cam.right * side.x + cam.up * side.y));
+    }
+    case PROJECTION_EQUIRECTANGULAR: {
+      // the left eye's panorama above the right eye's for stereo
+      var v = uv.y * 0.5 + 0.5;
+      var eye = 0.0;
+      if (cam.eye_separation > 0.0) {
+        eye = select(0.5, -0.5, v < 0.5) * cam.eye_separation;
+        v = fract(v * 2.0);
+      }
+      let phi = uv.x * PI;
+      let lat = (0.5 - v) * PI;
+      let level = cam.dir * cos(phi) + cam.right * sin(phi);
+      // omni-directional stereo: the eyes sit either side of the
+      // horizontal view direction, on a circle around the camera
+      let side = cam.right * cos(phi) - cam.dir * sin(phi);
+      return CameraRay(cam.origin + side * eye, level * cos(lat) + cam.up * sin(lat));
+    }
+    default: {
+      return CameraRay(cam.origin, normalize(cam.dir + (cam.right * p.x + cam.up * p.y) * tan(0.5 * cam.fov_y)));
+    }
+  }
+}
+
+// Point on the aperture, within the unit circle, along right and up.
+fn sample_aperture(u: vec2<f32>) -> vec2<f32> {
+  if (cam.aperture_kind == APERTURE_POLYGON) {
+    // one of the equal triangles between the centre and two neighbouring
+    // corners, then a uniform point in it
+    let n = f32(cam.aperture_blades);
+    let k = min(floor(u.x * n), n - 1.0);
+    let a0 = cam.aperture_rotation + 2.0 * PI * k / n;
+    let a1 = a0 + 2.0 * PI / n;
+    let c = mix(vec2<f32>(cos(a0), sin(a0)), vec2<f32>(cos(a1), sin(a1)), u.y);
+    return sqrt(u.x * n - k) * c;
+  }
+  if (cam.aperture_kind == APERTURE_MASK && scene.aperture_mask.x > 0u) {
+    let p = sample_cdf(aperture_cdf, vec2<i32>(scene.aperture_mask), u);
+    return vec2<f32>(p.x * 2.0 - 1.0, 1.0 - p.y * 2.0);
+  }
+  let r = sqrt(u.x);
+  let phi = 2.0 * PI * u.y;
+  return r * vec2<f32>(cos(phi), sin(phi));
+}
+
+// Distance along `dir` to the first surface seen through the centre of
+// `focus_pixel`, looking through medium boundaries; -1 if the ray escapes.
+@compute @workgroup_size(1)
+fn cs_focus() {
+  let res = vec2<f32>(f32(cam.img_size.x), f32(cam.img_size.y));
+  let ray = camera_ray((vec2<f32>(cam.focus_pixel) + 0.5) / res * 2.0 - 1.0);
+  let rd = ray.rd;
+  var ro = ray.ro;
+  var depth = -1.0;
+  for (var i = 0u; i <= MAX_CROSSINGS && any(rd != vec3<f32>(0.0)); i = i + 1u) {
+    let hit = hit_scene(ro, rd);
+    if (hit.dist == 1e30) { break; }
+    let p = ro + rd * hit.dist;
+    if (hit.kind != MAT_INTERFACE) {
+      depth = dot(p - ray.ro, cam.dir);
+      break;
+    }
+    ro = p + rd * 1e-3;
+  }
+  textureStore(focus_out, vec2<i32>(0, 0), vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
+
 @compute @workgroup_size(8,8,1)
 fn cs_main(@builtin(global_invocation_id) gid: vec3<u32>) {
   if (gid.x >= cam.img_size.x || gid.y >= cam.img_size.y) { return; }
@@ -142,93 +1881,178 @@ fn cs_main(@builtin(global_invocation_id) gid: vec3<u32>) {
   let px = vec2<f32>(f32(gid.x), f32(gid.y));
   let res = vec2<f32>(f32(cam.img_size.x), f32(cam.img_size.y));
   let uv_base = (px + vec2<f32>(0.5, 0.5)) / res * 2.0 - 1.0;
-  let aspect = res.x / max(1.0, res.y);
-  let fov_tan = tan(0.5 * 60.0 * 0.0174532925);
 
   // SPP
-  let spp: u32 = 16u; // adjust 8/16/32 depending on perf
+  let spp: u32 = cam.spp;
   var sum_radiance = vec3<f32>(0.0);
 
   for (var s: u32 = 0u; s < spp; s = s + 1u) {
+    sampler_start(gid.xy, cam.frame_index * spp + s);
+    if (cam.spectral != 0u) { lambdas = sample_wavelengths(sample_1d(DIM_WAVELENGTH)); }
     // jitter
-    let jitter = vec2<f32>(
-      rand(vec2<u32>(gid.x + cam.frame_index*13u + s*97u, gid.y + s*3u)),
-      rand(vec2<u32>(gid.y + cam.frame_index*31u + s*17u, gid.x + s*5u))
-    );
+    let jitter = sample_2d(DIM_PIXEL);
     let jitter_uv = (jitter - 0.5) / res;
 
-    var rd = normalize(
-      cam.dir +
-      cam.right * (uv_base.x + jitter_uv.x) * aspect * fov_tan * 2.0 +
-      cam.up    * (uv_base.y + jitter_uv.y)           * fov_tan * 2.0
-    );
-    var ro = cam.origin;
+    let ray = camera_ray(uv_base + jitter_uv);
+    if (all(ray.rd == vec3<f32>(0.0))) { continue; }
+    var rd = ray.rd;
+    var ro = ray.ro;
+    if (cam.aperture_radius > 0.0 && cam.projection == PROJECTION_PERSPECTIVE) {
+      // thin lens: rays from all over the aperture meet where the pinhole
+      // ray crosses the plane in focus
+      let focus = ro + rd * (cam.focus_distance / dot(rd, cam.dir));
+      let lens = sample_aperture(sample_2d(DIM_LENS)) * cam.aperture_radius;
+      ro += cam.right * lens.x + cam.up * lens.y;
+      rd = normalize(focus - ro);
+    }
 
     var throughput = vec3<f32>(1.0);
     var radiance  = vec3<f32>(0.0);
     var bounce: u32 = 0u;
+    // pdf of the BSDF or phase function sample that produced `rd`, 0 for
+    // camera rays and delta lobes, and the point it left from
+    var bsdf_pdf = 0.0;
+    var prev_p = ro;
+    var prev_n = vec3<f32>(0.0);
+    // the camera is outside all volumes
+    var medium = scene.atmosphere;
+    // every ray cast, interface crossings included, gets fresh dimensions
+    var segment = 0u;
+    var crossings = 0u;
+    // set once dispersion has left only the first wavelength
+    var single_wavelength = false;
 
     loop {
       if (bounce > cam.max_bounce) { break; }
+      let dim = DIM_BOUNCE + segment * DIMS_PER_BOUNCE;
+      segment += 1u;
 
       var hit = hit_scene(ro, rd);
-      if (hit.dist == 1e30) {
-        // No sky in Cornell box → contribute nothing (black)
-        break;
+
+      // Scattering in the medium before the surface
+      var scattered = false;
+      if (medium != NO_MEDIUM) {
+        let ev = sample_medium(medium, ro, rd, hit.dist, dim + DIM_MEDIUM);
+        throughput *= ev.weight;
+        if (all(throughput == vec3<f32>(0.0))) { break; }
+        if (ev.scattered) {
+          scattered = true;
+          let p = ro + rd * ev.t;
+          let g = scene.media[medium].sigma_a.w;
+          if (scene.num_lights > 0u && bounce < cam.max_bounce) {
+            let dl = sample_direct(p, vec3<f32>(0.0), dim);
+            if (dl.pdf > 0.0) {
+              let phase = hg_phase(dot(rd, dl.wi), g);
+              let le = shadow_ray(p, dl.wi, dl.dist, dl.light, dl.radiance, medium, dim);
+              var w = 1.0;
+              if (!dl.is_delta) { w = power_heuristic(dl.pdf, phase); }
+              radiance += throughput * phase * le * w / dl.pdf;
+            }
+          }
+          // the phase function is its own pdf, the throughput is unchanged
+          let wi = sample_hg(rd, g, sample_2d(dim + DIM_BSDF));
+          bsdf_pdf = hg_phase(dot(rd, wi), g);
+          prev_p = p;
+          prev_n = vec3<f32>(0.0);
+          ro = p;
+          rd = wi;
+        }
       }
 
-      let p = ro + rd * hit.dist;
-      let n = normalize(hit.n);
+      if (!scattered) {
+        if (hit.dist == 1e30) {
+          // the environment and sun disks, weighted against light sampling;
+          // black without them
+          for (var i = 0u; i < scene.num_infinite_lights; i = i + 1u) {
+            let le = infinite_light_radiance(lights[i], rd);
+            if (le.w <= 0.0) { continue; }
+            var w = 1.0;
+            if (bsdf_pdf > 0.0) { w = power_heuristic(bsdf_pdf, light_select_pdf(i, prev_p, prev_n) * le.w); }
+            radiance += throughput * le.rgb * w;
+          }
+          break;
+        }
 
-      // Add emission if we hit the light
-      if (max(max(hit.emissive.x, hit.emissive.y), hit.emissive.z) > 0.0) {
-        radiance += throughput * hit.emissive;
-        break; // light terminates the path
-      }
+        let p = ro + rd * hit.dist;
+        hit.n = normalize(hit.n);
+        let n = hit.n;
 
-      // Scatter
-      let seed0 = rand(vec2<u32>(u32(p.x*4096.0) ^ u32(p.y*8192.0) ^ u32(p.z*16384.0) ^ cam.frame_index ^ s,
-                                 u32(gid.x + gid.y + s)));
-      let seed1 = rand(vec2<u32>(u32(p.z*2048.0) ^ cam.frame_index ^ (s*11u),
-                                 u32(gid.y*3u + 7u + s)));
-
-      if (hit.mat >= 1.5) {
-        // Glass (dielectric) — 'mat' carries IOR
-        var n_face = n;
-        var eta = 1.0 / hit.mat;
-        let cosi = dot(-rd, n);
-        if (cosi < 0.0) { n_face = -n; eta = hit.mat; } // exiting
-        let reflect_prob = schlick_fresnel(abs(dot(-rd, n_face)), hit.mat);
-        let do_reflect = rand(vec2<u32>(cam.frame_index + s, gid.x ^ gid.y)) < reflect_prob;
-        if (do_reflect) { rd = reflect(rd, n_face); }
-        else { rd = refract_ray(rd, n_face, eta); }
-        ro = p + rd * 1e-3;
-        throughput *= vec3<f32>(0.98, 0.99, 0.99);
-      } else if (hit.mat > 0.5) {
-        // Perfect mirror (not used in this file, but kept for completeness)
-        rd = reflect(rd, n);
-        ro = p + n * 1e-3;
-        throughput *= vec3<f32>(0.95);
-      } else {
-        // Diffuse bounce
-        let TBN = onb(n);
-        let local = cosine_sample_hemisphere(seed0, seed1);
-        rd = normalize(TBN * local);
-        ro = p + n * 1e-3;
-        throughput *= hit.albedo;
+        // Medium boundaries only switch the medium
+        if (hit.kind == MAT_INTERFACE) {
+          crossings += 1u;
+          if (crossings > MAX_CROSSINGS) { break; }
+          medium = next_medium(hit, rd, medium);
+          ro = p + n * select(-1e-3, 1e-3, dot(rd, n) > 0.0);
+          continue;
+        }
+
+        // Emission found by the BSDF sample, weighted against light sampling
+        if (max(max(hit.emissive.x, hit.emissive.y), hit.emissive.z) > 0.0) {
+          var w = 1.0;
+          if (bsdf_pdf > 0.0 && hit.light != NO_LIGHT) {
+            let light_p = light_select_pdf(hit.light, prev_p, prev_n)
+              * light_pdf(lights[hit.light], prev_p, rd, distance(prev_p, p));
+            w = power_heuristic(bsdf_pdf, light_p);
+          }
+          radiance += throughput * hit.emissive * w;
+        }
+
+        // Dispersive glass refracts each wavelength its own way: follow the
+        // first and count it for all three
+        if (cam.spectral != 0u && !single_wavelength && hit.kind == MAT_GLASS && hit.params[0].z > 0.0) {
+          throughput *= vec3<f32>(3.0, 0.0, 0.0);
+          single_wavelength = true;
+        }
+
+        // Next-event estimation: one light sample with a shadow ray. Light
+        // reached at the last bounce was already counted above.
+        if (scene.num_lights > 0u && bounce < cam.max_bounce && !bsdf_is_delta(hit)) {
+          let dl = sample_direct(p, n, dim);
+          if (dl.pdf > 0.0) {
+            let f = eval_bsdf(hit, rd, dl.wi);
+            if (max(max(f.x, f.y), f.z) > 0.0) {
+              let o = p + n * select(-1e-3, 1e-3, dot(dl.wi, n) > 0.0);
+              let le = shadow_ray(o, dl.wi, dl.dist, dl.light, dl.radiance, next_medium(hit, dl.wi, medium), dim);
+              var w = 1.0;
+              if (!dl.is_delta) { w = power_heuristic(dl.pdf, f.w); }
+              radiance += throughput * f.rgb * le * w / dl.pdf;
+            }
+          }
+        }
+
+        // Scatter
+        let bs = sample_bsdf(hit, rd, vec3<f32>(sample_2d(dim + DIM_BSDF), sample_1d(dim + DIM_BSDF_LOBE)));
+        // leave from the side of the surface the new ray heads to
+        ro = p + n * select(-1e-3, 1e-3, dot(bs.wi, n) > 0.0);
+        rd = bs.wi;
+        medium = next_medium(hit, rd, medium);
+        bsdf_pdf = bs.pdf;
+        prev_p = p;
+        prev_n = n;
+        throughput *= bs.weight;
+        if (all(throughput == vec3<f32>(0.0))) { break; }
       }
+      if (cam.clamp > 0.0) { throughput = min(throughput, vec3<f32>(cam.clamp)); }
 
-      throughput = min(throughput, vec3<f32>(8.0)); // gentle clamp
+      // Russian roulette: end dim paths at random, boosting the survivors
+      // so the estimate stays unbiased
+      if (bounce >= cam.rr_depth) {
+        let survive = min(max(max(throughput.x, throughput.y), throughput.z), 0.95);
+        if (sample_1d(dim + DIM_ROULETTE) >= survive) { break; }
+        throughput /= survive;
+      }
       bounce += 1u;
     }
 
+    if (cam.spectral != 0u) { radiance = spectral_to_xyz(radiance); }
     sum_radiance += radiance;
   }
 
   // Accumulate into running average
   let prev = textureLoad(accum_in, vec2<i32>(i32(gid.x), i32(gid.y)));
   let prev_count = f32(cam.frame_index * spp);
-  let new_sum = prev.rgb * prev_count + sum_radiance;
-  let new_avg = new_sum / (prev_count + f32(spp));
+  let new_sum = prev.rgb * prev_count + sum_radiance * cam.exposure;
+  // saturate rather than overflow rgba16float, e.g. looking at the sun
+  let new_avg = min(new_sum / (prev_count + f32(spp)), vec3<f32>(65504.0));
   textureStore(accum_out, vec2<i32>(i32(gid.x), i32(gid.y)), vec4<f32>(new_avg, 1.0));
 }
@@ -0,0 +1,102 @@
+/// Width and height of a tile; it wraps around at the edges.
+pub(crate) const TILE_SIZE: u32 = 64;
+/// Filter width of the energy that finds clusters and voids.
+const SIGMA: f32 = 1.9;
+/// The filter is cut off beyond this many pixels.
+const RADIUS: i32 = 6;
+
+/// Blue-noise dither tile for the blue-noise sampler (`blue_noise` in
+/// compute.wgsl), made with Ulichney's void-and-cluster method: ranks
+/// 0..TILE_SIZE², row major. Thresholding it at any rank gives evenly spread
+/// points, so ranks of neighbouring pixels differ as much as possible.
+pub(crate) fn tile(seed: u32) -> Vec<u32> {
+    let n = (TILE_SIZE * TILE_SIZE) as usize;
+    let mut field = EnergyField::new();
+
+    // initial pattern: a tenth of the pixels at random, then moved from
+    // the tightest cluster to the largest void until that changes nothing
+    let mut state = seed;
+    let mut ones = 0;
+    while ones < n / 10 {
+        state = pcg_hash(state);
+        let i = state as usize % n;
+        if !field.set[i] {
+            field.toggle(i);
+            ones += 1;
+        }
+    }
+    loop {
+        let cluster = field.tightest_cluster();
+        field.toggle(cluster);
+        let void = field.largest_void();
+        if void == cluster {
+            field.toggle(cluster);
+            break;
+        }
+        field.toggle(void);
+    }
+    let initial = field.clone();
+
+    let mut ranks = vec![0; n];
+    // below the initial pattern: take away clusters
+    for rank in (0..ones).rev() {
+        let cluster = field.tightest_cluster();
+        field.toggle(cluster);
+        ranks[cluster] = rank as u32;
+    }
+    // above it: fill voids
+    field = initial;
+    for rank in ones..n {
+        let void = field.largest_void();
+        field.toggle(void);
+        ranks[void] = rank as u32;
+    }
+    ranks
+}
+
+/// Binary pattern on the tile and the Gaussian-filtered density of its set
+/// pixels.
+#[derive(Clone)]
+struct EnergyField {
+    set: Vec<bool>,
+    energy: Vec<f32>,
+}
+
+impl EnergyField {
+    fn new() -> Self {
+        let n = (TILE_SIZE * TILE_SIZE) as usize;
+        Self { set: vec![false; n], energy: vec![0.0; n] }
+    }
+
+    fn toggle(&mut self, i: usize) {
+        self.set[i] = !self.set[i];
+        let sign = if self.set[i] { 1.0 } else { -1.0 };
+        let size = TILE_SIZE as i32;
+        let (x, y) = (i as i32 % size, i as i32 / size);
+        for dy in -RADIUS..=RADIUS {
+            for dx in -RADIUS..=RADIUS {
+                let j = ((y + dy).rem_euclid(size) * size + (x + dx).rem_euclid(size)) as usize;
+                let d2 = (dx * dx + dy * dy) as f32;
+                self.energy[j] += sign * (-d2 / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+    }
+
+    /// Set pixel with the most energy.
+    fn tightest_cluster(&self) -> usize {
+        let set = (0..self.set.len()).filter(|&i| self.set[i]);
+        set.max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b])).unwrap_or(0)
+    }
+
+    /// Unset pixel with the least energy.
+    fn largest_void(&self) -> usize {
+        let unset = (0..self.set.len()).filter(|&i| !self.set[i]);
+        unset.min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b])).unwrap_or(0)
+    }
+}
+
+fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
@@ -0,0 +1,435 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, Vec3};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb { min: Vec3::splat(f32::INFINITY), max: Vec3::splat(f32::NEG_INFINITY) };
+
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points.iter().fold(Self::EMPTY, |b, &p| b.grow(p))
+    }
+
+    pub fn grow(self, p: Vec3) -> Self {
+        Self { min: self.min.min(p), max: self.max.max(p) }
+    }
+
+    pub fn union(self, o: Aabb) -> Self {
+        Self { min: self.min.min(o.min), max: self.max.max(o.max) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Bounds of the eight transformed corners.
+    pub fn transform(&self, m: Mat4) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+        (0..8).fold(Self::EMPTY, |b, i| {
+            let corner = Vec3::select(glam::BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), self.max, self.min);
+            b.grow(m.transform_point3(corner))
+        })
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+}
+
+/// Flattened node (`BvhNode` in compute.wgsl). Nodes are stored depth first:
+/// an interior node's left child directly follows it and `left_or_first`
+/// holds the right child; a leaf (`count > 0`) covers primitives
+/// `left_or_first..left_or_first + count` of [`Bvh::indices`].
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+pub struct BvhNode {
+    pub min: [f32; 3],
+    pub left_or_first: u32,
+    pub max: [f32; 3],
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb { min: Vec3::from(self.min), max: Vec3::from(self.max) }
+    }
+}
+
+/// Build-time statistics of a [`Bvh`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BvhStats {
+    pub primitives: usize,
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: u32,
+    pub max_leaf_size: u32,
+    /// Expected cost of a random ray relative to the root, with traversal
+    /// and intersection costs of 1.
+    pub sah_cost: f32,
+    pub build_time: Duration,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} prims, {} nodes ({} leaves, max {} prims), depth {}, SAH cost {:.1}, built in {:.1} ms",
+            self.primitives,
+            self.node_count,
+            self.leaf_count,
+            self.max_leaf_size,
+            self.max_depth,
+            self.sah_cost,
+            self.build_time.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Statistics of the two-level structure built by `Renderer::upload_scene`:
+/// a top-level BVH over instances and one bottom-level BVH per mesh.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccelStats {
+    pub top: BvhStats,
+    /// All mesh BVHs combined; `sah_cost` is the triangle-weighted mean.
+    pub bottom: BvhStats,
+    pub instances: usize,
+    /// Triangles as rendered, counting every instance.
+    pub instanced_triangles: usize,
+    /// Lights sampled for next-event estimation, and the depth of the
+    /// light BVH over them.
+    pub lights: usize,
+    pub light_tree_depth: u32,
+}
+
+impl AccelStats {
+    pub(crate) fn add_bottom(&mut self, stats: &BvhStats) {
+        let b = &mut self.bottom;
+        let total = b.primitives + stats.primitives;
+        if total > 0 {
+            b.sah_cost = (b.sah_cost * b.primitives as f32 + stats.sah_cost * stats.primitives as f32) / total as f32;
+        }
+        b.primitives = total;
+        b.node_count += stats.node_count;
+        b.leaf_count += stats.leaf_count;
+        b.max_depth = b.max_depth.max(stats.max_depth);
+        b.max_leaf_size = b.max_leaf_size.max(stats.max_leaf_size);
+        b.build_time += stats.build_time;
+    }
+}
+
+impl fmt::Display for AccelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} instances, {} triangles ({} unique)", self.instances, self.instanced_triangles, self.bottom.primitives)?;
+        writeln!(f, "  top:    {}", self.top)?;
+        writeln!(f, "  bottom: {}", self.bottom)?;
+        write!(f, "  lights: {}, light tree depth {}", self.lights, self.light_tree_depth)
+    }
+}
+
+/// Bounding volume hierarchy over primitive bounds, built with binned SAH.
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// Primitive indices in leaf order; leaves index into this.
+    pub indices: Vec<u32>,
+    pub stats: BvhStats,
+}
+
+const BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 8;
+// the WGSL traversal stack holds 64 entries
+const MAX_DEPTH: u32 = 60;
+const COST_TRAVERSAL: f32 = 1.0;
+const COST_INTERSECT: f32 = 1.0;
+
+impl Bvh {
+    /// Builds a BVH over `bounds`, one box per primitive. An empty input
+    /// yields an empty node list.
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let start = Instant::now();
+        let mut builder = Builder {
+            bounds,
+            centroids: bounds.iter().map(Aabb::center).collect(),
+            indices: (0..bounds.len() as u32).collect(),
+            nodes: Vec::with_capacity(bounds.len().max(1) * 2),
+            stats: BvhStats { primitives: bounds.len(), ..Default::default() },
+        };
+        if !bounds.is_empty() {
+            builder.build_node(0, bounds.len(), 0);
+        }
+
+        let mut stats = builder.stats;
+        stats.node_count = builder.nodes.len();
+        stats.sah_cost = sah_cost(&builder.nodes);
+        stats.build_time = start.elapsed();
+        Self { nodes: builder.nodes, indices: builder.indices, stats }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::EMPTY, BvhNode::bounds)
+    }
+}
+
+struct Builder<'a> {
+    bounds: &'a [Aabb],
+    centroids: Vec<Vec3>,
+    indices: Vec<u32>,
+    nodes: Vec<BvhNode>,
+    stats: BvhStats,
+}
+
+impl Builder<'_> {
+    fn build_node(&mut self, start: usize, end: usize, depth: u32) -> u32 {
+        let node_idx = self.nodes.len();
+        self.nodes.push(BvhNode::zeroed());
+        self.stats.max_depth = self.stats.max_depth.max(depth);
+
+        let ids = &self.indices[start..end];
+        let bounds = ids.iter().fold(Aabb::EMPTY, |b, &i| b.union(self.bounds[i as usize]));
+        let cbounds = ids.iter().fold(Aabb::EMPTY, |b, &i| b.grow(self.centroids[i as usize]));
+        let count = end - start;
+
+        let split = if count <= 1 || depth >= MAX_DEPTH { None } else { self.find_split(start, end, &bounds, &cbounds) };
+        let mid = match split {
+            Some((axis, pos, cost)) if cost < count as f32 * COST_INTERSECT || count > MAX_LEAF_SIZE => {
+                let mid = self.partition(start, end, axis, pos);
+                // degenerate partition (e.g. identical centroids): split by count
+                if mid == start || mid == end { Some((start + end) / 2) } else { Some(mid) }
+            }
+            None if count > MAX_LEAF_SIZE && depth < MAX_DEPTH => Some((start + end) / 2),
+            _ => None,
+        };
+
+        let node = match mid {
+            Some(mid) => {
+                self.build_node(start, mid, depth + 1);
+                let right = self.build_node(mid, end, depth + 1);
+                BvhNode { min: bounds.min.to_array(), left_or_first: right, max: bounds.max.to_array(), count: 0 }
+            }
+            None => {
+                self.stats.leaf_count += 1;
+                self.stats.max_leaf_size = self.stats.max_leaf_size.max(count as u32);
+                BvhNode {
+                    min: bounds.min.to_array(),
+                    left_or_first: start as u32,
+                    max: bounds.max.to_array(),
+                    count: count as u32,
+                }
+            }
+        };
+        self.nodes[node_idx] = node;
+        node_idx as u32
+    }
+
+    /// Best binned SAH split as (axis, centroid position, cost).
+    fn find_split(&self, start: usize, end: usize, bounds: &Aabb, cbounds: &Aabb) -> Option<(usize, f32, f32)> {
+        let parent_area = bounds.surface_area().max(f32::MIN_POSITIVE);
+        let mut best: Option<(usize, f32, f32)> = None;
+        for axis in 0..3 {
+            let (lo, hi) = (cbounds.min[axis], cbounds.max[axis]);
+            if hi - lo <= f32::EPSILON * lo.abs().max(1.0) {
+                continue;
+            }
+            let scale = BINS as f32 / (hi - lo);
+            let mut bin_bounds = [Aabb::EMPTY; BINS];
+            let mut bin_counts = [0usize; BINS];
+            for &i in &self.indices[start..end] {
+                let b = (((self.centroids[i as usize][axis] - lo) * scale) as usize).min(BINS - 1);
+                bin_bounds[b] = bin_bounds[b].union(self.bounds[i as usize]);
+                bin_counts[b] += 1;
+            }
+
+            // sweep from the right, then evaluate the split planes from the left
+            let mut right_area = [0.0; BINS];
+            let mut right_count = [0usize; BINS];
+            let (mut acc, mut n) = (Aabb::EMPTY, 0);
+            for b in (1..BINS).rev() {
+                acc = acc.union(bin_bounds[b]);
+                n += bin_counts[b];
+                right_area[b] = acc.surface_area();
+                right_count[b] = n;
+            }
+            let (mut acc, mut n) = (Aabb::EMPTY, 0);
+            for b in 0..BINS - 1 {
+                acc = acc.union(bin_bounds[b]);
+                n += bin_counts[b];
+                if n == 0 || right_count[b + 1] == 0 {
+                    continue;
+                }
+                let cost = COST_TRAVERSAL
+                    + COST_INTERSECT * (acc.surface_area() * n as f32 + right_area[b + 1] * right_count[b + 1] as f32)
+                        / parent_area;
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, lo + (b + 1) as f32 / scale, cost));
+                }
+            }
+        }
+        best
+    }
+
+    fn partition(&mut self, start: usize, end: usize, axis: usize, pos: f32) -> usize {
+        let mut mid = start;
+        for i in start..end {
+            if self.centroids[self.indices[i] as usize][axis] < pos {
+                self.indices.swap(i, mid);
+                mid += 1;
+            }
+        }
+        mid
+    }
+}
+
+fn sah_cost(nodes: &[BvhNode]) -> f32 {
+    let Some(root) = nodes.first() else { return 0.0 };
+    let root_area = root.bounds().surface_area().max(f32::MIN_POSITIVE);
+    nodes
+        .iter()
+        .map(|n| {
+            let rel = n.bounds().surface_area() / root_area;
+            if n.is_leaf() { rel * n.count as f32 * COST_INTERSECT } else { rel * COST_TRAVERSAL }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle(a: Vec3, b: Vec3, c: Vec3) -> Aabb {
+        Aabb::from_points(&[a, b, c])
+    }
+
+    /// A row of `n` unit triangles along X, each lifted a little more in Y.
+    fn triangle_row(n: usize) -> Vec<Aabb> {
+        (0..n)
+            .map(|i| {
+                let o = Vec3::new(i as f32 * 1.5, i as f32 * 0.1, 0.0);
+                triangle(o, o + Vec3::X, o + Vec3::Y)
+            })
+            .collect()
+    }
+
+    fn contains(outer: Aabb, inner: Aabb) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    /// Walks the tree the way the shader does, checking the depth-first
+    /// layout and the bounds, and counts how often each primitive is reached.
+    fn walk(bvh: &Bvh, node: usize, depth: u32, prims: &[Aabb], seen: &mut [u32]) -> u32 {
+        assert!(depth <= MAX_DEPTH, "node {node} is {depth} deep");
+        let n = bvh.nodes[node];
+        if n.is_leaf() {
+            let first = n.left_or_first as usize;
+            for &i in &bvh.indices[first..first + n.count as usize] {
+                assert!(contains(n.bounds(), prims[i as usize]), "leaf {node} misses primitive {i}");
+                seen[i as usize] += 1;
+            }
+            return depth;
+        }
+        let (left, right) = (node + 1, n.left_or_first as usize);
+        assert!(right > left && right < bvh.nodes.len(), "node {node} has right child {right}");
+        assert!(contains(n.bounds(), bvh.nodes[left].bounds()), "node {node} misses its left child");
+        assert!(contains(n.bounds(), bvh.nodes[right].bounds()), "node {node} misses its right child");
+        let left_depth = walk(bvh, left, depth + 1, prims, seen);
+        let right_depth = walk(bvh, right, depth + 1, prims, seen);
+        // the left subtree ends right where the right one starts
+        assert_eq!(subtree_end(bvh, left), right);
+        left_depth.max(right_depth)
+    }
+
+    fn subtree_end(bvh: &Bvh, node: usize) -> usize {
+        let n = bvh.nodes[node];
+        if n.is_leaf() { node + 1 } else { subtree_end(bvh, n.left_or_first as usize) }
+    }
+
+    fn check(prims: &[Aabb]) -> Bvh {
+        let bvh = Bvh::build(prims);
+        let mut seen = vec![0; prims.len()];
+        let depth = walk(&bvh, 0, 0, prims, &mut seen);
+        assert!(seen.iter().all(|&n| n == 1), "primitives reached {seen:?} times");
+        assert_eq!(subtree_end(&bvh, 0), bvh.nodes.len());
+        assert_eq!(depth, bvh.stats.max_depth);
+        assert_eq!(bvh.stats.node_count, bvh.nodes.len());
+        bvh
+    }
+
+    #[test]
+    fn empty_input_has_no_nodes() {
+        let bvh = Bvh::build(&[]);
+        assert!(bvh.nodes.is_empty() && bvh.indices.is_empty());
+        assert!(bvh.bounds().is_empty());
+    }
+
+    #[test]
+    fn single_triangle_is_a_leaf() {
+        let prims = [triangle(Vec3::ZERO, Vec3::X, Vec3::Y)];
+        let bvh = check(&prims);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 1);
+        assert_eq!(bvh.bounds().min, Vec3::ZERO);
+        assert_eq!(bvh.bounds().max, Vec3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn separates_distant_clusters() {
+        let mut prims = triangle_row(3);
+        prims.extend(triangle_row(3).iter().map(|b| Aabb { min: b.min + 100.0, max: b.max + 100.0 }));
+        let bvh = check(&prims);
+        // SAH keeps the clusters apart rather than paying for one big leaf
+        assert!(!bvh.nodes[0].is_leaf());
+        let left = &bvh.nodes[1];
+        let right = &bvh.nodes[bvh.nodes[0].left_or_first as usize];
+        assert!(left.bounds().max.x < 50.0 && right.bounds().min.x > 50.0);
+    }
+
+    #[test]
+    fn many_triangles_fit_in_small_leaves() {
+        let prims = triangle_row(1000);
+        let bvh = check(&prims);
+        assert!(bvh.stats.max_leaf_size as usize <= MAX_LEAF_SIZE);
+        assert_eq!(bvh.bounds().min, prims[0].min);
+        assert_eq!(bvh.bounds().max, prims[999].max);
+    }
+
+    #[test]
+    fn identical_triangles_are_split_by_count() {
+        let prims = vec![triangle(Vec3::ZERO, Vec3::X, Vec3::Z); 100];
+        let bvh = check(&prims);
+        assert!(bvh.stats.max_leaf_size as usize <= MAX_LEAF_SIZE);
+    }
+
+    #[test]
+    fn skewed_input_stays_within_max_depth() {
+        // each triangle lies twice as far out as the one before, so splits
+        // peel off a few triangles at a time and the tree goes deep
+        let prims: Vec<Aabb> = (0..100)
+            .map(|i| {
+                let o = Vec3::X * 2f32.powi(i);
+                triangle(o, o + Vec3::X, o + Vec3::Y)
+            })
+            .collect();
+        let bvh = check(&prims);
+        assert!(bvh.stats.max_depth > 16);
+    }
+}
@@ -0,0 +1,307 @@
+use anyhow::{bail, Result};
+use glam::{Mat3, Vec3};
+use std::f32::consts::{PI, TAU};
+use std::str::FromStr;
+
+/// Typical distance between human eyes in metres, for stereo panoramas.
+pub const DEFAULT_EYE_SEPARATION: f32 = 0.064;
+
+/// Viewer-style input used by [`Camera::apply_movement`].
+pub enum Movement {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// Shape of the lens opening, which out-of-focus highlights take on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Aperture {
+    #[default]
+    Circle,
+    /// Regular polygon with `blades` corners (at least 3) on the circle,
+    /// turned by `rotation` radians.
+    Polygon { blades: u32, rotation: f32 },
+    /// The scene's [`crate::Scene::aperture_mask`] stretched over the
+    /// circle's bounding square, its luminance weighting the opening; a
+    /// circle if the scene has none.
+    Mask,
+}
+
+/// How film positions map to rays (`PROJECTION_*` in compute.wgsl).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Projection {
+    /// Pinhole or thin lens with the vertical field of view.
+    #[default]
+    Perspective,
+    /// Parallel rays along the view direction, across
+    /// [`Camera::ortho_height`]; for technical drawings.
+    Orthographic,
+    /// Equidistant fisheye: the angle to the view direction grows with
+    /// the distance from the image centre, reaching half the field of view
+    /// at the top and bottom edges. Angles past 180° stay black.
+    Fisheye,
+    /// Full 360° x 180° panorama centred on the view direction, or an
+    /// omni-directional stereo pair with [`Camera::eye_separation`], the
+    /// left eye above the right.
+    Equirectangular,
+}
+
+impl Projection {
+    /// The next projection, for cycling through them in the viewer.
+    pub fn next(self) -> Self {
+        match self {
+            Self::Perspective => Self::Orthographic,
+            Self::Orthographic => Self::Fisheye,
+            Self::Fisheye => Self::Equirectangular,
+            Self::Equirectangular => Self::Perspective,
+        }
+    }
+
+    /// Whether a vertical field of view can be shown: below 180° for
+    /// perspective views, up to 360° for a fisheye. Orthographic and
+    /// equirectangular views ignore it but keep it for the others.
+    pub fn supports_fov(self, fov_y_radians: f32) -> bool {
+        match self {
+            Self::Fisheye => fov_y_radians > 0.0 && fov_y_radians <= TAU,
+            _ => fov_y_radians > 0.0 && fov_y_radians < PI,
+        }
+    }
+}
+
+impl FromStr for Projection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "perspective" => Ok(Self::Perspective),
+            "orthographic" => Ok(Self::Orthographic),
+            "fisheye" => Ok(Self::Fisheye),
+            "equirectangular" => Ok(Self::Equirectangular),
+            _ => bail!("unknown projection {s:?} (expected perspective, orthographic, fisheye or equirectangular)"),
+        }
+    }
+}
+
+/// Settings of a real camera body and lens. Scene units are taken as
+/// metres and radiance as nits (cd/m²), so renders are exposed like a
+/// photograph taken with the same settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicalCamera {
+    /// Millimetres.
+    pub focal_length: f32,
+    /// Sensor height in millimetres (24 for full frame); the image height
+    /// spans it, whatever the aspect ratio.
+    pub sensor_height: f32,
+    /// Focal length over aperture diameter.
+    pub f_stop: f32,
+    /// Exposure time in seconds.
+    pub shutter_speed: f32,
+    pub iso: f32,
+}
+
+impl Default for PhysicalCamera {
+    /// A 50 mm lens on full frame at f/8, 1/125 s and ISO 100.
+    fn default() -> Self {
+        Self { focal_length: 50.0, sensor_height: 24.0, f_stop: 8.0, shutter_speed: 1.0 / 125.0, iso: 100.0 }
+    }
+}
+
+impl PhysicalCamera {
+    pub fn fov_y_radians(&self) -> f32 {
+        2.0 * (self.sensor_height / (2.0 * self.focal_length)).atan()
+    }
+
+    /// Radius of the entrance pupil in metres.
+    pub fn aperture_radius(&self) -> f32 {
+        self.focal_length / (2.0 * self.f_stop) * 1e-3
+    }
+
+    /// Scale from luminance to film values that saturate at 1, following
+    /// the saturation-based speed of ISO 12232: luminance
+    /// 78 / (0.65 ISO) N² / t fills the sensor.
+    pub fn exposure(&self) -> f32 {
+        0.65 * self.iso * self.shutter_speed / (78.0 * self.f_stop * self.f_stop)
+    }
+}
+
+/// Thin-lens camera described by a position and yaw/pitch angles (radians).
+/// With a zero aperture radius it is a pinhole and everything is in focus.
+#[derive(Clone, Copy, Debug)]
+pub struct Camera {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov_y_radians: f32,
+    /// Radius of the lens in world units; only perspective views have
+    /// depth of field.
+    pub aperture_radius: f32,
+    /// Distance of the plane in focus along the view direction.
+    pub focus_distance: f32,
+    pub aperture: Aperture,
+    /// Scales the radiance reaching the film.
+    pub exposure: f32,
+    pub projection: Projection,
+    /// Height of the orthographic view in world units.
+    pub ortho_height: f32,
+    /// Distance between the eyes of a stereo panorama; zero renders a
+    /// single one.
+    pub eye_separation: f32,
+    /// The lens the field of view, aperture and exposure were derived
+    /// from, if any; zooming changes its focal length.
+    pub physical: Option<PhysicalCamera>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: Vec3::new(0.0, 1.0, 4.0),
+            yaw: 0.0,
+            pitch: 0.0,
+            fov_y_radians: 45f32.to_radians(),
+            aperture_radius: 0.0,
+            focus_distance: 4.0,
+            aperture: Aperture::Circle,
+            exposure: 1.0,
+            projection: Projection::Perspective,
+            ortho_height: 4.0,
+            eye_separation: 0.0,
+            physical: None,
+        }
+    }
+}
+
+impl Camera {
+    /// Places the camera at `position` looking towards `target`, which is
+    /// in focus and framed alike by both perspective and orthographic views.
+    pub fn look_at(position: Vec3, target: Vec3) -> Self {
+        let dir = (target - position).normalize_or_zero();
+        let mut camera = Self {
+            position,
+            yaw: dir.z.atan2(dir.x),
+            pitch: dir.y.clamp(-1.0, 1.0).asin().clamp(-1.5, 1.5),
+            focus_distance: position.distance(target).max(1e-3),
+            ..Self::default()
+        };
+        camera.ortho_height = camera.focus_plane_height();
+        camera
+    }
+
+    /// Height the perspective view covers at the focus distance, which
+    /// makes an orthographic view of about the same framing.
+    pub fn focus_plane_height(&self) -> f32 {
+        2.0 * self.focus_distance * (0.5 * self.fov_y_radians).tan()
+    }
+
+    /// Takes the field of view, aperture and exposure from `physical`.
+    pub fn set_physical(&mut self, physical: &PhysicalCamera) {
+        self.fov_y_radians = physical.fov_y_radians();
+        self.aperture_radius = physical.aperture_radius();
+        self.exposure = physical.exposure();
+        self.physical = Some(*physical);
+    }
+
+    /// Zooms to `fov_y_radians`. A physical camera gets the focal length
+    /// that frames it, which also changes its aperture and exposure; a
+    /// field of view no rectilinear lens can reach detaches the view from
+    /// the physical model.
+    pub fn set_fov(&mut self, fov_y_radians: f32) {
+        match self.physical {
+            Some(mut physical) if fov_y_radians < PI => {
+                physical.focal_length = physical.sensor_height / (2.0 * (0.5 * fov_y_radians).tan());
+                self.set_physical(&physical);
+            }
+            _ => {
+                self.physical = None;
+                self.fov_y_radians = fov_y_radians;
+            }
+        }
+    }
+
+    /// Columns are right, up and backward (the camera looks down -Z).
+    pub fn view_basis(&self) -> Mat3 {
+        let dir = Vec3::new(
+            self.yaw.cos() * self.pitch.cos(),
+            self.pitch.sin(),
+            self.yaw.sin() * self.pitch.cos(),
+        ).normalize();
+        let right = dir.cross(Vec3::Y).normalize();
+        let up = right.cross(dir).normalize();
+        Mat3::from_cols(right, up, -dir)
+    }
+
+    pub fn apply_movement(&mut self, m: Movement) {
+        let amt = 0.2;
+        let delta = match m {
+            Movement::Forward  => Vec3::new(0.0, 0.0, -amt),
+            Movement::Backward => Vec3::new(0.0, 0.0, amt),
+            Movement::Left     => Vec3::new(-amt, 0.0, 0.0),
+            Movement::Right    => Vec3::new(amt, 0.0, 0.0),
+            Movement::Up       => Vec3::new(0.0, amt, 0.0),
+            Movement::Down     => Vec3::new(0.0, -amt, 0.0),
+        };
+        self.position += self.view_basis() * delta;
+    }
+
+    pub fn rotate(&mut self, dx: f32, dy: f32) {
+        let sensitivity = 0.0025;
+        self.yaw   -= dx * sensitivity;
+        self.pitch -= dy * sensitivity;
+        self.pitch = self.pitch.clamp(-1.5, 1.5);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() <= 1e-5 * expected.abs().max(1.0), "{actual} != {expected}");
+    }
+
+    #[test]
+    fn physical_field_of_view() {
+        // a 50 mm lens on a 24 mm high sensor sees 2 atan(12 / 50) = 26.99°
+        let camera = PhysicalCamera::default();
+        assert_near(camera.fov_y_radians().to_degrees(), 26.9915);
+        // a lens as long as half the sensor height sees 90°
+        let wide = PhysicalCamera { focal_length: 12.0, ..camera };
+        assert_near(wide.fov_y_radians(), FRAC_PI_2);
+    }
+
+    #[test]
+    fn physical_aperture_radius() {
+        // f / (2 N): a 50 mm lens at f/8 has a 3.125 mm pupil radius
+        assert_near(PhysicalCamera::default().aperture_radius(), 0.003125);
+        let fast = PhysicalCamera { focal_length: 85.0, f_stop: 1.4, ..PhysicalCamera::default() };
+        assert_near(fast.aperture_radius(), 85.0 / 2.8 * 1e-3);
+    }
+
+    #[test]
+    fn physical_exposure() {
+        // 0.65 * 100 * (1/125) / (78 * 8²) = 0.52 / 4992
+        assert_near(PhysicalCamera::default().exposure(), 1.041_667e-4);
+        // one stop more light from each of shutter, ISO and aperture
+        let brighter = PhysicalCamera { shutter_speed: 2.0 / 125.0, iso: 200.0, f_stop: 8.0 / 2f32.sqrt(), ..PhysicalCamera::default() };
+        assert_near(brighter.exposure(), 8.0 * 1.041_667e-4);
+    }
+
+    #[test]
+    fn zooming_a_physical_camera_changes_its_lens() {
+        let mut camera = Camera::default();
+        camera.set_physical(&PhysicalCamera::default());
+        camera.set_fov(FRAC_PI_2);
+        let lens = camera.physical.unwrap();
+        assert_near(lens.focal_length, 12.0);
+        assert_near(camera.fov_y_radians, FRAC_PI_2);
+        assert_near(camera.aperture_radius, lens.aperture_radius());
+        assert_near(camera.exposure, lens.exposure());
+        // no rectilinear lens covers 200°
+        camera.set_fov(200f32.to_radians());
+        assert!(camera.physical.is_none());
+        assert_eq!(camera.fov_y_radians, 200f32.to_radians());
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use std::f32::consts::PI;
+use std::path::Path;
+
+use crate::hdr_image::HdrImage;
+
+/// Equirectangular environment map: the background, and a light that is
+/// importance sampled by its luminance. The top row of the image is
+/// straight up (+Y); the image centre looks down -Z.
+#[derive(Clone, Debug)]
+pub struct Environment {
+    pub image: HdrImage,
+    /// Rotation about +Y in radians.
+    pub rotation: f32,
+    /// Scales the image's radiance.
+    pub intensity: f32,
+}
+
+impl Environment {
+    pub fn new(image: HdrImage) -> Self {
+        Self { image, rotation: 0.0, intensity: 1.0 }
+    }
+
+    /// Loads a Radiance `.hdr` or OpenEXR file (any format the `image`
+    /// crate reads works, but 8-bit images carry no real radiance).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(HdrImage::load(path).context("failed to read environment map")?))
+    }
+}
+
+/// Piecewise-constant sampling distribution over the pixels of an image
+/// (`env_cdf` and `aperture_cdf` in compute.wgsl).
+pub(crate) struct PixelDistribution {
+    /// `width` x `height + 1` texels: row `y` holds the cumulative
+    /// distribution of row `y` (the value after each pixel, ending at 1),
+    /// the last row the cumulative distribution over rows.
+    pub cdf: Vec<f32>,
+    pub width: u32,
+    /// Mean weight per pixel; zero if the map is black.
+    pub mean: f32,
+}
+
+impl PixelDistribution {
+    /// Weighted by luminance times the solid angle of each row of an
+    /// environment map.
+    pub fn environment(image: &HdrImage) -> Self {
+        let h = image.height;
+        Self::new(image, |y| (PI * (y as f32 + 0.5) / h as f32).sin())
+    }
+
+    /// Weighted by luminance.
+    pub fn by_luminance(image: &HdrImage) -> Self {
+        Self::new(image, |_| 1.0)
+    }
+
+    fn new(image: &HdrImage, row_weight: impl Fn(u32) -> f32) -> Self {
+        let (w, h) = (image.width as usize, image.height as usize);
+        // the marginal row needs one texel per image row
+        let width = w.max(h);
+        let mut cdf = vec![0.0; width * (h + 1)];
+        let mut row_sums = Vec::with_capacity(h);
+        for y in 0..h {
+            let weight = row_weight(y as u32);
+            let row = &mut cdf[y * width..y * width + w];
+            let mut acc = 0.0f64;
+            for (x, c) in row.iter_mut().enumerate() {
+                acc += (luminance(image.get(x as u32, y as u32)) * weight) as f64;
+                *c = acc as f32;
+            }
+            normalize(row, acc);
+            row_sums.push(acc);
+        }
+        let total: f64 = row_sums.iter().sum();
+        let marginal = &mut cdf[h * width..h * width + h];
+        let mut acc = 0.0f64;
+        for (c, s) in marginal.iter_mut().zip(&row_sums) {
+            acc += s;
+            *c = acc as f32;
+        }
+        normalize(marginal, acc);
+        Self { cdf, width: width as u32, mean: (total / (w * h).max(1) as f64) as f32 }
+    }
+}
+
+/// Scales a running sum to end at 1; an all-zero range becomes uniform.
+fn normalize(cdf: &mut [f32], total: f64) {
+    let n = cdf.len();
+    for (i, c) in cdf.iter_mut().enumerate() {
+        *c = if total > 0.0 { (*c as f64 / total) as f32 } else { (i + 1) as f32 / n as f32 };
+    }
+    if let Some(last) = cdf.last_mut() {
+        *last = 1.0;
+    }
+}
+
+pub(crate) fn luminance(p: [f32; 4]) -> f32 {
+    (0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, value: impl Fn(u32, u32) -> f32) -> HdrImage {
+        let pixels = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| {
+            let v = value(x, y);
+            [v, v, v, 1.0]
+        });
+        HdrImage { width, height, pixels: pixels.collect() }
+    }
+
+    /// Row `y` of the texture, `height` being the marginal row.
+    fn row(dist: &PixelDistribution, y: usize) -> &[f32] {
+        let width = dist.width as usize;
+        &dist.cdf[y * width..(y + 1) * width]
+    }
+
+    fn assert_cdf(cdf: &[f32]) {
+        assert!(cdf.windows(2).all(|p| p[0] <= p[1]), "{cdf:?} is not monotonic");
+        assert!(cdf[0] >= 0.0);
+        assert_eq!(*cdf.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn rows_and_marginal_are_cdfs() {
+        let img = image(8, 4, |x, y| (x * x + y) as f32 * 0.25);
+        let dist = PixelDistribution::environment(&img);
+        assert_eq!(dist.width, 8);
+        assert_eq!(dist.cdf.len(), 8 * 5);
+        for y in 0..4 {
+            assert_cdf(row(&dist, y));
+        }
+        assert_cdf(&row(&dist, 4)[..4]);
+        // brighter rows get a larger share of the marginal
+        let marginal = row(&dist, 4);
+        let pdf: Vec<f32> = (0..4).map(|y| marginal[y] - if y == 0 { 0.0 } else { marginal[y - 1] }).collect();
+        assert!(pdf[2] > pdf[1], "{pdf:?}");
+        assert!(dist.mean > 0.0);
+    }
+
+    #[test]
+    fn black_image_is_uniform() {
+        let dist = PixelDistribution::by_luminance(&image(4, 2, |_, _| 0.0));
+        assert_eq!(dist.mean, 0.0);
+        for y in 0..2 {
+            assert_eq!(row(&dist, y), [0.25, 0.5, 0.75, 1.0]);
+        }
+        assert_eq!(&row(&dist, 2)[..2], [0.5, 1.0]);
+    }
+
+    #[test]
+    fn tall_images_widen_the_texture_for_the_marginal() {
+        let dist = PixelDistribution::by_luminance(&image(2, 5, |x, _| x as f32 + 1.0));
+        assert_eq!(dist.width, 5);
+        assert_eq!(dist.cdf.len(), 5 * 6);
+        for y in 0..5 {
+            let r = row(&dist, y);
+            assert!((r[0] - 1.0 / 3.0).abs() < 1e-6 && r[1] == 1.0, "{r:?}");
+            assert_eq!(&r[2..], [0.0; 3]);
+        }
+        let marginal = row(&dist, 5);
+        assert_cdf(marginal);
+        assert!((marginal[0] - 0.2).abs() < 1e-6);
+        assert!((dist.mean - 1.5).abs() < 1e-6);
+    }
+}
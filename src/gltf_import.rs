@@ -0,0 +1,281 @@
+use anyhow::{bail, Context, Result};
+use glam::{Mat4, Vec2, Vec3};
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::path::Path;
+
+use gltf::khr_lights_punctual::Kind as GltfLightKind;
+
+use crate::camera::Camera;
+use crate::mesh::Mesh;
+use crate::scene::{Light, LightKind, Material, MaterialKind, MaterialTextures, Principled, Scene};
+
+/// Extensions the importer understands; anything else in `extensionsUsed`
+/// is reported in [`Scene::warnings`].
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "KHR_lights_punctual",
+    "KHR_materials_emissive_strength",
+    "KHR_materials_ior",
+    "KHR_materials_transmission",
+];
+
+/// Deeper node hierarchies are treated as cyclic.
+const MAX_NODE_DEPTH: u32 = 256;
+
+/// Imports the default scene (or the first one) of a glTF/GLB file into
+/// `scene`, placed with `transform`. Meshes are shared between the nodes
+/// that use them; the first camera found becomes [`Scene::camera`] unless
+/// one is already set.
+///
+/// Metallic-roughness materials map onto the principled BSDF, including
+/// `KHR_materials_transmission` and `KHR_materials_ior`. Base colour,
+/// metallic-roughness, normal and emissive textures are kept. Punctual
+/// lights map onto [`Scene::lights`], taking candela and lux as radiant
+/// intensity and irradiance.
+pub fn load_gltf(path: &Path, scene: &mut Scene, transform: Mat4) -> Result<()> {
+    let (doc, buffers, images) =
+        gltf::import(path).with_context(|| format!("failed to import {}", path.display()))?;
+    let mut importer = Importer {
+        name: path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned()),
+        buffers: &buffers,
+        images: &images,
+        scene,
+        materials: HashMap::new(),
+        default_material: None,
+        textures: HashMap::new(),
+        meshes: HashMap::new(),
+        warned: Vec::new(),
+    };
+
+    for ext in doc.extensions_used() {
+        if !SUPPORTED_EXTENSIONS.contains(&ext) {
+            importer.warn(format!("skipped extension {ext}"));
+        }
+    }
+
+    let Some(gltf_scene) = doc.default_scene().or_else(|| doc.scenes().next()) else {
+        importer.warn("file contains no scene".into());
+        return Ok(());
+    };
+    for node in gltf_scene.nodes() {
+        importer.node(&node, transform, 0).with_context(|| format!("invalid glTF {}", path.display()))?;
+    }
+    Ok(())
+}
+
+struct Importer<'a> {
+    name: String,
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    scene: &'a mut Scene,
+    // glTF index -> scene index
+    materials: HashMap<usize, u32>,
+    default_material: Option<u32>,
+    textures: HashMap<usize, Option<u32>>,
+    meshes: HashMap<usize, Option<u32>>,
+    warned: Vec<String>,
+}
+
+impl Importer<'_> {
+    /// Records a warning, once per distinct message.
+    fn warn(&mut self, msg: String) {
+        if !self.warned.contains(&msg) {
+            self.scene.warnings.push(format!("{}: {msg}", self.name));
+            self.warned.push(msg);
+        }
+    }
+
+    fn node(&mut self, node: &gltf::Node, parent: Mat4, depth: u32) -> Result<()> {
+        if depth > MAX_NODE_DEPTH {
+            bail!("node {} is nested too deeply, the hierarchy may be cyclic", node.index());
+        }
+        let world = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            if let Some(id) = self.mesh(&mesh)? {
+                self.scene.add_instance(id, world);
+            }
+        }
+        if let Some(camera) = node.camera() {
+            self.camera(&camera, world);
+        }
+        if let Some(light) = node.light() {
+            self.light(&light, world);
+        }
+        if node.skin().is_some() {
+            self.warn("skins are not supported, skinned meshes use their bind pose".into());
+        }
+
+        for child in node.children() {
+            self.node(&child, world, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn mesh(&mut self, mesh: &gltf::Mesh) -> Result<Option<u32>> {
+        if let Some(&id) = self.meshes.get(&mesh.index()) {
+            return Ok(id);
+        }
+
+        let mut out = Mesh::default();
+        for prim in mesh.primitives() {
+            if prim.mode() != gltf::mesh::Mode::Triangles {
+                self.warn(format!("skipped {:?} primitives, only triangles are supported", prim.mode()));
+                continue;
+            }
+            let buffers = self.buffers;
+            let reader = prim.reader(|b| buffers.get(b.index()).map(|d| d.0.as_slice()));
+            let positions: Vec<Vec3> = reader
+                .read_positions()
+                .with_context(|| format!("mesh {} has a primitive without positions", mesh.index()))?
+                .map(Vec3::from)
+                .collect();
+            let normals = reader.read_normals().map_or_else(Vec::new, |n| n.map(Vec3::from).collect());
+            let uvs = reader
+                .read_tex_coords(0)
+                .map_or_else(Vec::new, |t| t.into_f32().map(Vec2::from).collect());
+            let flat: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            if flat.iter().any(|&i| i as usize >= positions.len()) {
+                bail!("mesh {} has an index past its {} vertices", mesh.index(), positions.len());
+            }
+            let indices: Vec<[u32; 3]> = flat.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+            let material = self.material(&prim.material());
+            out.append(&Mesh {
+                positions,
+                normals,
+                uvs,
+                materials: vec![material; indices.len()],
+                indices,
+            });
+        }
+
+        let id = (out.triangle_count() > 0).then(|| self.scene.add_mesh(out));
+        self.meshes.insert(mesh.index(), id);
+        Ok(id)
+    }
+
+    fn material(&mut self, m: &gltf::Material) -> u32 {
+        let cached = match m.index() {
+            Some(i) => self.materials.get(&i),
+            None => self.default_material.as_ref(),
+        };
+        if let Some(&id) = cached {
+            return id;
+        }
+
+        let pbr = m.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base = Vec3::new(r, g, b);
+        let kind = MaterialKind::Principled(Principled {
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            transmission: m.transmission().map_or(0.0, |t| t.transmission_factor()),
+            ior: m.ior().unwrap_or(1.5),
+            ..Principled::default()
+        });
+        if m.alpha_mode() != gltf::material::AlphaMode::Opaque {
+            self.warn("alpha blending and masking are not supported, surfaces are opaque".into());
+        }
+
+        let textures = MaterialTextures {
+            albedo: pbr.base_color_texture().and_then(|t| self.texture_info(t.texture(), t.tex_coord())),
+            normal: m.normal_texture().and_then(|t| self.texture_info(t.texture(), t.tex_coord())),
+            normal_scale: m.normal_texture().map_or(1.0, |t| t.scale()),
+            emission: m.emissive_texture().and_then(|t| self.texture_info(t.texture(), t.tex_coord())),
+            metallic_roughness: pbr
+                .metallic_roughness_texture()
+                .and_then(|t| self.texture_info(t.texture(), t.tex_coord())),
+        };
+        let material = Material {
+            albedo: base,
+            emission: Vec3::from(m.emissive_factor()) * m.emissive_strength().unwrap_or(1.0),
+            kind,
+            textures,
+            medium: None,
+        };
+
+        let id = self.scene.add_material(material);
+        match m.index() {
+            Some(i) => self.materials.insert(i, id),
+            None => self.default_material.replace(id),
+        };
+        id
+    }
+
+    fn texture_info(&mut self, texture: gltf::Texture, tex_coord: u32) -> Option<u32> {
+        if tex_coord != 0 {
+            self.warn(format!("texture coordinate set {tex_coord} is not supported, using set 0"));
+        }
+        let index = texture.source().index();
+        if let Some(&id) = self.textures.get(&index) {
+            return id;
+        }
+        let id = match self.images.get(index).and_then(to_rgba8) {
+            Some(image) => {
+                self.scene.textures.push(image);
+                Some((self.scene.textures.len() - 1) as u32)
+            }
+            None => {
+                self.warn(format!("skipped image {index} with an unsupported pixel format"));
+                None
+            }
+        };
+        self.textures.insert(index, id);
+        id
+    }
+
+    fn camera(&mut self, camera: &gltf::Camera, world: Mat4) {
+        if self.scene.camera.is_some() {
+            return;
+        }
+        let gltf::camera::Projection::Perspective(p) = camera.projection() else {
+            self.warn("orthographic cameras are not supported".into());
+            return;
+        };
+        // glTF cameras look down their local -Z axis
+        let position = world.transform_point3(Vec3::ZERO);
+        let forward = world.transform_vector3(Vec3::NEG_Z).normalize_or_zero();
+        let mut cam = Camera::look_at(position, position + forward);
+        cam.fov_y_radians = p.yfov();
+        self.scene.camera = Some(cam);
+    }
+
+    fn light(&mut self, light: &gltf::khr_lights_punctual::Light, world: Mat4) {
+        if light.range().is_some() {
+            self.warn("light ranges are ignored, lights fall off with the inverse square only".into());
+        }
+        // glTF lights shine down their local -Z axis
+        let position = world.transform_point3(Vec3::ZERO);
+        let direction = world.transform_vector3(Vec3::NEG_Z).normalize_or_zero();
+        let kind = match light.kind() {
+            GltfLightKind::Point => LightKind::Point { position },
+            GltfLightKind::Spot { inner_cone_angle, outer_cone_angle } => LightKind::Spot {
+                position,
+                direction,
+                inner_angle: inner_cone_angle,
+                outer_angle: outer_cone_angle,
+            },
+            GltfLightKind::Directional => LightKind::Directional { direction, angular_radius: 0.0 },
+        };
+        self.scene.add_light(Light::new(kind, Vec3::from(light.color()), light.intensity()));
+    }
+}
+
+fn to_rgba8(data: &gltf::image::Data) -> Option<RgbaImage> {
+    use gltf::image::Format;
+    let (w, h) = (data.width, data.height);
+    let pixels: Vec<u8> = match data.format {
+        Format::R8 => data.pixels.iter().flat_map(|&r| [r, r, r, 255]).collect(),
+        Format::R8G8 => data.pixels.chunks_exact(2).flat_map(|p| [p[0], p[1], 0, 255]).collect(),
+        Format::R8G8B8 => data.pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Format::R8G8B8A8 => data.pixels.clone(),
+        // keep the high byte of little-endian 16-bit channels
+        Format::R16G16B16 => data.pixels.chunks_exact(6).flat_map(|p| [p[1], p[3], p[5], 255]).collect(),
+        Format::R16G16B16A16 => data.pixels.chunks_exact(8).flat_map(|p| [p[1], p[3], p[5], p[7]]).collect(),
+        _ => return None,
+    };
+    RgbaImage::from_raw(w, h, pixels)
+}
@@ -0,0 +1,948 @@
+use anyhow::{bail, Result};
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, Vec3, Vec4};
+use std::f32::consts::PI;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+use crate::bvh::{AccelStats, Aabb, Bvh, BvhNode};
+use crate::environment::PixelDistribution;
+use crate::hdr_image::HdrImage;
+use crate::light_bvh::{LightBounds, LightBvh, LightNode};
+use crate::scene::{ggx_alpha, Dispersion, LightKind, MaterialKind, Scene, Shape, MAX_MEDIA};
+
+const PRIM_SPHERE: u32 = 0;
+const PRIM_QUAD: u32 = 1;
+const PRIM_DISK: u32 = 2;
+const MAT_DIFFUSE: u32 = 0;
+const MAT_MIRROR: u32 = 1;
+const MAT_GLASS: u32 = 2;
+const MAT_CONDUCTOR: u32 = 3;
+const MAT_PRINCIPLED: u32 = 4;
+const MAT_INTERFACE: u32 = 5;
+const NO_MEDIUM: u32 = u32::MAX;
+/// Cells of each medium's majorant grid along every axis.
+const MAJORANT_CELLS: u32 = 16;
+const NO_TEXTURE: u32 = u32::MAX;
+const LIGHT_SPHERE: u32 = 0;
+const LIGHT_QUAD: u32 = 1;
+const LIGHT_TRIANGLE: u32 = 2;
+const LIGHT_DISK: u32 = 3;
+const LIGHT_POINT: u32 = 4;
+const LIGHT_SPOT: u32 = 5;
+const LIGHT_DIRECTIONAL: u32 = 6;
+const LIGHT_ENVIRONMENT: u32 = 7;
+const NO_LIGHT: u32 = u32::MAX;
+// texture layers are resampled to the largest input size, up to this
+const MAX_TEXTURE_SIZE: u32 = 2048;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct SceneUBO {
+    num_prims: u32,
+    num_tris: u32,
+    num_instances: u32,
+    num_lights: u32,
+    /// Environment and directional lights, at the start of the light list.
+    num_infinite_lights: u32,
+    /// Medium filling all space, `NO_MEDIUM` for vacuum.
+    atmosphere: u32,
+    /// Size of the aperture mask, zero without one.
+    aperture_mask: [u32; 2],
+    /// Intensity, cos and sin of the rotation, mean sampling weight.
+    env: [f32; 4],
+    media: [GpuMedium; MAX_MEDIA],
+}
+
+/// `Medium` in compute.wgsl.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuMedium {
+    /// Absorption + Henyey-Greenstein g.
+    sigma_a: [f32; 4],
+    sigma_s: [f32; 4],
+    /// Bounds of the density grid.
+    grid_min: [f32; 4],
+    grid_max: [f32; 4],
+    /// Grid resolution and its first slice in `density_grids`; all zero for
+    /// homogeneous media.
+    grid: [u32; 4],
+}
+
+/// `Prim` in compute.wgsl.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuPrimitive {
+    kind: u32,
+    material: u32,
+    /// Index into the light list, `NO_LIGHT` unless emissive.
+    light: u32,
+    _pad: u32,
+    // sphere: center + radius; quad: corner, edge u, edge v
+    a: [f32; 4],
+    b: [f32; 4],
+    c: [f32; 4],
+}
+
+/// `Material` in compute.wgsl.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuMaterial {
+    albedo: [f32; 3],
+    /// `MAT_*`
+    kind: u32,
+    emission: [f32; 3],
+    normal_scale: f32,
+    /// Per kind, see `Material` in compute.wgsl.
+    params: [[f32; 4]; 3],
+    // texture layers: albedo, normal, emission, metallic-roughness; NO_TEXTURE if absent
+    textures: [u32; 4],
+    /// Medium inside, `NO_MEDIUM` if none.
+    medium: u32,
+    _pad: [u32; 3],
+}
+
+/// `Vertex` in compute.wgsl; the UV rides in the `w` components.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuVertex {
+    position_u: [f32; 4],
+    normal_v: [f32; 4],
+}
+
+/// `Tri` in compute.wgsl: vertex indices and material.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuTriangle {
+    v: [u32; 3],
+    material: u32,
+    /// Rank among the mesh's emissive triangles, `NO_LIGHT` if not emissive;
+    /// each instance adds its `light_base`.
+    emitter: u32,
+    _pad: [u32; 3],
+}
+
+/// `Instance` in compute.wgsl. Rays are moved into object space with
+/// `world_to_object` and traced against the mesh BVH rooted at `blas_root`.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuInstance {
+    world_to_object: [[f32; 4]; 4],
+    object_to_world: [[f32; 4]; 4],
+    blas_root: u32,
+    light_base: u32,
+    _pad: [u32; 2],
+}
+
+/// `Light` in compute.wgsl, sampled for next-event estimation: an emissive
+/// surface in world space, a point, spot or directional light, or the
+/// environment.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+struct GpuLight {
+    kind: u32,
+    /// Leaf in the light BVH, `NO_LIGHT` for infinite lights.
+    node: u32,
+    _pad: [u32; 2],
+    // sphere: center + radius; quad: corner, edge u, edge v; triangle: p0, p1 - p0, p2 - p0;
+    // disk: center + radius, normal; point: position; spot: position, direction + cos(inner),
+    // cos(outer); directional: -, direction, (cos, 1 - cos) of the angular radius
+    a: [f32; 4],
+    b: [f32; 4],
+    c: [f32; 4],
+    /// Point, spot and directional lights; surfaces take theirs from the material.
+    emission: [f32; 4],
+}
+
+impl GpuLight {
+    fn new(kind: u32, [a, b, c]: [Vec4; 3], emission: Vec3) -> Self {
+        Self {
+            kind,
+            node: NO_LIGHT,
+            _pad: [0; 2],
+            a: a.to_array(),
+            b: b.to_array(),
+            c: c.to_array(),
+            emission: emission.extend(0.0).to_array(),
+        }
+    }
+}
+
+/// Lights as uploaded, with the bounds the light BVH is built over
+/// (`None` for the environment and directional lights).
+#[derive(Default)]
+struct LightList {
+    lights: Vec<GpuLight>,
+    bounds: Vec<Option<LightBounds>>,
+}
+
+impl LightList {
+    fn push(&mut self, light: GpuLight) -> u32 {
+        self.lights.push(light);
+        self.bounds.push(None);
+        (self.lights.len() - 1) as u32
+    }
+
+    fn len(&self) -> usize {
+        self.lights.len()
+    }
+}
+
+/// Bounds of a bounded light for the light BVH; `radiance` is the largest
+/// channel of its emission (intensity for point and spot lights).
+fn light_bounds(l: &GpuLight, radiance: f32) -> LightBounds {
+    let [a, b, c] = [l.a, l.b, l.c].map(Vec4::from);
+    let (pos, r) = (a.truncate(), a.w);
+    match l.kind {
+        LIGHT_SPHERE => LightBounds::omni(Aabb { min: pos - r, max: pos + r }, radiance * 4.0 * PI * r * r * PI),
+        LIGHT_QUAD | LIGHT_TRIANGLE => {
+            let (u, v) = (b.truncate(), c.truncate());
+            let n = u.cross(v);
+            let (area, bounds) = if l.kind == LIGHT_QUAD {
+                (n.length(), Aabb::from_points(&[pos, pos + u, pos + v, pos + u + v]))
+            } else {
+                (0.5 * n.length(), Aabb::from_points(&[pos, pos + u, pos + v]))
+            };
+            LightBounds::surface(bounds, radiance * area * PI, n.normalize_or_zero())
+        }
+        LIGHT_DISK => {
+            let n = b.truncate();
+            let extent = r * Vec3::from((Vec3::ONE - n * n).max(Vec3::ZERO).to_array().map(f32::sqrt));
+            LightBounds::surface(Aabb { min: pos - extent, max: pos + extent }, radiance * PI * r * r * PI, n)
+        }
+        LIGHT_SPOT => {
+            let (cos_inner, cos_outer) = (b.w, c.x);
+            LightBounds {
+                cos_theta_o: cos_inner,
+                cos_theta_e: (cos_outer.clamp(-1.0, 1.0).acos() - cos_inner.clamp(-1.0, 1.0).acos()).cos(),
+                ..LightBounds::surface(Aabb::from_points(&[pos]), radiance * 4.0 * PI, b.truncate())
+            }
+        }
+        _ => LightBounds::omni(Aabb::from_points(&[pos]), radiance * 4.0 * PI),
+    }
+}
+
+/// Scene buffers bound at `@group(1)` of the trace pass.
+pub(crate) struct GpuScene {
+    pub bind_group: BindGroup,
+    _buffers: Vec<Buffer>,
+    _textures: Vec<Texture>,
+}
+
+impl GpuScene {
+    pub fn bind_group_layout(device: &Device) -> BindGroupLayout {
+        let unfilterable_texture = |binding, view_dimension| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let storage = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("scene layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(1),
+                storage(2),
+                storage(3),
+                storage(4),
+                storage(5),
+                storage(6),
+                BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+                storage(9),
+                storage(10),
+                unfilterable_texture(11, TextureViewDimension::D2),
+                unfilterable_texture(12, TextureViewDimension::D2),
+                unfilterable_texture(13, TextureViewDimension::D3),
+                unfilterable_texture(14, TextureViewDimension::D3),
+                unfilterable_texture(15, TextureViewDimension::D2),
+            ],
+        })
+    }
+
+    pub fn upload(device: &Device, queue: &Queue, layout: &BindGroupLayout, scene: &Scene) -> Result<(Self, AccelStats)> {
+        let (texture, layers) = upload_textures(device, queue, scene);
+        let layer = |t: Option<u32>| t.filter(|&t| t < layers).unwrap_or(NO_TEXTURE);
+        let medium = |m: Option<u32>| m.filter(|&m| (m as usize) < scene.media.len().min(MAX_MEDIA)).unwrap_or(NO_MEDIUM);
+        let mut materials: Vec<GpuMaterial> = scene
+            .materials
+            .iter()
+            .map(|m| {
+                let (kind, params) = match m.kind {
+                    MaterialKind::Diffuse => (MAT_DIFFUSE, [[0.0; 4]; 3]),
+                    MaterialKind::Interface => (MAT_INTERFACE, [[0.0; 4]; 3]),
+                    MaterialKind::Mirror => (MAT_MIRROR, [[0.0; 4]; 3]),
+                    MaterialKind::Glass { ior, roughness, dispersion } => {
+                        let alpha = ggx_alpha(roughness);
+                        let params = match dispersion {
+                            Dispersion::None => [[ior, alpha, 0.0, 0.0], [0.0; 4], [0.0; 4]],
+                            Dispersion::Cauchy { a, b } => [[ior, alpha, 1.0, 0.0], [a, b, 0.0, 0.0], [0.0; 4]],
+                            Dispersion::Sellmeier { b, c } => [
+                                [ior, alpha, 2.0, 0.0],
+                                [b[0], b[1], b[2], 0.0],
+                                [c[0], c[1], c[2], 0.0],
+                            ],
+                        };
+                        (MAT_GLASS, params)
+                    }
+                    MaterialKind::Conductor { roughness, complex_ior } => {
+                        let alpha = ggx_alpha(roughness);
+                        let params = match complex_ior {
+                            Some(c) => [c.eta.extend(alpha).to_array(), c.k.extend(1.0).to_array(), [0.0; 4]],
+                            None => [[0.0, 0.0, 0.0, alpha], [0.0; 4], [0.0; 4]],
+                        };
+                        (MAT_CONDUCTOR, params)
+                    }
+                    // roughness is squared in the shader, after the texture is applied
+                    MaterialKind::Principled(p) => (
+                        MAT_PRINCIPLED,
+                        [
+                            [p.metallic, p.roughness, p.specular, p.specular_tint],
+                            [p.clearcoat, p.clearcoat_gloss, p.sheen, p.sheen_tint],
+                            [p.transmission, p.subsurface, p.ior, 0.0],
+                        ],
+                    ),
+                };
+                let t = &m.textures;
+                GpuMaterial {
+                    albedo: m.albedo.to_array(),
+                    kind,
+                    emission: m.emission.to_array(),
+                    normal_scale: t.normal_scale,
+                    params,
+                    textures: [layer(t.albedo), layer(t.normal), layer(t.emission), layer(t.metallic_roughness)],
+                    medium: medium(m.medium),
+                    _pad: [0; 3],
+                }
+            })
+            .collect();
+
+        // Emissive primitives and area lights are sampled as lights and
+        // are also hit by rays; point, spot and directional lights are only
+        // sampled. The environment and directional lights come first, the
+        // light BVH holds the rest.
+        let emissive: Vec<f32> = scene.materials.iter().map(|m| m.emission.max_element()).collect();
+        let radiance = |material: u32| emissive.get(material as usize).copied().unwrap_or(0.0);
+        let mut lights = LightList::default();
+        let env = upload_environment(device, queue, scene);
+        if env.params[0] > 0.0 && env.params[3] > 0.0 {
+            lights.push(GpuLight::new(LIGHT_ENVIRONMENT, [Vec4::ZERO; 3], Vec3::ZERO));
+        }
+        for l in &scene.lights {
+            if let LightKind::Directional { direction, angular_radius } = l.kind {
+                let b = direction.normalize_or_zero().extend(0.0);
+                // 1 - cos without cancellation for the sun's half degree
+                let half = (angular_radius / 2.0).sin();
+                let c = Vec4::new(angular_radius.cos(), 2.0 * half * half, 0.0, 0.0);
+                lights.push(GpuLight::new(LIGHT_DIRECTIONAL, [Vec4::ZERO, b, c], l.color * l.intensity));
+            }
+        }
+        let num_infinite_lights = lights.len() as u32;
+
+        let mut prims: Vec<GpuPrimitive> = Vec::with_capacity(scene.primitives.len());
+        let mut push_prim = |shape: Shape, material: u32, radiance: f32, lights: &mut LightList| {
+            let (kind, light_kind, a, b, c) = match shape {
+                Shape::Sphere { center, radius } => (PRIM_SPHERE, LIGHT_SPHERE, center.extend(radius), Vec4::ZERO, Vec4::ZERO),
+                Shape::Quad { corner, u, v } => (PRIM_QUAD, LIGHT_QUAD, corner.extend(0.0), u.extend(0.0), v.extend(0.0)),
+                Shape::Disk { center, normal, radius } => {
+                    (PRIM_DISK, LIGHT_DISK, center.extend(radius), normal.normalize_or_zero().extend(0.0), Vec4::ZERO)
+                }
+            };
+            let mut light = NO_LIGHT;
+            if radiance > 0.0 {
+                light = lights.push(GpuLight::new(light_kind, [a, b, c], Vec3::ZERO));
+                lights.bounds[light as usize] = Some(light_bounds(&lights.lights[light as usize], radiance));
+            }
+            let (a, b, c) = (a.to_array(), b.to_array(), c.to_array());
+            prims.push(GpuPrimitive { kind, material, light, _pad: 0, a, b, c });
+        };
+        for p in &scene.primitives {
+            push_prim(p.shape, p.material, radiance(p.material), &mut lights);
+        }
+        for l in &scene.lights {
+            let emission = l.color * l.intensity;
+            if let Some(shape) = l.shape() {
+                materials.push(GpuMaterial {
+                    albedo: [0.0; 3],
+                    kind: MAT_DIFFUSE,
+                    emission: emission.to_array(),
+                    normal_scale: 1.0,
+                    params: [[0.0; 4]; 3],
+                    textures: [NO_TEXTURE; 4],
+                    medium: NO_MEDIUM,
+                    _pad: [0; 3],
+                });
+                push_prim(shape, materials.len() as u32 - 1, emission.max_element(), &mut lights);
+                continue;
+            }
+            let (kind, abc) = match l.kind {
+                LightKind::Point { position } => (LIGHT_POINT, [position.extend(0.0), Vec4::ZERO, Vec4::ZERO]),
+                LightKind::Spot { position, direction, inner_angle, outer_angle } => (
+                    LIGHT_SPOT,
+                    [
+                        position.extend(0.0),
+                        direction.normalize_or_zero().extend(inner_angle.cos()),
+                        Vec4::new(outer_angle.cos(), 0.0, 0.0, 0.0),
+                    ],
+                ),
+                // added above
+                LightKind::Directional { .. } => continue,
+                LightKind::Rect { .. } | LightKind::Disk { .. } | LightKind::Sphere { .. } => unreachable!("area light"),
+            };
+            let i = lights.push(GpuLight::new(kind, abc, emission));
+            lights.bounds[i as usize] = Some(light_bounds(&lights.lights[i as usize], emission.max_element()));
+        }
+
+        // One BVH per mesh over its object-space triangles; leaves reference
+        // contiguous triangle ranges, so triangles are stored in BVH order.
+        // The node buffer holds the instance BVH first, then the mesh BVHs.
+        let mut stats = AccelStats::default();
+        let mut verts = Vec::new();
+        let mut tris: Vec<GpuTriangle> = Vec::new();
+        let mut blas_nodes: Vec<BvhNode> = Vec::new();
+        // per mesh: root node within `blas_nodes` and object-space bounds
+        let mut blas: Vec<Option<(u32, Aabb)>> = Vec::with_capacity(scene.meshes.len());
+        for mesh in &scene.meshes {
+            let base = verts.len() as u32;
+            for (i, p) in mesh.positions.iter().enumerate() {
+                let n = mesh.normals.get(i).copied().unwrap_or_default();
+                let uv = mesh.uvs.get(i).copied().unwrap_or_default();
+                verts.push(GpuVertex {
+                    position_u: p.extend(uv.x).to_array(),
+                    normal_v: n.extend(uv.y).to_array(),
+                });
+            }
+            let bounds: Vec<Aabb> = mesh
+                .indices
+                .iter()
+                .map(|t| Aabb::from_points(&t.map(|i| mesh.positions[i as usize])))
+                .collect();
+            let bvh = Bvh::build(&bounds);
+            stats.add_bottom(&bvh.stats);
+            if bvh.nodes.is_empty() {
+                blas.push(None);
+                continue;
+            }
+
+            let (node_base, tri_base) = (blas_nodes.len() as u32, tris.len() as u32);
+            blas_nodes.extend(bvh.nodes.iter().map(|n| BvhNode {
+                left_or_first: n.left_or_first + if n.is_leaf() { tri_base } else { node_base },
+                ..*n
+            }));
+            // emissive triangles are ranked in mesh order, see GpuTriangle::emitter
+            let mut emitters = vec![NO_LIGHT; mesh.indices.len()];
+            let mut rank = 0;
+            for (e, &m) in emitters.iter_mut().zip(&mesh.materials) {
+                if radiance(m) > 0.0 {
+                    *e = rank;
+                    rank += 1;
+                }
+            }
+            tris.extend(bvh.indices.iter().map(|&i| GpuTriangle {
+                v: mesh.indices[i as usize].map(|v| v + base),
+                material: mesh.materials[i as usize],
+                emitter: emitters[i as usize],
+                _pad: [0; 3],
+            }));
+            blas.push(Some((node_base, bvh.bounds())));
+        }
+
+        let placed: Vec<(Mat4, u32, Aabb, u32)> = scene
+            .instances
+            .iter()
+            .filter_map(|inst| {
+                let (root, bounds) = blas.get(inst.mesh as usize).copied().flatten()?;
+                stats.instanced_triangles += scene.meshes[inst.mesh as usize].triangle_count();
+                Some((inst.transform, root, bounds.transform(inst.transform), inst.mesh))
+            })
+            .collect();
+        let tlas = Bvh::build(&placed.iter().map(|p| p.2).collect::<Vec<_>>());
+        stats.top = tlas.stats;
+        stats.instances = placed.len();
+        let tlas_len = tlas.nodes.len() as u32;
+        let mut instances: Vec<GpuInstance> = tlas
+            .indices
+            .iter()
+            .map(|&i| {
+                let (transform, root, _, mesh) = placed[i as usize];
+                let light_base = lights.len() as u32;
+                let mesh = &scene.meshes[mesh as usize];
+                // keep (p1 - p0) x (p2 - p0) on the emitting side under mirroring
+                let flip = transform.determinant() < 0.0;
+                for (t, &m) in mesh.indices.iter().zip(&mesh.materials) {
+                    let radiance = radiance(m);
+                    if radiance > 0.0 {
+                        let [p0, mut p1, mut p2] = t.map(|v| transform.transform_point3(mesh.positions[v as usize]));
+                        if flip {
+                            std::mem::swap(&mut p1, &mut p2);
+                        }
+                        let abc = [p0.extend(0.0), (p1 - p0).extend(0.0), (p2 - p0).extend(0.0)];
+                        let i = lights.push(GpuLight::new(LIGHT_TRIANGLE, abc, Vec3::ZERO));
+                        lights.bounds[i as usize] = Some(light_bounds(&lights.lights[i as usize], radiance));
+                    }
+                }
+                GpuInstance {
+                    world_to_object: transform.inverse().to_cols_array_2d(),
+                    object_to_world: transform.to_cols_array_2d(),
+                    blas_root: root + tlas_len,
+                    light_base,
+                    _pad: [0; 2],
+                }
+            })
+            .collect();
+        let mut nodes = tlas.nodes;
+        nodes.extend(blas_nodes.iter().map(|n| BvhNode {
+            left_or_first: n.left_or_first + if n.is_leaf() { 0 } else { tlas_len },
+            ..*n
+        }));
+
+        let LightList { mut lights, bounds } = lights;
+        let bounded: Vec<(u32, LightBounds)> =
+            bounds.iter().enumerate().filter_map(|(i, b)| Some((i as u32, (*b)?))).collect();
+        let light_bvh = LightBvh::build(&bounded);
+        for (&(i, _), &leaf) in bounded.iter().zip(&light_bvh.leaves) {
+            lights[i as usize].node = leaf;
+        }
+        stats.lights = lights.len();
+        stats.light_tree_depth = light_bvh.depth;
+        let mut light_nodes = light_bvh.nodes;
+
+        let mut ubo = SceneUBO {
+            num_prims: prims.len() as u32,
+            num_tris: tris.len() as u32,
+            num_instances: instances.len() as u32,
+            num_lights: lights.len() as u32,
+            num_infinite_lights,
+            atmosphere: medium(scene.atmosphere),
+            aperture_mask: [0; 2],
+            env: env.params,
+            media: [GpuMedium::zeroed(); MAX_MEDIA],
+        };
+        let grids = upload_grids(device, queue, scene)?;
+        let aperture_cdf = upload_aperture_mask(device, queue, scene, &mut ubo.aperture_mask);
+        for (gpu, m) in ubo.media.iter_mut().zip(&scene.media) {
+            *gpu = GpuMedium {
+                sigma_a: m.absorption.extend(m.g).to_array(),
+                sigma_s: m.scattering.extend(0.0).to_array(),
+                ..GpuMedium::zeroed()
+            };
+            if let Some(id) = m.density {
+                let grid = &scene.grids[id as usize];
+                gpu.grid_min = grid.min.extend(0.0).to_array();
+                gpu.grid_max = grid.max.extend(0.0).to_array();
+                let [x, y, z] = grid.resolution;
+                gpu.grid = [x, y, z, grids.slices[id as usize]];
+            }
+        }
+        // storage bindings must not be empty
+        if prims.is_empty() {
+            prims.push(GpuPrimitive::zeroed());
+        }
+        if materials.is_empty() {
+            materials.push(GpuMaterial::zeroed());
+        }
+        if verts.is_empty() {
+            verts.push(GpuVertex::zeroed());
+        }
+        if tris.is_empty() {
+            tris.push(GpuTriangle::zeroed());
+        }
+        if nodes.is_empty() {
+            nodes.push(BvhNode::zeroed());
+        }
+        if instances.is_empty() {
+            instances.push(GpuInstance::zeroed());
+        }
+        if lights.is_empty() {
+            lights.push(GpuLight::zeroed());
+        }
+        if light_nodes.is_empty() {
+            light_nodes.push(LightNode::zeroed());
+        }
+
+        let ubo_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("scene ubo"),
+            contents: bytemuck::bytes_of(&ubo),
+            usage: BufferUsages::UNIFORM,
+        });
+        let prim_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("primitives"),
+            contents: bytemuck::cast_slice(&prims),
+            usage: BufferUsages::STORAGE,
+        });
+        let material_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("materials"),
+            contents: bytemuck::cast_slice(&materials),
+            usage: BufferUsages::STORAGE,
+        });
+        let vertex_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("vertices"),
+            contents: bytemuck::cast_slice(&verts),
+            usage: BufferUsages::STORAGE,
+        });
+        let tri_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("triangles"),
+            contents: bytemuck::cast_slice(&tris),
+            usage: BufferUsages::STORAGE,
+        });
+
+        let node_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("bvh nodes"),
+            contents: bytemuck::cast_slice(&nodes),
+            usage: BufferUsages::STORAGE,
+        });
+        let instance_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("instances"),
+            contents: bytemuck::cast_slice(&instances),
+            usage: BufferUsages::STORAGE,
+        });
+
+        let light_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("lights"),
+            contents: bytemuck::cast_slice(&lights),
+            usage: BufferUsages::STORAGE,
+        });
+        let light_node_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("light bvh nodes"),
+            contents: bytemuck::cast_slice(&light_nodes),
+            usage: BufferUsages::STORAGE,
+        });
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("texture sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("scene bind"),
+            layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: ubo_buf.as_entire_binding() },
+                BindGroupEntry { binding: 1, resource: prim_buf.as_entire_binding() },
+                BindGroupEntry { binding: 2, resource: material_buf.as_entire_binding() },
+                BindGroupEntry { binding: 3, resource: vertex_buf.as_entire_binding() },
+                BindGroupEntry { binding: 4, resource: tri_buf.as_entire_binding() },
+                BindGroupEntry { binding: 5, resource: node_buf.as_entire_binding() },
+                BindGroupEntry { binding: 6, resource: instance_buf.as_entire_binding() },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: BindingResource::TextureView(&texture.create_view(&TextureViewDescriptor {
+                        dimension: Some(TextureViewDimension::D2Array),
+                        ..Default::default()
+                    })),
+                },
+                BindGroupEntry { binding: 8, resource: BindingResource::Sampler(&sampler) },
+                BindGroupEntry { binding: 9, resource: light_buf.as_entire_binding() },
+                BindGroupEntry { binding: 10, resource: light_node_buf.as_entire_binding() },
+                BindGroupEntry {
+                    binding: 11,
+                    resource: BindingResource::TextureView(&env.map.create_view(&TextureViewDescriptor::default())),
+                },
+                BindGroupEntry {
+                    binding: 12,
+                    resource: BindingResource::TextureView(&env.cdf.create_view(&TextureViewDescriptor::default())),
+                },
+                BindGroupEntry {
+                    binding: 13,
+                    resource: BindingResource::TextureView(&grids.density.create_view(&TextureViewDescriptor::default())),
+                },
+                BindGroupEntry {
+                    binding: 14,
+                    resource: BindingResource::TextureView(&grids.majorants.create_view(&TextureViewDescriptor::default())),
+                },
+                BindGroupEntry {
+                    binding: 15,
+                    resource: BindingResource::TextureView(&aperture_cdf.create_view(&TextureViewDescriptor::default())),
+                },
+            ],
+        });
+
+        let buffers = vec![ubo_buf, prim_buf, material_buf, vertex_buf, tri_buf, node_buf, instance_buf, light_buf, light_node_buf];
+        Ok((Self { bind_group, _buffers: buffers, _textures: vec![texture, env.map, env.cdf, grids.density, grids.majorants, aperture_cdf] }, stats))
+    }
+}
+
+/// Packs [`Scene::textures`] into one RGBA8 array texture, resampling them
+/// to a common size. Returns the texture and how many layers it holds;
+/// textures past the device's layer limit are dropped. Without textures a
+/// single white texel is uploaded, since the binding must not be empty.
+fn upload_textures(device: &Device, queue: &Queue, scene: &Scene) -> (Texture, u32) {
+    let limits = device.limits();
+    let max_size = MAX_TEXTURE_SIZE.min(limits.max_texture_dimension_2d);
+    let layers = (scene.textures.len() as u32).min(limits.max_texture_array_layers);
+    let (width, height) = scene.textures[..layers as usize]
+        .iter()
+        .fold((1, 1), |(w, h), t| (w.max(t.width()), h.max(t.height())));
+    let (width, height) = (width.min(max_size), height.min(max_size));
+
+    let mut data = Vec::with_capacity((width * height * layers.max(1) * 4) as usize);
+    for t in &scene.textures[..layers as usize] {
+        if t.dimensions() == (width, height) {
+            data.extend_from_slice(t.as_raw());
+        } else {
+            let resized = image::imageops::resize(t, width, height, image::imageops::FilterType::Triangle);
+            data.extend_from_slice(resized.as_raw());
+        }
+    }
+    if layers == 0 {
+        data.extend_from_slice(&[255; 4]);
+    }
+
+    let texture = device.create_texture_with_data(
+        queue,
+        &TextureDescriptor {
+            label: Some("material textures"),
+            size: Extent3d { width, height, depth_or_array_layers: layers.max(1) },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        util::TextureDataOrder::LayerMajor,
+        &data,
+    );
+    (texture, layers)
+}
+
+struct GpuEnvironment {
+    /// Linear RGBA radiance (`env_map` in compute.wgsl).
+    map: Texture,
+    /// Sampling distribution, see [`PixelDistribution`] (`env_cdf`).
+    cdf: Texture,
+    /// `SceneUBO::env`
+    params: [f32; 4],
+}
+
+/// Uploads [`Scene::environment`] and its sampling distribution, shrinking
+/// maps larger than the device allows. Without an environment both
+/// textures hold a single black texel and the intensity is zero.
+fn upload_environment(device: &Device, queue: &Queue, scene: &Scene) -> GpuEnvironment {
+    let black = HdrImage { width: 1, height: 1, pixels: vec![[0.0; 4]] };
+    let (image, params) = match &scene.environment {
+        Some(env) => {
+            // the distribution needs a row more than the image and a texel per row
+            let max_size = device.limits().max_texture_dimension_2d;
+            let (w, h) = (env.image.width.max(1), env.image.height.max(1));
+            let scale = (max_size as f32 / w.max(h + 1) as f32).min(1.0);
+            let image = if scale < 1.0 {
+                resize_hdr(&env.image, ((w as f32 * scale) as u32).max(1), ((h as f32 * scale) as u32).max(1))
+            } else {
+                env.image.clone()
+            };
+            (image, [env.intensity, env.rotation.cos(), env.rotation.sin(), 0.0])
+        }
+        None => (black, [0.0, 1.0, 0.0, 0.0]),
+    };
+    let dist = PixelDistribution::environment(&image);
+
+    let texture = |label, width, height, format, data: &[u8]| {
+        device.create_texture_with_data(
+            queue,
+            &TextureDescriptor {
+                label: Some(label),
+                size: Extent3d { width, height, depth_or_array_layers: 1 },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            util::TextureDataOrder::LayerMajor,
+            data,
+        )
+    };
+    GpuEnvironment {
+        map: texture(
+            "environment",
+            image.width,
+            image.height,
+            TextureFormat::Rgba32Float,
+            bytemuck::cast_slice(&image.pixels),
+        ),
+        cdf: texture(
+            "environment cdf",
+            dist.width,
+            image.height + 1,
+            TextureFormat::R32Float,
+            bytemuck::cast_slice(&dist.cdf),
+        ),
+        params: [params[0], params[1], params[2], dist.mean],
+    }
+}
+
+/// Uploads the sampling distribution of [`Scene::aperture_mask`]
+/// (`aperture_cdf` in compute.wgsl) and stores the mask's size in `size`.
+/// Masks larger than the device allows are shrunk; without one a single
+/// texel is uploaded and the size stays zero.
+fn upload_aperture_mask(device: &Device, queue: &Queue, scene: &Scene, size: &mut [u32; 2]) -> Texture {
+    let dist = match &scene.aperture_mask {
+        Some(mask) => {
+            let max_size = device.limits().max_texture_dimension_2d;
+            let (w, h) = (mask.width.max(1), mask.height.max(1));
+            let scale = (max_size as f32 / w.max(h + 1) as f32).min(1.0);
+            let mask = if scale < 1.0 {
+                resize_hdr(mask, ((w as f32 * scale) as u32).max(1), ((h as f32 * scale) as u32).max(1))
+            } else {
+                mask.clone()
+            };
+            *size = [mask.width, mask.height];
+            PixelDistribution::by_luminance(&mask)
+        }
+        None => PixelDistribution { cdf: vec![1.0; 2], width: 1, mean: 0.0 },
+    };
+    device.create_texture_with_data(
+        queue,
+        &TextureDescriptor {
+            label: Some("aperture cdf"),
+            size: Extent3d { width: dist.width, height: dist.cdf.len() as u32 / dist.width, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&dist.cdf),
+    )
+}
+
+struct GpuGrids {
+    /// [`Scene::grids`] stacked along z (`density_grids` in compute.wgsl).
+    density: Texture,
+    /// A [`MAJORANT_CELLS`]³ block per medium, stacked along z in the order
+    /// of [`Scene::media`] (`majorant_grids`).
+    majorants: Texture,
+    /// First slice of each grid in `density`.
+    slices: Vec<u32>,
+}
+
+/// Packs the density grids of [`Scene::grids`] into one R32 3D texture,
+/// padding them to the largest width and height, and fails if they exceed
+/// the device's size limit. Also builds the majorant grid of every medium
+/// with a density grid. Without grids both hold a single zero texel.
+fn upload_grids(device: &Device, queue: &Queue, scene: &Scene) -> Result<GpuGrids> {
+    let max_size = device.limits().max_texture_dimension_3d;
+    if let Some((i, g)) = scene.grids.iter().enumerate().find(|(_, g)| g.resolution.iter().any(|&r| r > max_size)) {
+        bail!("density grid {i} of {:?} voxels exceeds the GPU's 3D texture size of {max_size}", g.resolution);
+    }
+    let depth = scene.grids.iter().map(|g| g.resolution[2] as u64).sum::<u64>();
+    if depth > max_size as u64 {
+        bail!("density grids stack {depth} voxels deep, past the GPU's 3D texture size of {max_size}");
+    }
+    let width = scene.grids.iter().map(|g| g.resolution[0]).max().unwrap_or(1);
+    let height = scene.grids.iter().map(|g| g.resolution[1]).max().unwrap_or(1);
+    let mut depth = 0;
+    let mut density = Vec::new();
+    let slices = scene
+        .grids
+        .iter()
+        .map(|g| {
+            let [rx, ry, rz] = g.resolution;
+            let mut slab = vec![0.0f32; (width * height * rz) as usize];
+            for z in 0..rz {
+                for y in 0..ry {
+                    let src = ((z * ry + y) * rx) as usize;
+                    let dst = ((z * height + y) * width) as usize;
+                    slab[dst..dst + rx as usize].copy_from_slice(&g.density[src..src + rx as usize]);
+                }
+            }
+            density.extend(slab);
+            depth += rz;
+            depth - rz
+        })
+        .collect::<Vec<_>>();
+
+    let cells = MAJORANT_CELLS;
+    let block = (cells * cells * cells) as usize;
+    let mut majorants = vec![0.0f32; block * scene.media.len().max(1)];
+    for (i, m) in scene.media.iter().enumerate() {
+        if let Some(id) = m.density {
+            majorants[i * block..(i + 1) * block].copy_from_slice(&scene.grids[id as usize].majorants(cells));
+        }
+    }
+
+    let texture = |label, size, data: &[f32]| {
+        device.create_texture_with_data(
+            queue,
+            &TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D3,
+                format: TextureFormat::R32Float,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(data),
+        )
+    };
+    let (density_size, majorant_size) = if depth == 0 {
+        density.push(0.0);
+        majorants.truncate(1);
+        (Extent3d::default(), Extent3d::default())
+    } else {
+        (
+            Extent3d { width, height, depth_or_array_layers: depth },
+            Extent3d { width: cells, height: cells, depth_or_array_layers: cells * scene.media.len() as u32 },
+        )
+    };
+    Ok(GpuGrids {
+        density: texture("density grids", density_size, &density),
+        majorants: texture("majorant grids", majorant_size, &majorants),
+        slices,
+    })
+}
+
+fn resize_hdr(image: &HdrImage, width: u32, height: u32) -> HdrImage {
+    let raw = image.pixels.iter().flatten().copied().collect();
+    let buf = image::Rgba32FImage::from_raw(image.width, image.height, raw).expect("pixel count matches size");
+    let resized = image::imageops::resize(&buf, width, height, image::imageops::FilterType::Triangle);
+    HdrImage { width, height, pixels: resized.pixels().map(|p| p.0).collect() }
+}
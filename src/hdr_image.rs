@@ -0,0 +1,91 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// File formats [`HdrImage::save`] can write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Tonemapped 8-bit RGBA.
+    Png,
+    /// Linear Radiance RGBE.
+    Hdr,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "png" => Ok(Self::Png),
+            "hdr" => Ok(Self::Hdr),
+            _ => bail!("unsupported output format {:?} (expected .png or .hdr)", path),
+        }
+    }
+}
+
+/// Linear RGBA radiance, read back from the accumulation buffer or loaded as
+/// an [`crate::Environment`].
+#[derive(Clone, Debug)]
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    /// Row major, starting at the top-left pixel.
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl HdrImage {
+    /// Loads any format the `image` crate reads, e.g. Radiance `.hdr`,
+    /// OpenEXR or PNG; 8-bit images come out in [0, 1].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let img = image::open(path)
+            .with_context(|| format!("failed to read image {}", path.display()))?
+            .into_rgba32f();
+        let (width, height) = img.dimensions();
+        let pixels = img.pixels().map(|p| p.0).collect();
+        Ok(Self { width, height, pixels })
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [f32; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Tonemaps to 8-bit sRGB-ish RGBA exactly like the viewer's blit pass.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pixels.len() * 4);
+        for p in &self.pixels {
+            for c in &p[..3] {
+                let v = aces_tonemap(c.max(0.0)).powf(1.0 / 2.2);
+                out.push((v * 255.0 + 0.5) as u8);
+            }
+            out.push(255);
+        }
+        out
+    }
+
+    /// Writes the image in the format implied by the file extension.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        match OutputFormat::from_path(path)? {
+            OutputFormat::Png => {
+                let buf = image::RgbaImage::from_raw(self.width, self.height, self.to_rgba8())
+                    .context("image size does not match pixel data")?;
+                buf.save(path)
+            }
+            OutputFormat::Hdr => {
+                let rgb = self.pixels.iter().flat_map(|p| [p[0], p[1], p[2]]).collect();
+                let buf = image::Rgb32FImage::from_raw(self.width, self.height, rgb)
+                    .context("image size does not match pixel data")?;
+                buf.save(path)
+            }
+        }
+        .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+fn aces_tonemap(x: f32) -> f32 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0)
+}
@@ -0,0 +1,34 @@
+//! PhotonForge RT — progressive path tracing in wgpu compute shaders.
+//!
+//! [`Renderer`] owns the GPU state. It is created either for a winit window
+//! ([`Renderer::new`]) or offscreen ([`Renderer::new_headless`]); each
+//! [`Renderer::render`] call traces one frame of `spp` samples into the
+//! accumulation buffer, which [`Renderer::read_image`] copies back.
+//! Geometry and materials come from a [`Scene`], usually loaded from a TOML
+//! file (see `scenes/cornell.toml`) and sent over with [`Renderer::upload_scene`].
+
+mod blue_noise;
+pub mod bvh;
+pub mod camera;
+pub mod environment;
+pub mod gltf_import;
+mod gpu_scene;
+pub mod hdr_image;
+mod light_bvh;
+pub mod mesh;
+pub mod renderer;
+pub mod scene;
+pub mod settings;
+pub mod sky;
+pub mod volume;
+
+pub use bvh::{AccelStats, Bvh, BvhStats};
+pub use camera::{Aperture, Camera, Movement, PhysicalCamera, Projection};
+pub use environment::Environment;
+pub use hdr_image::{HdrImage, OutputFormat};
+pub use mesh::Mesh;
+pub use renderer::Renderer;
+pub use scene::{Light, LightKind, Medium, Scene};
+pub use settings::{RenderSettings, SamplerKind};
+pub use sky::Sky;
+pub use volume::{DensityGrid, VoxelFormat};
@@ -0,0 +1,308 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Quat, Vec3};
+use std::f32::consts::PI;
+
+use crate::bvh::Aabb;
+
+/// Spatial and directional bounds of one or more lights: where they are,
+/// how much they emit and towards which directions (Conty Estevez and
+/// Kulla 2018, in the form used by pbrt-v4).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LightBounds {
+    pub bounds: Aabb,
+    /// Emitted power, up to a constant factor shared by all lights.
+    pub phi: f32,
+    /// Axis of the cone of surface normals (or spot directions).
+    pub w: Vec3,
+    /// Spread of the normals around `w`.
+    pub cos_theta_o: f32,
+    /// How far past the normals light is emitted (pi/2 for surfaces).
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    /// Bounds of a light emitting in every direction from `bounds`.
+    pub fn omni(bounds: Aabb, phi: f32) -> Self {
+        Self { bounds, phi, w: Vec3::Z, cos_theta_o: -1.0, cos_theta_e: 0.0, two_sided: false }
+    }
+
+    /// Bounds of a flat one-sided emitter facing `normal`.
+    pub fn surface(bounds: Aabb, phi: f32, normal: Vec3) -> Self {
+        Self { bounds, phi, w: normal, cos_theta_o: 1.0, cos_theta_e: 0.0, two_sided: false }
+    }
+
+    fn union(self, o: Self) -> Self {
+        if self.phi == 0.0 {
+            return o;
+        }
+        if o.phi == 0.0 {
+            return self;
+        }
+        let (w, cos_theta_o) = cone_union(self.w, self.cos_theta_o, o.w, o.cos_theta_o);
+        Self {
+            bounds: self.bounds.union(o.bounds),
+            phi: self.phi + o.phi,
+            w,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(o.cos_theta_e),
+            two_sided: self.two_sided || o.two_sided,
+        }
+    }
+
+    /// Surface area orientation heuristic: power times the solid angle
+    /// measure of the emission cones times the area of the box.
+    fn cost(&self, axis_ratio: f32) -> f32 {
+        let theta_o = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = (1.0 - self.cos_theta_o * self.cos_theta_o).max(0.0).sqrt();
+        let m_omega = 2.0 * PI * (1.0 - self.cos_theta_o)
+            + PI / 2.0
+                * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() - 2.0 * theta_o * sin_theta_o
+                    + self.cos_theta_o);
+        self.phi * m_omega * axis_ratio * self.bounds.surface_area()
+    }
+}
+
+/// Smallest cone (axis, cosine of the half angle) holding both cones.
+fn cone_union(wa: Vec3, cos_a: f32, wb: Vec3, cos_b: f32) -> (Vec3, f32) {
+    let (theta_a, theta_b) = (cos_a.clamp(-1.0, 1.0).acos(), cos_b.clamp(-1.0, 1.0).acos());
+    let theta_d = wa.angle_between(wb);
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (wa, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (wb, cos_b);
+    }
+    let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+    let axis = wa.cross(wb);
+    if theta_o >= PI || axis.length_squared() == 0.0 {
+        return (wa, -1.0);
+    }
+    let w = Quat::from_axis_angle(axis.normalize(), theta_o - theta_a) * wa;
+    (w.normalize(), theta_o.cos())
+}
+
+/// Flattened node (`LightNode` in compute.wgsl), depth first like
+/// [`crate::bvh::BvhNode`]: an interior node's left child follows it and
+/// `child_or_light` is its right child; a leaf holds one light index.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+pub(crate) struct LightNode {
+    min: [f32; 3],
+    phi: f32,
+    max: [f32; 3],
+    cos_theta_o: f32,
+    w: [f32; 3],
+    cos_theta_e: f32,
+    child_or_light: u32,
+    /// `LIGHT_NODE_LEAF | LIGHT_NODE_TWO_SIDED`
+    flags: u32,
+    /// Lets the shader walk up from a leaf to find a light's probability.
+    parent: u32,
+    _pad: u32,
+}
+
+const LIGHT_NODE_LEAF: u32 = 1;
+const LIGHT_NODE_TWO_SIDED: u32 = 2;
+const BUCKETS: usize = 12;
+
+/// Light hierarchy for importance sampling many lights, built with the
+/// surface area orientation heuristic; one light per leaf.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LightBvh {
+    pub nodes: Vec<LightNode>,
+    /// Leaf node of each input light, in input order.
+    pub leaves: Vec<u32>,
+    pub depth: u32,
+}
+
+impl LightBvh {
+    /// Builds the hierarchy over `lights`, given as (light index, bounds).
+    /// Lights without power are never sampled and left out; their leaf is
+    /// `u32::MAX`.
+    pub fn build(lights: &[(u32, LightBounds)]) -> Self {
+        let mut items: Vec<(usize, LightBounds)> =
+            lights.iter().enumerate().filter(|(_, (_, b))| b.phi > 0.0).map(|(i, &(_, b))| (i, b)).collect();
+        let mut bvh = Self { nodes: Vec::new(), leaves: vec![u32::MAX; lights.len()], depth: 0 };
+        if !items.is_empty() {
+            bvh.build_node(lights, &mut items, u32::MAX, 0);
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, lights: &[(u32, LightBounds)], items: &mut [(usize, LightBounds)], parent: u32, depth: u32) -> LightBounds {
+        let node_idx = self.nodes.len() as u32;
+        self.nodes.push(LightNode::zeroed());
+        self.depth = self.depth.max(depth);
+
+        let bounds = if let [(i, b)] = *items {
+            self.leaves[i] = node_idx;
+            self.nodes[node_idx as usize].child_or_light = lights[i].0;
+            self.nodes[node_idx as usize].flags = LIGHT_NODE_LEAF;
+            b
+        } else {
+            let mid = split(items);
+            let (left, right) = items.split_at_mut(mid);
+            let lb = self.build_node(lights, left, node_idx, depth + 1);
+            let right_idx = self.nodes.len() as u32;
+            let rb = self.build_node(lights, right, node_idx, depth + 1);
+            self.nodes[node_idx as usize].child_or_light = right_idx;
+            lb.union(rb)
+        };
+
+        let node = &mut self.nodes[node_idx as usize];
+        node.min = bounds.bounds.min.to_array();
+        node.max = bounds.bounds.max.to_array();
+        node.phi = bounds.phi;
+        node.w = bounds.w.to_array();
+        node.cos_theta_o = bounds.cos_theta_o;
+        node.cos_theta_e = bounds.cos_theta_e;
+        node.parent = parent;
+        if bounds.two_sided {
+            node.flags |= LIGHT_NODE_TWO_SIDED;
+        }
+        bounds
+    }
+}
+
+/// Reorders `items` for the cheapest bucketed split and returns the split
+/// point; falls back to halving by count when the centroids coincide.
+fn split(items: &mut [(usize, LightBounds)]) -> usize {
+    let bounds = items.iter().fold(Aabb::EMPTY, |b, (_, l)| b.union(l.bounds));
+    let cbounds = items.iter().fold(Aabb::EMPTY, |b, (_, l)| b.grow(l.bounds.center()));
+    let extent = bounds.max - bounds.min;
+
+    let mut best: Option<(usize, f32, f32)> = None;
+    for axis in 0..3 {
+        let (lo, hi) = (cbounds.min[axis], cbounds.max[axis]);
+        if hi - lo <= f32::EPSILON * lo.abs().max(1.0) {
+            continue;
+        }
+        let scale = BUCKETS as f32 / (hi - lo);
+        let bucket = |l: &LightBounds| (((l.bounds.center()[axis] - lo) * scale) as usize).min(BUCKETS - 1);
+        let mut buckets: [Option<LightBounds>; BUCKETS] = [None; BUCKETS];
+        for (_, l) in items.iter() {
+            let b = &mut buckets[bucket(l)];
+            *b = Some(b.map_or(*l, |acc| acc.union(*l)));
+        }
+        // penalise long thin boxes across the split axis
+        let axis_ratio = extent.max_element() / extent[axis].max(f32::MIN_POSITIVE);
+        let union = |range: &[Option<LightBounds>]| {
+            range.iter().flatten().fold(None, |acc: Option<LightBounds>, l| Some(acc.map_or(*l, |a| a.union(*l))))
+        };
+        for b in 1..BUCKETS {
+            let (Some(left), Some(right)) = (union(&buckets[..b]), union(&buckets[b..])) else { continue };
+            let cost = left.cost(axis_ratio) + right.cost(axis_ratio);
+            if best.is_none_or(|(_, _, c)| cost < c) {
+                best = Some((axis, lo + b as f32 / scale, cost));
+            }
+        }
+    }
+
+    let mid = match best {
+        Some((axis, pos, _)) => partition(items, |l| l.bounds.center()[axis] < pos),
+        None => 0,
+    };
+    if mid == 0 || mid == items.len() { items.len() / 2 } else { mid }
+}
+
+fn partition(items: &mut [(usize, LightBounds)], left: impl Fn(&LightBounds) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if left(&items[i].1) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{a} != {b}");
+    }
+
+    /// Whether the cone (w, cos_theta) holds the cone (v, cos_phi).
+    fn holds(w: Vec3, cos_theta: f32, v: Vec3, cos_phi: f32) -> bool {
+        w.angle_between(v) + cos_phi.acos() <= cos_theta.acos() + 1e-4
+    }
+
+    #[test]
+    fn cone_union_keeps_the_outer_of_nested_cones() {
+        let tilted = Vec3::new(0.1, 0.0, 1.0).normalize();
+        let (wide, narrow) = (60f32.to_radians().cos(), 10f32.to_radians().cos());
+        assert_eq!(cone_union(Vec3::Z, wide, tilted, narrow), (Vec3::Z, wide));
+        assert_eq!(cone_union(tilted, narrow, Vec3::Z, wide), (Vec3::Z, wide));
+    }
+
+    #[test]
+    fn cone_union_spans_disjoint_cones() {
+        let cos_a = 10f32.to_radians().cos();
+        let (w, cos_theta) = cone_union(Vec3::Z, cos_a, Vec3::X, cos_a);
+        assert!(w.abs_diff_eq(Vec3::new(1.0, 0.0, 1.0).normalize(), 1e-5), "{w}");
+        assert_near(cos_theta, 55f32.to_radians().cos());
+        assert!(holds(w, cos_theta, Vec3::Z, cos_a) && holds(w, cos_theta, Vec3::X, cos_a));
+    }
+
+    #[test]
+    fn cone_union_of_opposite_cones_is_the_sphere() {
+        let narrow = 10f32.to_radians().cos();
+        assert_eq!(cone_union(Vec3::Z, narrow, -Vec3::Z, narrow).1, -1.0);
+        // wide enough that the union would pass the full sphere
+        let wide = 120f32.to_radians().cos();
+        assert_eq!(cone_union(Vec3::X, wide, -Vec3::X, 1.0).1, -1.0);
+    }
+
+    fn point_light(x: f32, phi: f32) -> LightBounds {
+        LightBounds::omni(Aabb::from_points(&[Vec3::new(x, 0.0, 0.0)]), phi)
+    }
+
+    #[test]
+    fn leaves_map_back_to_their_lights() {
+        // light indices differ from the input positions, as with lights
+        // that are emissive triangles further down the light list
+        let lights: Vec<(u32, LightBounds)> = [1.0, 0.0, 2.0, 3.0, 0.0, 4.0, 5.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &phi)| (10 + i as u32, point_light(i as f32, phi)))
+            .collect();
+        let bvh = LightBvh::build(&lights);
+        assert_eq!(bvh.leaves.len(), lights.len());
+        for (i, &(light, bounds)) in lights.iter().enumerate() {
+            let leaf = bvh.leaves[i];
+            if bounds.phi == 0.0 {
+                assert_eq!(leaf, u32::MAX, "light {i} has no power");
+                continue;
+            }
+            let node = bvh.nodes[leaf as usize];
+            assert_eq!(node.flags & LIGHT_NODE_LEAF, LIGHT_NODE_LEAF);
+            assert_eq!(node.child_or_light, light);
+            assert_eq!(node.phi, bounds.phi);
+            // walking up reaches the root through nodes that hold the leaf
+            let mut n = leaf;
+            while n != 0 {
+                let parent = bvh.nodes[n as usize].parent;
+                assert!(parent < n);
+                let p = bvh.nodes[parent as usize];
+                assert_eq!(p.flags & LIGHT_NODE_LEAF, 0);
+                assert!(p.child_or_light == n || parent + 1 == n, "node {n} is not a child of {parent}");
+                n = parent;
+            }
+        }
+        assert_eq!(bvh.nodes.len(), 2 * 5 - 1);
+        assert_eq!(bvh.nodes[0].phi, 15.0);
+        assert_eq!(bvh.nodes[0].parent, u32::MAX);
+    }
+
+    #[test]
+    fn lights_without_power_leave_the_tree_empty() {
+        let bvh = LightBvh::build(&[(0, point_light(0.0, 0.0))]);
+        assert!(bvh.nodes.is_empty());
+        assert_eq!(bvh.leaves, [u32::MAX]);
+    }
+}
@@ -1,4 +1,7 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand};
+use glam::Vec3;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use winit::{
@@ -8,14 +11,230 @@ use winit::{
     window::WindowBuilder,
 };
 
-mod renderer;
-use renderer::{Movement, Renderer};
+use photonforge_rt::camera::DEFAULT_EYE_SEPARATION;
+use photonforge_rt::{Camera, Movement, OutputFormat, Projection, RenderSettings, Renderer, SamplerKind, Scene};
+
+#[derive(Parser)]
+#[command(name = "photonforge_rt", version, about = "Progressive GPU path tracer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Open the interactive viewer (the default)
+    View(ViewArgs),
+    /// Render a still offscreen and write it to a file
+    Render(RenderArgs),
+}
+
+#[derive(Args, Default)]
+struct ViewArgs {
+    /// Scene file (.toml, .gltf or .glb); defaults to the built-in Cornell box
+    scene: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RenderArgs {
+    /// Scene file (.toml, .gltf or .glb); defaults to the built-in Cornell box
+    scene: Option<PathBuf>,
+    /// Output file; `.png` is tonemapped, `.hdr` keeps linear radiance
+    #[arg(short, long)]
+    output: PathBuf,
+    #[arg(long, default_value_t = 1280)]
+    width: u32,
+    #[arg(long, default_value_t = 720)]
+    height: u32,
+    /// Samples per pixel to accumulate before writing the image
+    #[arg(short, long, default_value_t = 1024)]
+    samples: u32,
+    /// Samples per pixel traced per frame
+    #[arg(long, default_value_t = 16)]
+    spp: u32,
+    /// Maximum bounce depth
+    #[arg(long, default_value_t = 16)]
+    max_bounce: u32,
+    /// Bounces before Russian roulette may end a path
+    #[arg(long, default_value_t = 3)]
+    rr_depth: u32,
+    /// Clamp path throughput to this value (biased; off by default)
+    #[arg(long)]
+    clamp: Option<f32>,
+    /// Random number source: `sobol`, `independent` or `blue-noise`
+    #[arg(long, default_value = "sobol")]
+    sampler: SamplerKind,
+    /// Trace sampled wavelengths instead of RGB, for dispersion
+    #[arg(long)]
+    spectral: bool,
+    /// Camera position as `x,y,z`; without it the scene's camera is used
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+    camera_pos: Option<Vec3>,
+    /// Point to look at as `x,y,z`; overrides --yaw/--pitch
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true, requires = "camera_pos")]
+    look_at: Option<Vec3>,
+    /// Camera yaw in degrees
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    yaw: f32,
+    /// Camera pitch in degrees
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    pitch: f32,
+    /// Camera projection: `perspective`, `orthographic`, `fisheye` or
+    /// `equirectangular` (360° panorama)
+    #[arg(long)]
+    projection: Option<Projection>,
+    /// Vertical field of view in degrees; up to 360 for a fisheye
+    #[arg(long)]
+    fov: Option<f32>,
+    /// Height of an orthographic view in world units
+    #[arg(long)]
+    ortho_height: Option<f32>,
+    /// Render a stereo panorama, left eye on top, with the eyes this far
+    /// apart (a typical human eye distance if no value is given)
+    #[arg(long, num_args = 0..=1)]
+    stereo: Option<Option<f32>>,
+    /// Lens radius for depth of field; 0 is a pinhole
+    #[arg(long)]
+    aperture: Option<f32>,
+    /// Distance of the plane in focus
+    #[arg(long)]
+    focus_distance: Option<f32>,
+    /// Only accept a software (fallback) adapter
+    #[arg(long)]
+    cpu: bool,
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3, String> {
+    let parts = s
+        .split(',')
+        .map(|p| p.trim().parse::<f32>().map_err(|e| format!("{p:?}: {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    match parts[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!("expected `x,y,z`, got {s:?}")),
+    }
+}
 
 fn main() -> Result<()> {
-    pollster::block_on(run())
+    let cli = Cli::parse();
+    match cli.command {
+        None => pollster::block_on(run(ViewArgs::default())),
+        Some(Command::View(args)) => pollster::block_on(run(args)),
+        Some(Command::Render(args)) => pollster::block_on(render_still(args)),
+    }
+}
+
+async fn render_still(args: RenderArgs) -> Result<()> {
+    if args.width == 0 || args.height == 0 {
+        bail!("image size must be non-zero, got {}x{}", args.width, args.height);
+    }
+    if args.samples == 0 || args.spp == 0 {
+        bail!("--samples and --spp must be at least 1");
+    }
+    if args.clamp.is_some_and(|c| !(c.is_finite() && c > 0.0)) {
+        bail!("--clamp must be positive");
+    }
+    if args.ortho_height.is_some_and(|h| !(h.is_finite() && h > 0.0)) {
+        bail!("--ortho-height must be positive");
+    }
+    if args.stereo.flatten().is_some_and(|e| !(e.is_finite() && e >= 0.0)) {
+        bail!("--stereo eye separation must not be negative");
+    }
+    if args.aperture.is_some_and(|a| !(a.is_finite() && a >= 0.0)) {
+        bail!("--aperture must not be negative");
+    }
+    if args.focus_distance.is_some_and(|d| !(d.is_finite() && d > 0.0)) {
+        bail!("--focus-distance must be positive");
+    }
+    // fail on a bad output path before spending minutes on the render
+    OutputFormat::from_path(&args.output)?;
+
+    let scene = load_scene(args.scene.as_deref())?;
+    let mut renderer = Renderer::new_headless(args.width, args.height, args.cpu).await?;
+    let stats = renderer.upload_scene(&scene)?;
+    if stats.instances > 0 {
+        eprintln!("BVH: {stats}");
+    }
+    renderer.set_settings(RenderSettings {
+        spp: args.spp.min(args.samples),
+        max_bounce: args.max_bounce,
+        rr_depth: args.rr_depth,
+        clamp: args.clamp,
+        sampler: args.sampler,
+        spectral: args.spectral,
+    });
+    let mut camera = match (args.camera_pos, args.look_at) {
+        (Some(pos), Some(target)) => Camera::look_at(pos, target),
+        (Some(pos), None) => Camera {
+            position: pos,
+            yaw: args.yaw.to_radians(),
+            pitch: args.pitch.to_radians().clamp(-1.5, 1.5),
+            ..Camera::default()
+        },
+        (None, _) => scene.camera.unwrap_or_default(),
+    };
+    camera.projection = args.projection.unwrap_or(camera.projection);
+    camera.fov_y_radians = args.fov.map_or(camera.fov_y_radians, f32::to_radians);
+    // the scene's field of view may not suit an overridden projection
+    if !camera.projection.supports_fov(camera.fov_y_radians) {
+        bail!(
+            "a {}° field of view does not suit the {:?} projection (below 180°, up to 360° for a fisheye)",
+            camera.fov_y_radians.to_degrees(),
+            camera.projection
+        );
+    }
+    if let Some(ortho_height) = args.ortho_height {
+        if camera.projection != Projection::Orthographic {
+            bail!("--ortho-height needs the orthographic projection");
+        }
+        camera.ortho_height = ortho_height;
+    }
+    if let Some(eye_separation) = args.stereo {
+        if camera.projection != Projection::Equirectangular {
+            bail!("--stereo needs the equirectangular projection");
+        }
+        camera.eye_separation = eye_separation.unwrap_or(DEFAULT_EYE_SEPARATION);
+    }
+    camera.aperture_radius = args.aperture.unwrap_or(camera.aperture_radius);
+    camera.focus_distance = args.focus_distance.unwrap_or(camera.focus_distance);
+    renderer.set_camera(camera);
+
+    let start = Instant::now();
+    let mut last_report = Instant::now();
+    renderer.accumulate(args.samples, |done| {
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            eprintln!("{done}/{} spp ({:.1}s)", args.samples, start.elapsed().as_secs_f32());
+            last_report = Instant::now();
+        }
+    })?;
+
+    let image = renderer.read_image()?;
+    image.save(&args.output)?;
+    eprintln!(
+        "wrote {} ({}x{}, {} spp) in {:.1}s",
+        args.output.display(),
+        image.width,
+        image.height,
+        renderer.samples_accumulated(),
+        start.elapsed().as_secs_f32()
+    );
+    Ok(())
 }
 
-async fn run() -> Result<()> {
+fn load_scene(path: Option<&std::path::Path>) -> Result<Scene> {
+    let scene = match path {
+        Some(path) => Scene::load(path)?,
+        None => Scene::cornell_box(),
+    };
+    for warning in &scene.warnings {
+        eprintln!("warning: {warning}");
+    }
+    Ok(scene)
+}
+
+async fn run(args: ViewArgs) -> Result<()> {
+    let scene = load_scene(args.scene.as_deref())?;
+
     // winit 0.29: EventLoop::new() -> Result<...>
     let event_loop = EventLoop::new()?;
 
@@ -27,10 +246,21 @@ async fn run() -> Result<()> {
 
     // Create renderer (needs &Window)
     let mut renderer = Renderer::new(window.as_ref()).await?;
+    let stats = renderer.upload_scene(&scene)?;
+    if stats.instances > 0 {
+        println!("BVH: {stats}");
+    }
+    if let Some(camera) = scene.camera {
+        renderer.set_camera(camera);
+    }
+    // blue noise looks cleanest while moving around at a few samples
+    renderer.set_settings(RenderSettings { sampler: SamplerKind::BlueNoise, ..*renderer.settings() });
 
-    // Input state
+    // Input state; a left click without dragging focuses
     let mut mouse_down = false;
+    let mut dragged = false;
     let mut last_mouse_pos: Option<(f32, f32)> = None;
+    let mut cursor = (0.0f32, 0.0f32);
 
     // Perf counters
     let win_for_loop = window.clone();
@@ -58,23 +288,35 @@ async fn run() -> Result<()> {
                     handle_keyboard(&mut renderer, &key_event);
                 }
 
+                #[allow(clippy::collapsible_match)]
                 WindowEvent::MouseInput { state, button, .. } => {
                     if button == MouseButton::Left {
                         mouse_down = state == ElementState::Pressed;
-                        if !mouse_down {
+                        if mouse_down {
+                            dragged = false;
+                        } else {
                             last_mouse_pos = None;
+                            if !dragged {
+                                match renderer.focus_at(cursor.0 as u32, cursor.1 as u32) {
+                                    Ok(Some(d)) => println!("focus distance: {d:.3}"),
+                                    Ok(None) => println!("focus: nothing under the cursor"),
+                                    Err(e) => eprintln!("focus error: {e:?}"),
+                                }
+                            }
                         }
                     }
                 }
 
                 WindowEvent::CursorMoved { position, .. } => {
+                    cursor = (position.x as f32, position.y as f32);
                     if mouse_down {
                         if let Some((lx, ly)) = last_mouse_pos {
-                            let dx = position.x as f32 - lx;
-                            let dy = position.y as f32 - ly;
+                            let dx = cursor.0 - lx;
+                            let dy = cursor.1 - ly;
+                            dragged |= dx != 0.0 || dy != 0.0;
                             renderer.on_mouse_delta(dx, dy);
                         }
-                        last_mouse_pos = Some((position.x as f32, position.y as f32));
+                        last_mouse_pos = Some(cursor);
                     }
                 }
 
@@ -126,6 +368,41 @@ fn handle_keyboard(renderer: &mut Renderer, key_event: &KeyEvent) {
             "q" | "Q" => renderer.queue_movement(Movement::Down),
             "e" | "E" => renderer.queue_movement(Movement::Up),
             "r" | "R" => renderer.reset_accum(),
+            "n" | "N" => {
+                let sampler = match renderer.settings().sampler {
+                    SamplerKind::BlueNoise => SamplerKind::Sobol,
+                    SamplerKind::Sobol => SamplerKind::Independent,
+                    SamplerKind::Independent => SamplerKind::BlueNoise,
+                };
+                println!("sampler: {sampler:?}");
+                renderer.set_settings(RenderSettings { sampler, ..*renderer.settings() });
+            }
+            "[" | "]" => {
+                let r = renderer.camera().aperture_radius;
+                let aperture_radius = match txt.as_str() {
+                    "]" => (r * 1.5).max(0.005),
+                    _ if r > 0.005 => r / 1.5,
+                    _ => 0.0,
+                };
+                println!("aperture radius: {aperture_radius:.4}");
+                // a hand-picked aperture no longer follows the lens
+                renderer.set_camera(Camera { aperture_radius, physical: None, ..*renderer.camera() });
+            }
+            "p" | "P" => {
+                let projection = renderer.camera().projection.next();
+                println!("projection: {projection:?}");
+                renderer.set_projection(projection);
+            }
+            "v" | "V" => {
+                let eye_separation = if renderer.camera().eye_separation > 0.0 { 0.0 } else { DEFAULT_EYE_SEPARATION };
+                println!("stereo panorama: {}", eye_separation > 0.0);
+                renderer.set_camera(Camera { eye_separation, ..*renderer.camera() });
+            }
+            "l" | "L" => {
+                let spectral = !renderer.settings().spectral;
+                println!("spectral: {spectral}");
+                renderer.set_settings(RenderSettings { spectral, ..*renderer.settings() });
+            }
             _ => {}
         },
         _ => {}
@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use glam::{Mat4, Vec2, Vec3};
+use std::path::Path;
+
+use crate::scene::{Dispersion, Material, MaterialKind, Scene};
+
+/// Indexed triangle mesh. `normals` and `uvs` are either empty or have one
+/// entry per position.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<[u32; 3]>,
+    /// Per-triangle index into [`Scene::materials`].
+    pub materials: Vec<u32>,
+}
+
+impl Mesh {
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Applies `transform` to positions and normals in place.
+    pub fn transform(&mut self, transform: Mat4) {
+        let normal_m = transform.inverse().transpose();
+        for p in &mut self.positions {
+            *p = transform.transform_point3(*p);
+        }
+        for n in &mut self.normals {
+            *n = normal_m.transform_vector3(*n).normalize_or_zero();
+        }
+        // mirroring transforms flip the winding
+        if transform.determinant() < 0.0 {
+            for tri in &mut self.indices {
+                tri.swap(1, 2);
+            }
+        }
+    }
+
+    /// Appends `other`, re-basing its indices. Missing normals/UVs on either
+    /// side are zero-filled (the shader then falls back to the face normal).
+    pub fn append(&mut self, other: &Mesh) {
+        let base = self.positions.len();
+        let total = base + other.positions.len();
+        if !self.normals.is_empty() || !other.normals.is_empty() {
+            self.normals.resize(base, Vec3::ZERO);
+            self.normals.extend_from_slice(&other.normals);
+            self.normals.resize(total, Vec3::ZERO);
+        }
+        if !self.uvs.is_empty() || !other.uvs.is_empty() {
+            self.uvs.resize(base, Vec2::ZERO);
+            self.uvs.extend_from_slice(&other.uvs);
+            self.uvs.resize(total, Vec2::ZERO);
+        }
+        self.positions.extend_from_slice(&other.positions);
+        self.indices.extend(other.indices.iter().map(|t| t.map(|i| i + base as u32)));
+        self.materials.extend_from_slice(&other.materials);
+    }
+}
+
+/// Loads a Wavefront OBJ (and its MTL library, if any) as a single mesh.
+/// MTL materials are appended to `scene.materials`; faces without one use
+/// `default_material`.
+pub fn load_obj(path: &Path, scene: &mut Scene, default_material: u32) -> Result<Mesh> {
+    let (models, mtl) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)
+        .with_context(|| format!("failed to load {}", path.display()))?;
+    // a missing or broken .mtl is not fatal, the faces fall back to the default
+    let material_ids: Vec<u32> = mtl
+        .unwrap_or_default()
+        .iter()
+        .map(|m| scene.add_material(mtl_material(m)))
+        .collect();
+
+    let mut mesh = Mesh::default();
+    for model in &models {
+        let m = &model.mesh;
+        let material = m
+            .material_id
+            .and_then(|id| material_ids.get(id).copied())
+            .unwrap_or(default_material);
+        let part = Mesh {
+            positions: m.positions.chunks_exact(3).map(|p| Vec3::new(p[0], p[1], p[2])).collect(),
+            normals: m.normals.chunks_exact(3).map(|n| Vec3::new(n[0], n[1], n[2])).collect(),
+            // OBJ puts v = 0 at the bottom of the image
+            uvs: m.texcoords.chunks_exact(2).map(|t| Vec2::new(t[0], 1.0 - t[1])).collect(),
+            indices: m.indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+            materials: vec![material; m.indices.len() / 3],
+        };
+        mesh.append(&part);
+    }
+    Ok(mesh)
+}
+
+fn mtl_material(m: &tobj::Material) -> Material {
+    let albedo = Vec3::from(m.diffuse.unwrap_or([0.8; 3]));
+    let emission = Vec3::from(m.emissive.unwrap_or([0.0; 3]));
+    let ior = m.optical_density.filter(|&n| n > 0.0).unwrap_or(1.5);
+    let kind = match m.illumination_model {
+        // 4, 6, 7: refraction / glass
+        Some(4) | Some(6) | Some(7) => MaterialKind::Glass { ior, roughness: 0.0, dispersion: Dispersion::None },
+        // 3, 5: ray traced reflection
+        Some(3) | Some(5) => MaterialKind::Mirror,
+        _ if m.dissolve.is_some_and(|d| d < 1.0) => MaterialKind::Glass { ior, roughness: 0.0, dispersion: Dispersion::None },
+        _ => MaterialKind::Diffuse,
+    };
+    let albedo = match kind {
+        MaterialKind::Diffuse | MaterialKind::Principled(_) | MaterialKind::Interface => albedo,
+        MaterialKind::Mirror | MaterialKind::Conductor { .. } => Vec3::from(m.specular.unwrap_or([1.0; 3])),
+        // transmission filter, "Tf r g b"
+        MaterialKind::Glass { .. } => m
+            .unknown_param
+            .get("Tf")
+            .and_then(|tf| parse_rgb(tf))
+            .unwrap_or(Vec3::ONE),
+    };
+    Material::new(albedo, emission, kind)
+}
+
+fn parse_rgb(s: &str) -> Option<Vec3> {
+    let c: Vec<f32> = s.split_whitespace().map(str::parse).collect::<Result<_, _>>().ok()?;
+    match c[..] {
+        [v] => Some(Vec3::splat(v)),
+        [r, g, b] => Some(Vec3::new(r, g, b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle(normals: bool, uvs: bool) -> Mesh {
+        Mesh {
+            positions: vec![Vec3::ZERO, Vec3::X, Vec3::Y],
+            normals: if normals { vec![Vec3::Z; 3] } else { Vec::new() },
+            uvs: if uvs { vec![Vec2::ONE; 3] } else { Vec::new() },
+            indices: vec![[0, 1, 2]],
+            materials: vec![7],
+        }
+    }
+
+    #[test]
+    fn append_rebases_indices() {
+        let mut mesh = triangle(false, false);
+        mesh.append(&triangle(false, false));
+        assert_eq!(mesh.positions.len(), 6);
+        assert_eq!(mesh.indices, [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(mesh.materials, [7, 7]);
+        assert!(mesh.normals.is_empty() && mesh.uvs.is_empty());
+    }
+
+    #[test]
+    fn append_zero_fills_missing_attributes() {
+        let mut mesh = triangle(false, true);
+        mesh.append(&triangle(true, false));
+        assert_eq!(mesh.normals, [Vec3::ZERO, Vec3::ZERO, Vec3::ZERO, Vec3::Z, Vec3::Z, Vec3::Z]);
+        assert_eq!(mesh.uvs, [Vec2::ONE, Vec2::ONE, Vec2::ONE, Vec2::ZERO, Vec2::ZERO, Vec2::ZERO]);
+
+        let mut mesh = Mesh::default();
+        mesh.append(&triangle(true, true));
+        mesh.append(&triangle(false, false));
+        assert_eq!(mesh.normals.len(), 6);
+        assert_eq!(mesh.uvs.len(), 6);
+        assert_eq!(mesh.normals[5], Vec3::ZERO);
+    }
+
+    #[test]
+    fn transform_moves_points_and_normals() {
+        let mut mesh = triangle(true, false);
+        mesh.transform(Mat4::from_translation(Vec3::X) * Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0)));
+        assert_eq!(mesh.positions, [Vec3::X, Vec3::new(3.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0)]);
+        assert_eq!(mesh.normals, [Vec3::Z; 3]);
+        assert_eq!(mesh.indices, [[0, 1, 2]]);
+    }
+
+    #[test]
+    fn mirroring_keeps_faces_outward() {
+        let mut mesh = triangle(true, false);
+        mesh.transform(Mat4::from_scale(Vec3::new(1.0, 1.0, -1.0)));
+        assert_eq!(mesh.normals, [-Vec3::Z; 3]);
+        assert_eq!(mesh.indices, [[0, 2, 1]]);
+        // the winding agrees with the mirrored normal again
+        let [a, b, c] = mesh.indices[0].map(|i| mesh.positions[i as usize]);
+        assert!((b - a).cross(c - a).dot(-Vec3::Z) > 0.0);
+    }
+
+    fn mtl(illum: Option<u8>, params: &[(&str, &str)]) -> tobj::Material {
+        tobj::Material {
+            diffuse: Some([0.5, 0.25, 0.125]),
+            specular: Some([0.9; 3]),
+            optical_density: Some(1.33),
+            illumination_model: illum,
+            unknown_param: params.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mtl_illumination_models_pick_the_kind() {
+        let diffuse = mtl_material(&mtl(Some(2), &[]));
+        assert_eq!(diffuse.kind, MaterialKind::Diffuse);
+        assert_eq!(diffuse.albedo, Vec3::new(0.5, 0.25, 0.125));
+
+        for illum in [3, 5] {
+            let mirror = mtl_material(&mtl(Some(illum), &[]));
+            assert_eq!(mirror.kind, MaterialKind::Mirror, "illum {illum}");
+            assert_eq!(mirror.albedo, Vec3::splat(0.9));
+        }
+        for illum in [4, 6, 7] {
+            let glass = mtl_material(&mtl(Some(illum), &[]));
+            assert_eq!(glass.kind, MaterialKind::Glass { ior: 1.33, roughness: 0.0, dispersion: Dispersion::None });
+            assert_eq!(glass.albedo, Vec3::ONE, "illum {illum}");
+        }
+        let faded = mtl_material(&tobj::Material { dissolve: Some(0.5), ..mtl(None, &[]) });
+        assert!(matches!(faded.kind, MaterialKind::Glass { .. }));
+    }
+
+    #[test]
+    fn mtl_transmission_filter_tints_glass() {
+        let tinted = mtl_material(&mtl(Some(7), &[("Tf", "0.9 0.5 0.1")]));
+        assert_eq!(tinted.albedo, Vec3::new(0.9, 0.5, 0.1));
+        let grey = mtl_material(&mtl(Some(7), &[("Tf", "0.5")]));
+        assert_eq!(grey.albedo, Vec3::splat(0.5));
+        // spectral and CIE XYZ forms are not supported
+        let spectral = mtl_material(&mtl(Some(7), &[("Tf", "spectral glass.rfl")]));
+        assert_eq!(spectral.albedo, Vec3::ONE);
+        assert_eq!(parse_rgb("1 2"), None);
+    }
+
+    #[test]
+    fn loads_obj_with_materials() {
+        let dir = std::env::temp_dir().join(format!("photonforge_mesh_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("two.mtl"), "newmtl red\nKd 1 0 0\nillum 2\n").unwrap();
+        std::fs::write(
+            dir.join("two.obj"),
+            "mtllib two.mtl\n\
+             o plain\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n\
+             o red\nusemtl red\nv 0 0 1\nv 1 0 1\nv 0 1 1\nvt 0 0\nvt 1 0\nvt 0 1\nf 4/1 5/2 6/3\n",
+        )
+        .unwrap();
+        let mut scene = Scene::default();
+        let mesh = load_obj(&dir.join("two.obj"), &mut scene, 42);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mesh = mesh.unwrap();
+
+        assert_eq!(scene.materials.len(), 1);
+        assert_eq!(scene.materials[0].albedo, Vec3::X);
+        assert_eq!(mesh.positions.len(), 6);
+        assert_eq!(mesh.indices, [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(mesh.materials, [42, 0]);
+        // only the second part has UVs, flipped to image orientation
+        assert_eq!(mesh.uvs.len(), 6);
+        assert_eq!(mesh.uvs[0], Vec2::ZERO);
+        assert_eq!(mesh.uvs[3], Vec2::new(0.0, 1.0));
+    }
+}
@@ -1,10 +1,19 @@
 use anyhow::Result;
 use bytemuck::{Pod, Zeroable};
-use glam::{Mat3, Vec3};
+use wgpu::util::DeviceExt;
 use wgpu::*;
 use winit::{dpi::PhysicalSize, window::Window};
+use std::sync::mpsc;
 use std::time::Instant;
 
+use crate::blue_noise;
+use crate::bvh::AccelStats;
+use crate::camera::{Aperture, Camera, Movement, Projection};
+use crate::gpu_scene::GpuScene;
+use crate::hdr_image::HdrImage;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable, Debug)]
 pub struct CameraUBO {
@@ -19,22 +28,58 @@ pub struct CameraUBO {
     img_size: [u32; 2],
     frame_index: u32,
     max_bounce: u32,
+    spp: u32,
+    sampler_kind: u32,
+    rr_depth: u32,
+    clamp: f32,
+    /// 1 if the accumulation holds CIE XYZ from spectral rendering.
+    spectral: u32,
+    aperture_radius: f32,
+    focus_distance: f32,
+    /// `APERTURE_*` in compute.wgsl.
+    aperture_kind: u32,
+    aperture_blades: u32,
+    aperture_rotation: f32,
+    /// Pixel the focus pass looks through.
+    focus_pixel: [u32; 2],
+    /// Vertical field of view in radians.
+    fov_y: f32,
+    exposure: f32,
+    /// `PROJECTION_*` in compute.wgsl.
+    projection: u32,
+    ortho_height: f32,
+    eye_separation: f32,
+    _pad4: [u32; 3],
 }
 
-pub enum Movement {
-    Forward,
-    Backward,
-    Left,
-    Right,
-    Up,
-    Down,
+/// CIE XYZ to linear sRGB, with the equal-energy white point adapted to
+/// D65 (Bradford) so flat spectra come out neutral; `XYZ_TO_RGB` in
+/// blit.wgsl as well.
+const XYZ_TO_RGB: [[f32; 3]; 3] = [
+    [3.147_809, -1.662_846, -0.480_574],
+    [-0.994_747, 1.953_571, 0.039_740],
+    [0.063_516, -0.214_511, 1.151_595],
+];
+
+/// Widest perspective view, in degrees, that scrolling zooms out to.
+const MAX_SCROLL_FOV: f32 = 120.0;
+
+/// Window presentation state; absent for headless renderers.
+struct SurfaceTarget<'w> {
+    surface: Surface<'w>,
+    config: SurfaceConfiguration,
+
+    sampler: Sampler,
+    blit_pipeline: RenderPipeline,
+    blit_bind_layout: BindGroupLayout,
+    blit_bind_a: BindGroup,
+    blit_bind_b: BindGroup,
 }
 
 pub struct Renderer<'w> {
-    surface: Surface<'w>,
+    target: Option<SurfaceTarget<'w>>,
     device: Device,
     queue: Queue,
-    config: SurfaceConfiguration,
 
     size: PhysicalSize<u32>,
 
@@ -46,29 +91,27 @@ pub struct Renderer<'w> {
     accum_a_view_sample: TextureView,
     accum_b_view_sample: TextureView,
 
-    sampler: Sampler,
-
     compute_pipeline: ComputePipeline,
-    blit_pipeline: RenderPipeline,
-
+    focus_pipeline: ComputePipeline,
     compute_bind_layout: BindGroupLayout,
-    blit_bind_layout: BindGroupLayout,
-
     compute_bind_a: BindGroup,
     compute_bind_b: BindGroup,
-    blit_bind_a: BindGroup,
-    blit_bind_b: BindGroup,
+    blue_noise_view: TextureView,
+    /// 1x1 R32Float target of the focus pass.
+    focus_tex: Texture,
+    focus_view: TextureView,
+    focus_pixel: [u32; 2],
+
+    scene_bind_layout: BindGroupLayout,
+    gpu_scene: GpuScene,
 
     camera_buf: Buffer,
 
     frame_index: u32,
     use_a_as_src: bool,
 
-    cam_pos: Vec3,
-    yaw: f32,
-    pitch: f32,
-    move_delta: Vec3,
-    fov_y_radians: f32,
+    camera: Camera,
+    settings: RenderSettings,
 
     // --- perf metrics (ms) ---
     pub last_ms_gbuffer: f32,
@@ -95,17 +138,7 @@ impl<'w> Renderer<'w> {
             })
             .await
             .ok_or_else(|| anyhow::anyhow!("No GPU adapter found"))?;
-
-        let (device, queue) = adapter
-            .request_device(
-                &DeviceDescriptor {
-                    label: Some("device"),
-                    required_features: Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
-                    required_limits: Limits::default().using_resolution(adapter.limits()),
-                },
-                None,
-            )
-            .await?;
+        let (device, queue) = Self::request_device(&adapter).await?;
 
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps
@@ -139,16 +172,37 @@ impl<'w> Renderer<'w> {
         };
         surface.configure(&device, &config);
 
+        Ok(Self::from_device(device, queue, size, Some((surface, config))))
+    }
+
+    async fn request_device(adapter: &Adapter) -> Result<(Device, Queue)> {
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: Some("device"),
+                    required_features: Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+                    required_limits: Limits {
+                        // mesh scenes easily outgrow the 128 MiB default
+                        max_storage_buffer_binding_size: adapter.limits().max_storage_buffer_binding_size,
+                        max_buffer_size: adapter.limits().max_buffer_size,
+                        ..Limits::default().using_resolution(adapter.limits())
+                    },
+                },
+                None,
+            )
+            .await?;
+        Ok((device, queue))
+    }
+
+    fn from_device(
+        device: Device,
+        queue: Queue,
+        size: PhysicalSize<u32>,
+        surface: Option<(Surface<'w>, SurfaceConfiguration)>,
+    ) -> Self {
         let (accum_a, a_storage, a_sample) = Self::make_accum(&device, size);
         let (accum_b, b_storage, b_sample) = Self::make_accum(&device, size);
 
-        let sampler = device.create_sampler(&SamplerDescriptor {
-            label: Some("linear sampler"),
-            mag_filter: FilterMode::Linear,
-            min_filter: FilterMode::Linear,
-            ..Default::default()
-        });
-
         // layouts
         let compute_bind_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("compute layout"),
@@ -183,27 +237,11 @@ impl<'w> Renderer<'w> {
                     },
                     count: None,
                 },
-            ],
-        });
-
-        let blit_bind_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("blit layout"),
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT | ShaderStages::VERTEX,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
                 BindGroupLayoutEntry {
                     binding: 3,
-                    visibility: ShaderStages::FRAGMENT,
+                    visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Texture {
-                        sample_type: TextureSampleType::Float { filterable: true },
+                        sample_type: TextureSampleType::Uint,
                         view_dimension: TextureViewDimension::D2,
                         multisampled: false,
                     },
@@ -211,8 +249,12 @@ impl<'w> Renderer<'w> {
                 },
                 BindGroupLayoutEntry {
                     binding: 4,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: TextureFormat::R32Float,
+                        view_dimension: TextureViewDimension::D2,
+                    },
                     count: None,
                 },
             ],
@@ -225,59 +267,43 @@ impl<'w> Renderer<'w> {
             mapped_at_creation: false,
         });
 
-        // bind groups (compute)
-        let compute_bind_a = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("compute_bind_a"),
-            layout: &compute_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(&a_storage) },
-                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(&b_storage) },
-            ],
-        });
-        let compute_bind_b = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("compute_bind_b"),
-            layout: &compute_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(&b_storage) },
-                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(&a_storage) },
-            ],
+        let blue_noise_view = Self::make_blue_noise(&device, &queue);
+        let focus_tex = device.create_texture(&TextureDescriptor {
+            label: Some("focus distance"),
+            size: Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+            view_formats: &[],
         });
+        let focus_view = focus_tex.create_view(&TextureViewDescriptor::default());
 
-        // bind groups (blit)
-        let blit_bind_a = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("blit_bind_a"),
-            layout: &blit_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(&a_sample) },
-                BindGroupEntry { binding: 4, resource: BindingResource::Sampler(&sampler) },
-            ],
-        });
-        let blit_bind_b = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("blit_bind_b"),
-            layout: &blit_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(&b_sample) },
-                BindGroupEntry { binding: 4, resource: BindingResource::Sampler(&sampler) },
-            ],
-        });
+        // bind groups (compute)
+        let (compute_bind_a, compute_bind_b) = Self::make_compute_binds(
+            &device,
+            &compute_bind_layout,
+            &camera_buf,
+            &blue_noise_view,
+            &focus_view,
+            &a_storage,
+            &b_storage,
+        );
 
         // shaders + pipelines
         let compute_mod = device.create_shader_module(ShaderModuleDescriptor {
             label: Some("compute"),
             source: ShaderSource::Wgsl(include_str!("../shaders/compute.wgsl").into()),
         });
-        let blit_mod = device.create_shader_module(ShaderModuleDescriptor {
-            label: Some("blit"),
-            source: ShaderSource::Wgsl(include_str!("../shaders/blit.wgsl").into()),
-        });
+
+        let scene_bind_layout = GpuScene::bind_group_layout(&device);
+        let (gpu_scene, _) = GpuScene::upload(&device, &queue, &scene_bind_layout, &Scene::cornell_box())
+            .expect("the Cornell box has no density grids");
 
         let pipeline_layout_compute = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("compute pipeline layout"),
-            bind_group_layouts: &[&compute_bind_layout],
+            bind_group_layouts: &[&compute_bind_layout, &scene_bind_layout],
             push_constant_ranges: &[],
         });
         let compute_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
@@ -286,7 +312,109 @@ impl<'w> Renderer<'w> {
             module: &compute_mod,
             entry_point: "cs_main",
         });
+        let focus_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("focus pipeline"),
+            layout: Some(&pipeline_layout_compute),
+            module: &compute_mod,
+            entry_point: "cs_focus",
+        });
+
+        let target = surface.map(|(surface, config)| {
+            Self::make_surface_target(&device, surface, config, &camera_buf, &a_sample, &b_sample)
+        });
+
+        let mut renderer = Self {
+            target,
+            device,
+            queue,
+            size,
+            accum_a,
+            accum_b,
+            accum_a_view_storage: a_storage,
+            accum_b_view_storage: b_storage,
+            accum_a_view_sample: a_sample,
+            accum_b_view_sample: b_sample,
+            compute_pipeline,
+            focus_pipeline,
+            compute_bind_layout,
+            compute_bind_a,
+            compute_bind_b,
+            blue_noise_view,
+            focus_tex,
+            focus_view,
+            focus_pixel: [0; 2],
+            scene_bind_layout,
+            gpu_scene,
+            camera_buf,
+            frame_index: 0,
+            use_a_as_src: true,
+            camera: Camera::default(),
+            settings: RenderSettings::default(),
+            last_ms_gbuffer: 0.0,
+            last_ms_rt_shadows: 0.0,
+            last_ms_rt_reflections: 0.0,
+            last_ms_denoise: 0.0,
+            last_ms_total: 0.0,
+        };
+        // the first frame must not trace with an all-zero camera
+        renderer.update_camera();
+        renderer
+    }
+
+    fn make_surface_target(
+        device: &Device,
+        surface: Surface<'w>,
+        config: SurfaceConfiguration,
+        camera_buf: &Buffer,
+        a_sample: &TextureView,
+        b_sample: &TextureView,
+    ) -> SurfaceTarget<'w> {
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("linear sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let blit_bind_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("blit layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT | ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
 
+        let (blit_bind_a, blit_bind_b) =
+            Self::make_blit_binds(device, &blit_bind_layout, camera_buf, &sampler, a_sample, b_sample);
+
+        let blit_mod = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("blit"),
+            source: ShaderSource::Wgsl(include_str!("../shaders/blit.wgsl").into()),
+        });
         let pipeline_layout_blit = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("blit pipeline layout"),
             bind_group_layouts: &[&blit_bind_layout],
@@ -300,7 +428,7 @@ impl<'w> Renderer<'w> {
                 module: &blit_mod,
                 entry_point: "fs_main",
                 targets: &[Some(ColorTargetState {
-                    format: surface_format,
+                    format: config.format,
                     blend: Some(BlendState::REPLACE),
                     write_mask: ColorWrites::ALL,
                 })],
@@ -311,41 +439,99 @@ impl<'w> Renderer<'w> {
             multiview: None,
         });
 
-        Ok(Self {
+        SurfaceTarget {
             surface,
-            device,
-            queue,
             config,
-            size,
-            accum_a,
-            accum_b,
-            accum_a_view_storage: a_storage,
-            accum_b_view_storage: b_storage,
-            accum_a_view_sample: a_sample,
-            accum_b_view_sample: b_sample,
             sampler,
-            compute_pipeline,
             blit_pipeline,
-            compute_bind_layout,
             blit_bind_layout,
-            compute_bind_a,
-            compute_bind_b,
             blit_bind_a,
             blit_bind_b,
-            camera_buf,
-            frame_index: 0,
-            use_a_as_src: true,
-            cam_pos: Vec3::new(0.0, 1.0, 4.0),
-            yaw: 0.0,
-            pitch: 0.0,
-            move_delta: Vec3::ZERO,
-            fov_y_radians: 45f32.to_radians(),
-            last_ms_gbuffer: 0.0,
-            last_ms_rt_shadows: 0.0,
-            last_ms_rt_reflections: 0.0,
-            last_ms_denoise: 0.0,
-            last_ms_total: 0.0,
-        })
+        }
+    }
+
+    fn make_blit_binds(
+        device: &Device,
+        layout: &BindGroupLayout,
+        camera_buf: &Buffer,
+        sampler: &Sampler,
+        a_sample: &TextureView,
+        b_sample: &TextureView,
+    ) -> (BindGroup, BindGroup) {
+        let blit_bind_a = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("blit_bind_a"),
+            layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
+                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(a_sample) },
+                BindGroupEntry { binding: 4, resource: BindingResource::Sampler(sampler) },
+            ],
+        });
+        let blit_bind_b = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("blit_bind_b"),
+            layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
+                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(b_sample) },
+                BindGroupEntry { binding: 4, resource: BindingResource::Sampler(sampler) },
+            ],
+        });
+        (blit_bind_a, blit_bind_b)
+    }
+
+    /// Ping-pong compute bind groups: `a` reads accumulation texture a and
+    /// writes b, `b` the other way round.
+    fn make_compute_binds(
+        device: &Device,
+        layout: &BindGroupLayout,
+        camera_buf: &Buffer,
+        blue_noise: &TextureView,
+        focus: &TextureView,
+        a_storage: &TextureView,
+        b_storage: &TextureView,
+    ) -> (BindGroup, BindGroup) {
+        let bind = |label, src, dst| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some(label),
+                layout,
+                entries: &[
+                    BindGroupEntry { binding: 0, resource: camera_buf.as_entire_binding() },
+                    BindGroupEntry { binding: 1, resource: BindingResource::TextureView(src) },
+                    BindGroupEntry { binding: 2, resource: BindingResource::TextureView(dst) },
+                    BindGroupEntry { binding: 3, resource: BindingResource::TextureView(blue_noise) },
+                    BindGroupEntry { binding: 4, resource: BindingResource::TextureView(focus) },
+                ],
+            })
+        };
+        (bind("compute_bind_a", a_storage, b_storage), bind("compute_bind_b", b_storage, a_storage))
+    }
+
+    /// Two blue-noise tiles for the blue-noise sampler, one per channel,
+    /// holding each pixel's rank as a fraction of 2^32.
+    fn make_blue_noise(device: &Device, queue: &Queue) -> TextureView {
+        let shift = 32 - (blue_noise::TILE_SIZE * blue_noise::TILE_SIZE).trailing_zeros();
+        let (r, g) = (blue_noise::tile(1), blue_noise::tile(2));
+        let texels: Vec<[u32; 2]> = r.iter().zip(&g).map(|(r, g)| [r << shift, g << shift]).collect();
+        let tex = device.create_texture_with_data(
+            queue,
+            &TextureDescriptor {
+                label: Some("blue noise"),
+                size: Extent3d {
+                    width: blue_noise::TILE_SIZE,
+                    height: blue_noise::TILE_SIZE,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rg32Uint,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(&texels),
+        );
+        tex.create_view(&TextureViewDescriptor::default())
     }
 
     fn make_accum(device: &Device, size: PhysicalSize<u32>) -> (Texture, TextureView, TextureView) {
@@ -377,37 +563,46 @@ impl<'w> Renderer<'w> {
             cpass.set_pipeline(&self.compute_pipeline);
             let cbind = if self.use_a_as_src { &self.compute_bind_a } else { &self.compute_bind_b };
             cpass.set_bind_group(0, cbind, &[]);
-            let gx = (self.size.width + 7) / 8;
-            let gy = (self.size.height + 7) / 8;
+            cpass.set_bind_group(1, &self.gpu_scene.bind_group, &[]);
+            let gx = self.size.width.div_ceil(8);
+            let gy = self.size.height.div_ceil(8);
             cpass.dispatch_workgroups(gx, gy, 1);
         }
         self.last_ms_rt_shadows = t_rt.elapsed().as_secs_f32() * 1000.0;
 
         // --- blit / tonemap ---
         let t_denoise = Instant::now();
-        let surface_tex = self.surface.get_current_texture()?;
-        let view = surface_tex.texture.create_view(&TextureViewDescriptor::default());
-        {
-            let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
-                label: Some("blit pass"),
-                color_attachments: &[Some(RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: Operations { load: LoadOp::Clear(Color::BLACK), store: StoreOp::Store },
-                })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
-            rpass.set_pipeline(&self.blit_pipeline);
-            let bbind = if self.use_a_as_src { &self.blit_bind_b } else { &self.blit_bind_a };
-            rpass.set_bind_group(0, bbind, &[]);
-            rpass.draw(0..3, 0..1);
-        }
+        let surface_tex = match &self.target {
+            Some(target) => {
+                let surface_tex = target.surface.get_current_texture()?;
+                let view = surface_tex.texture.create_view(&TextureViewDescriptor::default());
+                {
+                    let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
+                        label: Some("blit pass"),
+                        color_attachments: &[Some(RenderPassColorAttachment {
+                            view: &view,
+                            resolve_target: None,
+                            ops: Operations { load: LoadOp::Clear(Color::BLACK), store: StoreOp::Store },
+                        })],
+                        depth_stencil_attachment: None,
+                        timestamp_writes: None,
+                        occlusion_query_set: None,
+                    });
+                    rpass.set_pipeline(&target.blit_pipeline);
+                    let bbind = if self.use_a_as_src { &target.blit_bind_b } else { &target.blit_bind_a };
+                    rpass.set_bind_group(0, bbind, &[]);
+                    rpass.draw(0..3, 0..1);
+                }
+                Some(surface_tex)
+            }
+            None => None,
+        };
         self.last_ms_denoise = t_denoise.elapsed().as_secs_f32() * 1000.0;
 
         self.queue.submit([encoder.finish()]);
-        surface_tex.present();
+        if let Some(surface_tex) = surface_tex {
+            surface_tex.present();
+        }
 
         self.frame_index = self.frame_index.wrapping_add(1);
         self.use_a_as_src = !self.use_a_as_src;
@@ -418,25 +613,19 @@ impl<'w> Renderer<'w> {
         Ok(())
     }
 
-    fn view_basis(&self) -> Mat3 {
-        let dir = Vec3::new(
-            self.yaw.cos() * self.pitch.cos(),
-            self.pitch.sin(),
-            self.yaw.sin() * self.pitch.cos(),
-        ).normalize();
-        let right = dir.cross(Vec3::Y).normalize();
-        let up = right.cross(dir).normalize();
-        Mat3::from_cols(right, up, -dir)
-    }
-
     fn update_camera(&mut self) {
-        let basis = self.view_basis();
+        let basis = self.camera.view_basis();
         let dir = -(basis.col(2));
         let right = basis.col(0);
         let up = basis.col(1);
+        let (aperture_kind, aperture_blades, aperture_rotation) = match self.camera.aperture {
+            Aperture::Circle => (0, 0, 0.0),
+            Aperture::Polygon { blades, rotation } => (1, blades.max(3), rotation),
+            Aperture::Mask => (2, 0, 0.0),
+        };
 
         let ubo = CameraUBO {
-            origin: self.cam_pos.to_array(),
+            origin: self.camera.position.to_array(),
             _pad0: 0.0,
             dir: dir.to_array(),
             _pad1: 0.0,
@@ -446,7 +635,24 @@ impl<'w> Renderer<'w> {
             _pad3: 0.0,
             img_size: [self.size.width.max(1), self.size.height.max(1)],
             frame_index: self.frame_index,
-            max_bounce: 4,
+            max_bounce: self.settings.max_bounce,
+            spp: self.settings.spp.max(1),
+            sampler_kind: self.settings.sampler as u32,
+            rr_depth: self.settings.rr_depth,
+            clamp: self.settings.clamp.unwrap_or(0.0),
+            spectral: self.settings.spectral as u32,
+            aperture_radius: self.camera.aperture_radius.max(0.0),
+            focus_distance: self.camera.focus_distance,
+            aperture_kind,
+            aperture_blades,
+            aperture_rotation,
+            focus_pixel: self.focus_pixel,
+            fov_y: self.camera.fov_y_radians,
+            exposure: self.camera.exposure,
+            projection: self.camera.projection as u32,
+            ortho_height: self.camera.ortho_height,
+            eye_separation: self.camera.eye_separation.max(0.0),
+            _pad4: [0; 3],
         };
         self.queue.write_buffer(&self.camera_buf, 0, bytemuck::bytes_of(&ubo));
     }
@@ -468,9 +674,6 @@ impl<'w> Renderer<'w> {
             return;
         }
         self.size = new_size;
-        self.config.width = new_size.width.max(1);
-        self.config.height = new_size.height.max(1);
-        self.surface.configure(&self.device, &self.config);
 
         // Recreate accumulation textures
         let (accum_a, a_storage, a_sample) = Self::make_accum(&self.device, self.size);
@@ -483,80 +686,275 @@ impl<'w> Renderer<'w> {
         self.accum_b_view_sample = b_sample;
 
         // Rebuild bind groups after resize
-        self.compute_bind_a = self.device.create_bind_group(&BindGroupDescriptor {
-            label: Some("compute_bind_a"),
-            layout: &self.compute_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: self.camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(&self.accum_a_view_storage) },
-                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(&self.accum_b_view_storage) },
-            ],
-        });
-        self.compute_bind_b = self.device.create_bind_group(&BindGroupDescriptor {
-            label: Some("compute_bind_b"),
-            layout: &self.compute_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: self.camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(&self.accum_b_view_storage) },
-                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(&self.accum_a_view_storage) },
-            ],
-        });
-        self.blit_bind_a = self.device.create_bind_group(&BindGroupDescriptor {
-            label: Some("blit_bind_a"),
-            layout: &self.blit_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: self.camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(&self.accum_a_view_sample) },
-                BindGroupEntry { binding: 4, resource: BindingResource::Sampler(&self.sampler) },
-            ],
-        });
-        self.blit_bind_b = self.device.create_bind_group(&BindGroupDescriptor {
-            label: Some("blit_bind_b"),
-            layout: &self.blit_bind_layout,
-            entries: &[
-                BindGroupEntry { binding: 0, resource: self.camera_buf.as_entire_binding() },
-                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(&self.accum_b_view_sample) },
-                BindGroupEntry { binding: 4, resource: BindingResource::Sampler(&self.sampler) },
-            ],
-        });
+        (self.compute_bind_a, self.compute_bind_b) = Self::make_compute_binds(
+            &self.device,
+            &self.compute_bind_layout,
+            &self.camera_buf,
+            &self.blue_noise_view,
+            &self.focus_view,
+            &self.accum_a_view_storage,
+            &self.accum_b_view_storage,
+        );
+        if let Some(target) = &mut self.target {
+            target.config.width = new_size.width.max(1);
+            target.config.height = new_size.height.max(1);
+            target.surface.configure(&self.device, &target.config);
+            let (blit_bind_a, blit_bind_b) = Self::make_blit_binds(
+                &self.device,
+                &target.blit_bind_layout,
+                &self.camera_buf,
+                &target.sampler,
+                &self.accum_a_view_sample,
+                &self.accum_b_view_sample,
+            );
+            target.blit_bind_a = blit_bind_a;
+            target.blit_bind_b = blit_bind_b;
+        }
 
         self.reset_accum();
-        self.update_camera();
     }
 
+    /// Restarts progressive accumulation from the next frame on.
     pub fn reset_accum(&mut self) {
         self.frame_index = 0;
+        self.update_camera();
+    }
+
+    /// Replaces the traced scene and returns the statistics of the instance
+    /// and mesh BVHs built for it. The renderer starts out with [`Scene::cornell_box`];
+    /// the scene's suggested camera is not applied. Fails if the scene's
+    /// density grids do not fit in the device's 3D textures.
+    pub fn upload_scene(&mut self, scene: &Scene) -> Result<AccelStats> {
+        let (gpu_scene, stats) = GpuScene::upload(&self.device, &self.queue, &self.scene_bind_layout, scene)?;
+        self.gpu_scene = gpu_scene;
+        self.reset_accum();
+        Ok(stats)
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+        self.reset_accum();
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: RenderSettings) {
+        self.settings = settings;
+        self.reset_accum();
     }
 
     pub fn queue_movement(&mut self, m: Movement) {
-        let amt = 0.2;
-        match m {
-            Movement::Forward  => self.move_delta.z -= amt,
-            Movement::Backward => self.move_delta.z += amt,
-            Movement::Left     => self.move_delta.x -= amt,
-            Movement::Right    => self.move_delta.x += amt,
-            Movement::Up       => self.move_delta.y += amt,
-            Movement::Down     => self.move_delta.y -= amt,
-        }
-        self.cam_pos += self.view_basis() * self.move_delta;
-        self.move_delta = Vec3::ZERO;
+        self.camera.apply_movement(m);
         self.reset_accum();
-        self.update_camera();
     }
 
     pub fn on_mouse_delta(&mut self, dx: f32, dy: f32) {
-        let sensitivity = 0.0025;
-        self.yaw   -= dx * sensitivity;
-        self.pitch -= dy * sensitivity;
-        self.pitch = self.pitch.clamp(-1.5, 1.5);
+        self.camera.rotate(dx, dy);
         self.reset_accum();
+    }
+
+    /// Focuses the camera on the surface seen through pixel (`x`, `y`) and
+    /// returns the new focus distance, or `None` if nothing is there. Blocks
+    /// until the GPU has finished.
+    pub fn focus_at(&mut self, x: u32, y: u32) -> Result<Option<f32>> {
+        self.focus_pixel = [x.min(self.size.width.max(1) - 1), y.min(self.size.height.max(1) - 1)];
         self.update_camera();
+
+        let readback = self.device.create_buffer(&BufferDescriptor {
+            label: Some("focus readback"),
+            size: 4,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&CommandEncoderDescriptor { label: Some("focus encoder") });
+        {
+            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor { label: Some("focus pass"), ..Default::default() });
+            cpass.set_pipeline(&self.focus_pipeline);
+            cpass.set_bind_group(0, &self.compute_bind_a, &[]);
+            cpass.set_bind_group(1, &self.gpu_scene.bind_group, &[]);
+            cpass.dispatch_workgroups(1, 1, 1);
+        }
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture { texture: &self.focus_tex, mip_level: 0, origin: Origin3d::ZERO, aspect: TextureAspect::All },
+            ImageCopyBuffer { buffer: &readback, layout: ImageDataLayout { offset: 0, bytes_per_row: None, rows_per_image: None } },
+            Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        let (tx, rx) = mpsc::channel();
+        slice.map_async(MapMode::Read, move |res| {
+            let _ = tx.send(res);
+        });
+        self.device.poll(Maintain::Wait);
+        rx.recv()??;
+        let depth = f32::from_le_bytes(<[u8; 4]>::try_from(&slice.get_mapped_range()[..]).expect("4 bytes"));
+        readback.unmap();
+
+        if depth <= 0.0 {
+            return Ok(None);
+        }
+        self.camera.focus_distance = depth;
+        self.reset_accum();
+        Ok(Some(depth))
     }
 
     pub fn on_scroll(&mut self, delta: f32) {
-        self.fov_y_radians = (self.fov_y_radians - delta * 0.02)
-            .clamp(10f32.to_radians(), 90f32.to_radians());
+        match self.camera.projection {
+            Projection::Orthographic => self.camera.ortho_height *= 0.95f32.powf(delta),
+            // a fisheye can look all the way round
+            Projection::Fisheye => {
+                let fov = self.camera.fov_y_radians - delta * 0.02;
+                self.camera.set_fov(fov.clamp(10f32.to_radians(), 360f32.to_radians()));
+            }
+            Projection::Perspective | Projection::Equirectangular => {
+                let fov = self.camera.fov_y_radians - delta * 0.02;
+                self.camera.set_fov(fov.clamp(10f32.to_radians(), MAX_SCROLL_FOV.to_radians()));
+            }
+        }
         self.reset_accum();
-        self.update_camera();
+    }
+
+    /// Switches the camera's projection. A field of view the new projection
+    /// cannot show, e.g. a wide fisheye's, is brought back into the scroll
+    /// range, and orthographic views start out framed like the perspective
+    /// view at the focus distance.
+    pub fn set_projection(&mut self, projection: Projection) {
+        let camera = &mut self.camera;
+        camera.projection = projection;
+        if !projection.supports_fov(camera.fov_y_radians) {
+            camera.set_fov(MAX_SCROLL_FOV.to_radians());
+        }
+        if projection == Projection::Orthographic {
+            camera.ortho_height = camera.focus_plane_height();
+        }
+        self.reset_accum();
+    }
+}
+
+impl<'w> Renderer<'w> {
+    /// Creates a renderer without a window. Frames are traced into the
+    /// offscreen accumulation textures and fetched with [`Renderer::read_image`].
+    ///
+    /// With `force_fallback_adapter` only a software adapter (e.g. llvmpipe or
+    /// WARP) is accepted, which is what build servers without a GPU provide.
+    pub async fn new_headless(
+        width: u32,
+        height: u32,
+        force_fallback_adapter: bool,
+    ) -> Result<Renderer<'static>> {
+        let instance = Instance::new(InstanceDescriptor {
+            backends: Backends::all(),
+            ..Default::default()
+        });
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptions {
+                compatible_surface: None,
+                power_preference: PowerPreference::HighPerformance,
+                force_fallback_adapter,
+            })
+            .await
+            .ok_or_else(|| {
+                if force_fallback_adapter {
+                    anyhow::anyhow!("No fallback (software) adapter found")
+                } else {
+                    anyhow::anyhow!("No GPU adapter found")
+                }
+            })?;
+        let (device, queue) = Self::request_device(&adapter).await?;
+
+        let size = PhysicalSize::new(width.max(1), height.max(1));
+        Ok(Renderer::from_device(device, queue, size, None))
+    }
+
+    /// Copies the accumulated radiance back to the CPU as linear RGBA, row
+    /// major from the top-left pixel; spectral renders are converted from
+    /// XYZ, clipping colours outside sRGB. Blocks until the GPU has finished.
+    pub fn read_image(&self) -> Result<HdrImage> {
+        // the texture written by the last frame is the next frame's source
+        let src = if self.use_a_as_src { &self.accum_a } else { &self.accum_b };
+        let width = self.size.width.max(1);
+        let height = self.size.height.max(1);
+        let texel_bytes = 8; // Rgba16Float
+        let unpadded_row = width * texel_bytes;
+        let padded_row = unpadded_row.div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT) * COPY_BYTES_PER_ROW_ALIGNMENT;
+
+        let readback = self.device.create_buffer(&BufferDescriptor {
+            label: Some("readback buffer"),
+            size: (padded_row * height) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&CommandEncoderDescriptor { label: Some("readback encoder") });
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture { texture: src, mip_level: 0, origin: Origin3d::ZERO, aspect: TextureAspect::All },
+            ImageCopyBuffer {
+                buffer: &readback,
+                layout: ImageDataLayout { offset: 0, bytes_per_row: Some(padded_row), rows_per_image: Some(height) },
+            },
+            Extent3d { width, height, depth_or_array_layers: 1 },
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        let (tx, rx) = mpsc::channel();
+        slice.map_async(MapMode::Read, move |res| {
+            let _ = tx.send(res);
+        });
+        self.device.poll(Maintain::Wait);
+        rx.recv()??;
+
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks_exact(padded_row as usize) {
+                let texels: &[half::f16] = bytemuck::cast_slice(&row[..unpadded_row as usize]);
+                pixels.extend(texels.chunks_exact(4).map(|t| {
+                    let c = [t[0].to_f32(), t[1].to_f32(), t[2].to_f32()];
+                    let [r, g, b] = if self.settings.spectral {
+                        XYZ_TO_RGB.map(|row| (row[0] * c[0] + row[1] * c[1] + row[2] * c[2]).max(0.0))
+                    } else {
+                        c
+                    };
+                    [r, g, b, t[3].to_f32()]
+                }));
+            }
+        }
+        readback.unmap();
+        Ok(HdrImage { width, height, pixels })
+    }
+
+    /// Number of frames accumulated since the last reset.
+    pub fn frame_index(&self) -> u32 {
+        self.frame_index
+    }
+
+    /// Samples per pixel accumulated since the last reset.
+    pub fn samples_accumulated(&self) -> u32 {
+        self.frame_index * self.settings.spp.max(1)
+    }
+
+    /// Renders frames until at least `target_samples` samples per pixel have
+    /// been accumulated, calling `progress` with the running count after
+    /// each frame.
+    pub fn accumulate(&mut self, target_samples: u32, mut progress: impl FnMut(u32)) -> Result<()> {
+        while self.samples_accumulated() < target_samples {
+            self.render()?;
+            progress(self.samples_accumulated());
+        }
+        // make sure the last frame has landed before anyone reads it back
+        self.device.poll(Maintain::Wait);
+        Ok(())
+    }
+
+    pub fn size(&self) -> PhysicalSize<u32> {
+        self.size
     }
 }
@@ -0,0 +1,1453 @@
+use anyhow::{bail, Context, Result};
+use glam::{EulerRot, Mat4, Quat, Vec3};
+use image::RgbaImage;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::camera::{Aperture, Camera, PhysicalCamera, Projection};
+use crate::environment::{self, Environment};
+use crate::gltf_import;
+use crate::hdr_image::HdrImage;
+use crate::mesh::{self, Mesh};
+use crate::sky::{self, Sky};
+use crate::volume::{DensityGrid, VoxelFormat};
+
+/// Surface response of a material, with the parameters of each model
+/// (`MAT_*` and `Material.params` in compute.wgsl).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaterialKind {
+    /// Lambertian reflection of `albedo`.
+    Diffuse,
+    /// Perfect specular reflection tinted by `albedo`.
+    Mirror,
+    /// Dielectric; the surface normal points to the outside medium (IOR 1).
+    /// Transmission is tinted by `albedo`. A `roughness` above zero gives
+    /// frosted glass (GGX microfacets). Spectral rendering follows
+    /// `dispersion`; RGB rendering uses `ior` for all colours.
+    Glass { ior: f32, roughness: f32, dispersion: Dispersion },
+    /// GGX microfacet metal. The Fresnel term uses `complex_ior` when set,
+    /// else Schlick's approximation with `albedo` as the normal-incidence
+    /// reflectance.
+    Conductor { roughness: f32, complex_ior: Option<ComplexIor> },
+    /// Disney/principled BSDF; `albedo` is the base colour.
+    Principled(Principled),
+    /// Invisible boundary of the material's medium; rays pass straight
+    /// through.
+    Interface,
+}
+
+/// Parameters of the principled BSDF, all but `ior` within [0, 1].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Principled {
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Dielectric specular reflectance; 0.5 is an F0 of 4 %.
+    pub specular: f32,
+    /// Tints the dielectric specular towards the base colour.
+    pub specular_tint: f32,
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub transmission: f32,
+    /// Blends the diffuse lobe towards a flattened subsurface look.
+    pub subsurface: f32,
+    /// Used by the transmission lobe.
+    pub ior: f32,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Self {
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            transmission: 0.0,
+            subsurface: 0.0,
+            ior: 1.5,
+        }
+    }
+}
+
+/// Complex index of refraction `eta + i k` of a conductor, per RGB channel.
+/// `albedo` still tints the reflection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ComplexIor {
+    pub eta: Vec3,
+    pub k: Vec3,
+}
+
+impl ComplexIor {
+    /// Measured values for a few common metals, by lowercase name (`gold`,
+    /// `silver`, `copper`, `aluminium`).
+    pub fn metal(name: &str) -> Option<Self> {
+        let (eta, k) = match name {
+            "gold" => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+            "silver" => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+            "copper" => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+            "aluminium" | "aluminum" => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+            _ => return None,
+        };
+        Some(Self { eta: Vec3::from(eta), k: Vec3::from(k) })
+    }
+}
+
+/// How the IOR of a dielectric varies with the wavelength `λ`, in
+/// micrometres.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Dispersion {
+    /// The same IOR at every wavelength.
+    #[default]
+    None,
+    /// Cauchy's equation, `n = a + b / λ²`.
+    Cauchy { a: f32, b: f32 },
+    /// Sellmeier's equation, `n² = 1 + Σ b_i λ² / (λ² - c_i)` with `c_i` in
+    /// µm².
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+/// Fraunhofer d, F and C lines in µm, which define the Abbe number.
+const LINE_D: f32 = 0.5876;
+const LINE_F: f32 = 0.4861;
+const LINE_C: f32 = 0.6563;
+
+impl Dispersion {
+    /// Cauchy's equation through `ior` at the d line (587.6 nm) with the
+    /// given Abbe number; lower numbers disperse more (crown glass is
+    /// about 60, dense flint 25).
+    pub fn from_abbe(ior: f32, abbe: f32) -> Self {
+        let b = (ior - 1.0) / (abbe * (1.0 / (LINE_F * LINE_F) - 1.0 / (LINE_C * LINE_C)));
+        Self::Cauchy { a: ior - b / (LINE_D * LINE_D), b }
+    }
+
+    /// Sellmeier coefficients of a few optical materials, by lowercase name
+    /// (`bk7`, `fused_silica`, `sf11`, `diamond`).
+    pub fn glass(name: &str) -> Option<Self> {
+        let (b, c) = match name {
+            "bk7" => ([1.039_612, 0.231_792_34, 1.010_469_5], [0.006_000_699, 0.020_017_914, 103.560_65]),
+            "fused_silica" => ([0.696_166_3, 0.407_942_6, 0.897_479_4], [0.004_679_148, 0.013_512_063, 97.934]),
+            "sf11" => ([1.737_597, 0.313_747_35, 1.898_781], [0.013_188_707, 0.062_306_814, 155.236_3]),
+            "diamond" => ([0.3306, 4.3356, 0.0], [0.030_625, 0.011_236, 0.0]),
+            _ => return None,
+        };
+        Some(Self::Sellmeier { b, c })
+    }
+
+    /// IOR at `wavelength` µm, `None` without dispersion.
+    pub fn ior(&self, wavelength: f32) -> Option<f32> {
+        let l2 = wavelength * wavelength;
+        match *self {
+            Self::None => None,
+            Self::Cauchy { a, b } => Some(a + b / l2),
+            Self::Sellmeier { b, c } => Some((1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt()),
+        }
+    }
+}
+
+/// Squared roughness, the GGX alpha the shader works with.
+pub(crate) fn ggx_alpha(roughness: f32) -> f32 {
+    roughness * roughness
+}
+
+/// Optional texture maps of a material, as indices into [`Scene::textures`].
+/// Albedo and emission textures are sRGB encoded and multiply the constant
+/// values; the normal map is a tangent-space glTF-style map.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialTextures {
+    pub albedo: Option<u32>,
+    pub normal: Option<u32>,
+    /// Scales the normal map's tangent-space x and y.
+    pub normal_scale: f32,
+    pub emission: Option<u32>,
+    /// Principled only: scales roughness (green) and metallic (blue), as in glTF.
+    pub metallic_roughness: Option<u32>,
+}
+
+impl Default for MaterialTextures {
+    fn default() -> Self {
+        Self { albedo: None, normal: None, normal_scale: 1.0, emission: None, metallic_roughness: None }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Material {
+    pub albedo: Vec3,
+    pub emission: Vec3,
+    pub kind: MaterialKind,
+    pub textures: MaterialTextures,
+    /// Medium inside closed surfaces of this material, as an index into
+    /// [`Scene::media`]; the surface normals must point outwards.
+    pub medium: Option<u32>,
+}
+
+impl Material {
+    pub fn diffuse(albedo: Vec3) -> Self {
+        Self::new(albedo, Vec3::ZERO, MaterialKind::Diffuse)
+    }
+
+    pub fn emissive(emission: Vec3) -> Self {
+        Self::new(Vec3::ZERO, emission, MaterialKind::Diffuse)
+    }
+
+    pub fn new(albedo: Vec3, emission: Vec3, kind: MaterialKind) -> Self {
+        Self { albedo, emission, kind, textures: MaterialTextures::default(), medium: None }
+    }
+}
+
+/// Participating medium, filling the inside of closed surfaces whose
+/// material names it, or all space as [`Scene::atmosphere`]. Coefficients
+/// are per unit length, for each RGB channel.
+#[derive(Clone, Copy, Debug)]
+pub struct Medium {
+    pub absorption: Vec3,
+    pub scattering: Vec3,
+    /// Henyey-Greenstein anisotropy in (-1, 1); positive values scatter
+    /// forwards.
+    pub g: f32,
+    /// Index into [`Scene::grids`] scaling the coefficients; homogeneous
+    /// without one.
+    pub density: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Sphere { center: Vec3, radius: f32 },
+    /// Parallelogram spanned by `u` and `v` from `corner`; the normal is `u x v`.
+    Quad { corner: Vec3, u: Vec3, v: Vec3 },
+    /// Flat disk; `normal` need not be normalised.
+    Disk { center: Vec3, normal: Vec3, radius: f32 },
+}
+
+/// An emitter in [`Scene::lights`], radiating `color * intensity`.
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Vec3,
+    pub intensity: f32,
+}
+
+/// Shape of a [`Light`]. For point and spot lights the intensity is radiant
+/// intensity (per steradian), for directional lights the irradiance on a
+/// surface facing them, and for area lights their radiance. Area lights
+/// are visible to rays and one-sided, except spheres.
+#[derive(Clone, Copy, Debug)]
+pub enum LightKind {
+    Point { position: Vec3 },
+    /// Point light shining along `direction`, fading out between the inner
+    /// and outer cone angles (radians, from the axis).
+    Spot { position: Vec3, direction: Vec3, inner_angle: f32, outer_angle: f32 },
+    /// Infinitely far light travelling along `direction`, seen as a disk
+    /// of `angular_radius` (radians; 0 for parallel rays and sharp shadows).
+    Directional { direction: Vec3, angular_radius: f32 },
+    /// Parallelogram spanned by `u` and `v`, emitting towards `u x v`.
+    Rect { corner: Vec3, u: Vec3, v: Vec3 },
+    /// Disk emitting towards `normal`.
+    Disk { center: Vec3, normal: Vec3, radius: f32 },
+    Sphere { center: Vec3, radius: f32 },
+}
+
+impl Light {
+    pub fn new(kind: LightKind, color: Vec3, intensity: f32) -> Self {
+        Self { kind, color, intensity }
+    }
+
+    /// The surface of an area light, `None` for point, spot and directional
+    /// lights.
+    pub fn shape(&self) -> Option<Shape> {
+        match self.kind {
+            LightKind::Point { .. } | LightKind::Spot { .. } | LightKind::Directional { .. } => None,
+            LightKind::Rect { corner, u, v } => Some(Shape::Quad { corner, u, v }),
+            LightKind::Disk { center, normal, radius } => Some(Shape::Disk { center, normal, radius }),
+            LightKind::Sphere { center, radius } => Some(Shape::Sphere { center, radius }),
+        }
+    }
+}
+
+/// Placement of a shared [`Mesh`]; many instances can reference one mesh.
+#[derive(Clone, Copy, Debug)]
+pub struct Instance {
+    /// Index into [`Scene::meshes`].
+    pub mesh: u32,
+    /// Object to world transform.
+    pub transform: Mat4,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Primitive {
+    pub shape: Shape,
+    /// Index into [`Scene::materials`].
+    pub material: u32,
+}
+
+/// Media the tracer holds at once (`SceneUBO.media` in compute.wgsl).
+pub const MAX_MEDIA: usize = 16;
+
+/// Everything the tracer needs besides the camera settings, in world space.
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    /// Suggested viewpoint; the renderer does not apply it on its own.
+    pub camera: Option<Camera>,
+    /// Opening of the lens for [`Aperture::Mask`], weighted by luminance;
+    /// the image is stretched to a square.
+    pub aperture_mask: Option<HdrImage>,
+    pub materials: Vec<Material>,
+    pub primitives: Vec<Primitive>,
+    /// Lights besides emissive materials, which are lights as well.
+    pub lights: Vec<Light>,
+    /// Background and distant light; without one, rays escaping the scene
+    /// see black.
+    pub environment: Option<Environment>,
+    /// Media referenced by [`Material::medium`] and [`Scene::atmosphere`],
+    /// at most [`MAX_MEDIA`]. Volumes do not nest: leaving one returns to
+    /// the atmosphere.
+    pub media: Vec<Medium>,
+    /// Medium filling the space outside all volumes, which the camera is
+    /// in. It hides the environment beyond a few mean free paths.
+    pub atmosphere: Option<u32>,
+    /// Voxel grids referenced by [`Medium::density`].
+    pub grids: Vec<DensityGrid>,
+    /// Triangle meshes in object space, placed by [`Scene::instances`].
+    pub meshes: Vec<Mesh>,
+    pub instances: Vec<Instance>,
+    /// RGBA8 images referenced by [`MaterialTextures`]. They are resampled
+    /// to a common size on upload.
+    pub textures: Vec<RgbaImage>,
+    /// Problems that did not stop loading, e.g. skipped glTF extensions.
+    pub warnings: Vec<String>,
+}
+
+impl Scene {
+    /// The built-in Cornell box (`scenes/cornell.toml`).
+    pub fn cornell_box() -> Self {
+        Self::parse(include_str!("../scenes/cornell.toml"), Path::new("."))
+            .expect("built-in scene is valid")
+    }
+
+    /// Loads a TOML scene description, or a glTF/GLB file directly.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("gltf") || e.eq_ignore_ascii_case("glb")) {
+            let mut scene = Scene::default();
+            gltf_import::load_gltf(path, &mut scene, Mat4::IDENTITY)?;
+            return Ok(scene);
+        }
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read scene {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        Self::parse(&src, base_dir).with_context(|| format!("invalid scene {}", path.display()))
+    }
+
+    /// Parses a TOML scene description; relative file paths in it are
+    /// resolved against `base_dir`.
+    pub fn parse(src: &str, base_dir: &Path) -> Result<Self> {
+        let desc: SceneDesc = toml::from_str(src)?;
+        desc.build(base_dir)
+    }
+
+    pub fn add_material(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        (self.materials.len() - 1) as u32
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh) -> u32 {
+        self.meshes.push(mesh);
+        (self.meshes.len() - 1) as u32
+    }
+
+    pub fn add_instance(&mut self, mesh: u32, transform: Mat4) {
+        self.instances.push(Instance { mesh, transform });
+    }
+
+    pub fn add_medium(&mut self, medium: Medium) -> u32 {
+        self.media.push(medium);
+        (self.media.len() - 1) as u32
+    }
+
+    pub fn add_grid(&mut self, grid: DensityGrid) -> u32 {
+        self.grids.push(grid);
+        (self.grids.len() - 1) as u32
+    }
+
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    /// Lights the scene with `sky`: its dome becomes the environment and
+    /// the sun, while above the horizon, a directional light.
+    pub fn set_sky(&mut self, sky: &Sky) {
+        self.environment = Some(sky.environment());
+        self.lights.extend(sky.sun());
+    }
+}
+
+// --- scene file format ---
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: Option<CameraDesc>,
+    /// Name of the medium filling all space.
+    atmosphere: Option<String>,
+    #[serde(default)]
+    media: Vec<MediumDesc>,
+    #[serde(default)]
+    materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    meshes: Vec<MeshDesc>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>,
+    #[serde(default)]
+    lights: Vec<LightDesc>,
+    environment: Option<EnvironmentDesc>,
+    sky: Option<SkyDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    /// Equirectangular `.hdr` or `.exr`.
+    path: String,
+    /// Degrees about +Y.
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "default_light_intensity")]
+    intensity: f32,
+}
+
+/// Sun placed either by `sun_elevation` and `sun_azimuth`, or by `date`,
+/// `time`, `latitude` and `longitude`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkyDesc {
+    /// Degrees above the horizon.
+    sun_elevation: Option<f32>,
+    /// Degrees clockwise from north (-Z) towards east (+X).
+    sun_azimuth: Option<f32>,
+    /// `YYYY-MM-DD`
+    date: Option<String>,
+    /// `HH:MM` local time.
+    time: Option<String>,
+    /// Degrees, north positive.
+    latitude: Option<f32>,
+    /// Degrees, east positive.
+    longitude: Option<f32>,
+    /// Hours local time is ahead of UTC.
+    #[serde(default)]
+    utc_offset: f32,
+    turbidity: Option<f32>,
+    intensity: Option<f32>,
+    ground_albedo: Option<f32>,
+}
+
+impl SkyDesc {
+    fn sky(&self) -> Result<Sky> {
+        let (elevation, azimuth) = match (self, self.sun_elevation, self.sun_azimuth) {
+            (SkyDesc { date: None, time: None, latitude: None, longitude: None, .. }, Some(e), Some(a)) => {
+                (e.to_radians(), a.to_radians())
+            }
+            (
+                SkyDesc { date: Some(date), time: Some(time), latitude: Some(lat), longitude: Some(lon), .. },
+                None,
+                None,
+            ) => {
+                let day = parse_date(date).with_context(|| format!("invalid date {date:?}, expected YYYY-MM-DD"))?;
+                let hours = parse_time(time).with_context(|| format!("invalid time {time:?}, expected HH:MM"))?;
+                sky::solar_position(day, hours, self.utc_offset, *lat, *lon)
+            }
+            _ => bail!("place the sun with sun_elevation and sun_azimuth, or with date, time, latitude and longitude"),
+        };
+        let mut sky = Sky::new(Sky::sun_direction(elevation, azimuth));
+        sky.turbidity = self.turbidity.unwrap_or(sky.turbidity);
+        sky.intensity = self.intensity.unwrap_or(sky.intensity);
+        sky.ground_albedo = self.ground_albedo.unwrap_or(sky.ground_albedo);
+        if !(1.7..=10.0).contains(&sky.turbidity) {
+            bail!("sky turbidity must be between 1.7 and 10");
+        }
+        if !(sky.intensity.is_finite() && sky.intensity >= 0.0 && (0.0..=1.0).contains(&sky.ground_albedo)) {
+            bail!("sky intensity must not be negative and ground_albedo must be in [0, 1]");
+        }
+        Ok(sky)
+    }
+}
+
+fn parse_date(s: &str) -> Option<u32> {
+    let mut parts = s.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    sky::day_of_year(year, month, day)
+}
+
+fn parse_time(s: &str) -> Option<f32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60).then(|| h as f32 + m as f32 / 60.0)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    position: [f32; 3],
+    look_at: [f32; 3],
+    #[serde(default)]
+    projection: ProjectionDesc,
+    /// Vertical field of view in degrees; up to 360 for a fisheye.
+    fov: Option<f32>,
+    /// Height of an orthographic view; defaults to what the perspective
+    /// view covers at the focus distance.
+    ortho_height: Option<f32>,
+    /// Stereo panorama with the eyes this far apart.
+    eye_separation: Option<f32>,
+    /// Lens radius; zero (the default) for a pinhole.
+    aperture_radius: Option<f32>,
+    /// Sets the field of view, aperture radius and exposure instead.
+    physical: Option<PhysicalCameraDesc>,
+    /// Defaults to the distance to `look_at`.
+    focus_distance: Option<f32>,
+    /// Polygonal aperture with this many corners instead of a circle.
+    aperture_blades: Option<u32>,
+    /// Degrees the polygon is turned by.
+    #[serde(default)]
+    aperture_rotation: f32,
+    /// Image whose luminance shapes the aperture.
+    aperture_mask: Option<String>,
+}
+
+impl CameraDesc {
+    fn camera(&self) -> Result<Camera> {
+        let mut camera = Camera::look_at(Vec3::from(self.position), Vec3::from(self.look_at));
+        if let Some(p) = &self.physical {
+            if self.fov.is_some() || self.aperture_radius.is_some() {
+                bail!("fov and aperture_radius follow from the physical camera");
+            }
+            camera.set_physical(&p.physical()?);
+        }
+        camera.projection = match self.projection {
+            ProjectionDesc::Perspective => Projection::Perspective,
+            ProjectionDesc::Orthographic => Projection::Orthographic,
+            ProjectionDesc::Fisheye => Projection::Fisheye,
+            ProjectionDesc::Equirectangular => Projection::Equirectangular,
+        };
+        if let Some(fov) = self.fov {
+            if !camera.projection.supports_fov(fov.to_radians()) {
+                bail!("fov must be in (0, 180) degrees, or (0, 360] for a fisheye");
+            }
+            camera.fov_y_radians = fov.to_radians();
+        }
+        if let Some(r) = self.aperture_radius {
+            if !(r.is_finite() && r >= 0.0) {
+                bail!("aperture_radius must not be negative");
+            }
+            camera.aperture_radius = r;
+        }
+        if let Some(d) = self.focus_distance {
+            if !(d.is_finite() && d > 0.0) {
+                bail!("focus_distance must be positive");
+            }
+            camera.focus_distance = d;
+        }
+        match self.ortho_height {
+            Some(_) if camera.projection != Projection::Orthographic => bail!("ortho_height needs projection = \"orthographic\""),
+            Some(h) if !(h.is_finite() && h > 0.0) => bail!("ortho_height must be positive"),
+            Some(h) => camera.ortho_height = h,
+            None => camera.ortho_height = camera.focus_plane_height(),
+        }
+        match self.eye_separation {
+            Some(_) if camera.projection != Projection::Equirectangular => {
+                bail!("eye_separation needs projection = \"equirectangular\"")
+            }
+            Some(e) if !(e.is_finite() && e >= 0.0) => bail!("eye_separation must not be negative"),
+            Some(e) => camera.eye_separation = e,
+            None => {}
+        }
+        camera.aperture = match (self.aperture_blades, &self.aperture_mask) {
+            (Some(_), Some(_)) => bail!("an aperture has blades or a mask, not both"),
+            (Some(blades), None) if blades < 3 => bail!("aperture_blades must be at least 3"),
+            (Some(blades), None) => Aperture::Polygon { blades, rotation: self.aperture_rotation.to_radians() },
+            (None, Some(_)) => Aperture::Mask,
+            (None, None) => Aperture::Circle,
+        };
+        Ok(camera)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum ProjectionDesc {
+    #[default]
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+/// Unset fields come from [`PhysicalCamera::default`].
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PhysicalCameraDesc {
+    /// Millimetres.
+    focal_length: f32,
+    /// Millimetres.
+    sensor_height: f32,
+    f_stop: f32,
+    /// Seconds.
+    shutter_speed: f32,
+    iso: f32,
+}
+
+impl Default for PhysicalCameraDesc {
+    fn default() -> Self {
+        let PhysicalCamera { focal_length, sensor_height, f_stop, shutter_speed, iso } = PhysicalCamera::default();
+        Self { focal_length, sensor_height, f_stop, shutter_speed, iso }
+    }
+}
+
+impl PhysicalCameraDesc {
+    fn physical(&self) -> Result<PhysicalCamera> {
+        let Self { focal_length, sensor_height, f_stop, shutter_speed, iso } = *self;
+        if ![focal_length, sensor_height, f_stop, shutter_speed, iso].iter().all(|v| v.is_finite() && *v > 0.0) {
+            bail!("physical camera settings must be positive");
+        }
+        Ok(PhysicalCamera { focal_length, sensor_height, f_stop, shutter_speed, iso })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MediumDesc {
+    name: String,
+    #[serde(default)]
+    absorption: [f32; 3],
+    #[serde(default)]
+    scattering: [f32; 3],
+    #[serde(default)]
+    g: f32,
+    density: Option<DensityDesc>,
+}
+
+/// Voxel grid scaling a medium's coefficients.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DensityDesc {
+    /// Mitsuba `.vol`, or a raw dump of `resolution` voxels.
+    path: String,
+    resolution: Option<[u32; 3]>,
+    #[serde(default)]
+    format: VoxelFormatDesc,
+    /// World-space box the grid fills; by default the one in the `.vol`
+    /// header, or the unit cube.
+    min: Option<[f32; 3]>,
+    max: Option<[f32; 3]>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum VoxelFormatDesc {
+    U8,
+    #[default]
+    F32,
+}
+
+impl DensityDesc {
+    fn grid(&self, base_dir: &Path) -> Result<DensityGrid> {
+        let path = base_dir.join(&self.path);
+        let is_vol = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("vol"));
+        let grid = match (is_vol, self.resolution) {
+            (true, None) => DensityGrid::load_vol(&path)?,
+            (true, Some(_)) => bail!("a .vol file carries its own resolution"),
+            (false, Some(resolution)) => {
+                let format = match self.format {
+                    VoxelFormatDesc::U8 => VoxelFormat::U8,
+                    VoxelFormatDesc::F32 => VoxelFormat::F32,
+                };
+                DensityGrid::load_raw(&path, resolution, format)?
+            }
+            (false, None) => bail!("raw density grids need a resolution"),
+        };
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => DensityGrid::new(grid.resolution, grid.density, Vec3::from(min), Vec3::from(max)),
+            (None, None) => Ok(grid),
+            _ => bail!("give both min and max, or neither"),
+        }
+    }
+}
+
+impl MediumDesc {
+    fn medium(&self) -> Result<Medium> {
+        let coeffs = self.absorption.iter().chain(&self.scattering);
+        if !coeffs.clone().all(|c| c.is_finite() && *c >= 0.0) {
+            bail!("absorption and scattering must not be negative");
+        }
+        if !(self.g > -1.0 && self.g < 1.0) {
+            bail!("g must be within (-1, 1), got {}", self.g);
+        }
+        Ok(Medium {
+            absorption: Vec3::from(self.absorption),
+            scattering: Vec3::from(self.scattering),
+            g: self.g,
+            density: None,
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum MaterialKindDesc {
+    #[default]
+    Diffuse,
+    Mirror,
+    Glass,
+    #[serde(alias = "metal")]
+    Conductor,
+    Principled,
+    Interface,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    name: String,
+    #[serde(default)]
+    kind: MaterialKindDesc,
+    /// Defaults to 0.8 for diffuse and 1 (untinted) otherwise.
+    albedo: Option<[f32; 3]>,
+    #[serde(default)]
+    emission: [f32; 3],
+    /// Defaults to 1.5, or for glass with `cauchy`, `sellmeier` or
+    /// `dispersion` the IOR at 587.6 nm.
+    ior: Option<f32>,
+    /// Glass dispersion, at most one of: an Abbe number (with `ior`),
+    /// Cauchy's `[a, b]`, Sellmeier's `[b1, b2, b3, c1, c2, c3]` or a named
+    /// glass, see [`Dispersion::glass`].
+    abbe: Option<f32>,
+    cauchy: Option<[f32; 2]>,
+    sellmeier: Option<[f32; 6]>,
+    dispersion: Option<String>,
+    /// Glass, conductors and principled, 0 (smooth) to 1; defaults to 0,
+    /// or 0.5 for principled.
+    roughness: Option<f32>,
+    /// Conductors: named complex IOR, see [`ComplexIor::metal`].
+    metal: Option<String>,
+    /// Conductors: complex IOR per channel, given together.
+    eta: Option<[f32; 3]>,
+    k: Option<[f32; 3]>,
+    /// Principled lobes; defaults as in [`Principled::default`].
+    metallic: Option<f32>,
+    specular: Option<f32>,
+    specular_tint: Option<f32>,
+    clearcoat: Option<f32>,
+    clearcoat_gloss: Option<f32>,
+    sheen: Option<f32>,
+    sheen_tint: Option<f32>,
+    transmission: Option<f32>,
+    subsurface: Option<f32>,
+    /// Medium inside, by name.
+    medium: Option<String>,
+}
+
+impl MaterialDesc {
+    /// Dispersion given by `cauchy`, `sellmeier` or `dispersion`; an `abbe`
+    /// number needs the IOR and is applied by [`MaterialDesc::kind`].
+    fn dispersion(&self) -> Result<Dispersion> {
+        let given = [self.abbe.is_some(), self.cauchy.is_some(), self.sellmeier.is_some(), self.dispersion.is_some()];
+        if given.iter().filter(|&&g| g).count() > 1 {
+            bail!("give at most one of `abbe`, `cauchy`, `sellmeier` and `dispersion`");
+        }
+        Ok(match (self.cauchy, self.sellmeier, &self.dispersion) {
+            (Some([a, b]), ..) => Dispersion::Cauchy { a, b },
+            (_, Some([b1, b2, b3, c1, c2, c3]), _) => Dispersion::Sellmeier { b: [b1, b2, b3], c: [c1, c2, c3] },
+            (_, _, Some(name)) => Dispersion::glass(name).with_context(|| format!("unknown glass {name:?}"))?,
+            _ => Dispersion::None,
+        })
+    }
+
+    fn kind(&self) -> Result<MaterialKind> {
+        let is_principled = matches!(self.kind, MaterialKindDesc::Principled);
+        let roughness = unit("roughness", self.roughness, if is_principled { 0.5 } else { 0.0 })?;
+        let is_conductor = matches!(self.kind, MaterialKindDesc::Conductor);
+        if !is_conductor && (self.metal.is_some() || self.eta.is_some() || self.k.is_some()) {
+            bail!("`metal`, `eta` and `k` only apply to conductors");
+        }
+        let lobes = [
+            self.metallic,
+            self.specular,
+            self.specular_tint,
+            self.clearcoat,
+            self.clearcoat_gloss,
+            self.sheen,
+            self.sheen_tint,
+            self.transmission,
+            self.subsurface,
+        ];
+        if !is_principled && lobes.iter().any(Option::is_some) {
+            bail!("metallic, specular, clearcoat, sheen, transmission and subsurface only apply to principled materials");
+        }
+        let dispersion = self.dispersion()?;
+        let ior = match (self.ior, dispersion) {
+            (Some(_), Dispersion::Cauchy { .. } | Dispersion::Sellmeier { .. }) => {
+                bail!("`ior` follows from `cauchy`, `sellmeier` or `dispersion`; leave it out")
+            }
+            (Some(ior), _) => ior,
+            (None, d) => d.ior(LINE_D).unwrap_or(1.5),
+        };
+        if !(ior.is_finite() && ior > 0.0) {
+            bail!("IOR must be positive, got {ior}");
+        }
+        let dispersion = match self.abbe {
+            Some(abbe) if !(abbe.is_finite() && abbe > 0.0) => bail!("Abbe number must be positive, got {abbe}"),
+            Some(abbe) => Dispersion::from_abbe(ior, abbe),
+            None => dispersion,
+        };
+        if dispersion != Dispersion::None && !matches!(self.kind, MaterialKindDesc::Glass) {
+            bail!("dispersion only applies to glass");
+        }
+        Ok(match self.kind {
+            MaterialKindDesc::Diffuse => MaterialKind::Diffuse,
+            MaterialKindDesc::Mirror => MaterialKind::Mirror,
+            MaterialKindDesc::Interface => MaterialKind::Interface,
+            MaterialKindDesc::Glass => MaterialKind::Glass { ior, roughness, dispersion },
+            MaterialKindDesc::Conductor => {
+                let complex_ior = match (&self.metal, self.eta, self.k) {
+                    (None, None, None) => None,
+                    (Some(name), None, None) => {
+                        Some(ComplexIor::metal(name).with_context(|| format!("unknown metal {name:?}"))?)
+                    }
+                    (None, Some(eta), Some(k)) => Some(ComplexIor { eta: Vec3::from(eta), k: Vec3::from(k) }),
+                    _ => bail!("give either `metal` or both `eta` and `k`"),
+                };
+                MaterialKind::Conductor { roughness, complex_ior }
+            }
+            MaterialKindDesc::Principled => {
+                let d = Principled::default();
+                MaterialKind::Principled(Principled {
+                    metallic: unit("metallic", self.metallic, d.metallic)?,
+                    roughness,
+                    specular: unit("specular", self.specular, d.specular)?,
+                    specular_tint: unit("specular_tint", self.specular_tint, d.specular_tint)?,
+                    clearcoat: unit("clearcoat", self.clearcoat, d.clearcoat)?,
+                    clearcoat_gloss: unit("clearcoat_gloss", self.clearcoat_gloss, d.clearcoat_gloss)?,
+                    sheen: unit("sheen", self.sheen, d.sheen)?,
+                    sheen_tint: unit("sheen_tint", self.sheen_tint, d.sheen_tint)?,
+                    transmission: unit("transmission", self.transmission, d.transmission)?,
+                    subsurface: unit("subsurface", self.subsurface, d.subsurface)?,
+                    ior,
+                })
+            }
+        })
+    }
+}
+
+/// A parameter within [0, 1], or `default` if absent.
+fn unit(name: &str, value: Option<f32>, default: f32) -> Result<f32> {
+    let v = value.unwrap_or(default);
+    if !(0.0..=1.0).contains(&v) {
+        bail!("{name} must be within [0, 1], got {v}");
+    }
+    Ok(v)
+}
+
+/// Shared geometry, placed with `instance` objects.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDesc {
+    name: String,
+    path: String,
+    material: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDesc {
+    Sphere { center: [f32; 3], radius: f32, material: String },
+    Quad { corner: [f32; 3], u: [f32; 3], v: [f32; 3], material: String },
+    Disk { center: [f32; 3], normal: [f32; 3], radius: f32, material: String },
+    /// Axis-aligned box made of six outward-facing quads.
+    Box { min: [f32; 3], max: [f32; 3], material: String },
+    /// Wavefront OBJ; `material` overrides faces without an MTL material.
+    Mesh {
+        path: String,
+        material: Option<String>,
+        #[serde(default)]
+        translate: [f32; 3],
+        /// Euler angles in degrees, applied X then Y then Z.
+        #[serde(default)]
+        rotate: [f32; 3],
+        #[serde(default)]
+        scale: ScaleDesc,
+    },
+    /// glTF/GLB file; its nodes are placed relative to this transform.
+    Gltf {
+        path: String,
+        #[serde(default)]
+        translate: [f32; 3],
+        #[serde(default)]
+        rotate: [f32; 3],
+        #[serde(default)]
+        scale: ScaleDesc,
+    },
+    /// A copy of a mesh from `[[meshes]]`.
+    Instance {
+        mesh: String,
+        #[serde(default)]
+        translate: [f32; 3],
+        #[serde(default)]
+        rotate: [f32; 3],
+        #[serde(default)]
+        scale: ScaleDesc,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScaleDesc {
+    Uniform(f32),
+    Axes([f32; 3]),
+}
+
+impl Default for ScaleDesc {
+    fn default() -> Self {
+        Self::Uniform(1.0)
+    }
+}
+
+fn transform_matrix(translate: [f32; 3], rotate: [f32; 3], scale: &ScaleDesc) -> Mat4 {
+    let scale = match *scale {
+        ScaleDesc::Uniform(s) => Vec3::splat(s),
+        ScaleDesc::Axes(s) => Vec3::from(s),
+    };
+    let [rx, ry, rz] = rotate.map(f32::to_radians);
+    Mat4::from_scale_rotation_translation(
+        scale,
+        Quat::from_euler(EulerRot::ZYX, rz, ry, rx),
+        Vec3::from(translate),
+    )
+}
+
+/// Angles are in degrees; every light also takes `color` and `intensity`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LightDesc {
+    Point {
+        position: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        intensity: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        #[serde(default)]
+        inner_angle: f32,
+        #[serde(default = "default_outer_angle")]
+        outer_angle: f32,
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        intensity: f32,
+    },
+    Directional {
+        direction: [f32; 3],
+        #[serde(default)]
+        angular_radius: f32,
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        intensity: f32,
+    },
+    #[serde(alias = "quad")]
+    Rect {
+        corner: [f32; 3],
+        u: [f32; 3],
+        v: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        intensity: f32,
+    },
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        intensity: f32,
+    },
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        intensity: f32,
+    },
+}
+
+fn default_light_color() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn default_light_intensity() -> f32 {
+    1.0
+}
+
+fn default_outer_angle() -> f32 {
+    45.0
+}
+
+impl LightDesc {
+    fn light(&self) -> Result<Light> {
+        let (LightDesc::Point { color, intensity, .. }
+        | LightDesc::Spot { color, intensity, .. }
+        | LightDesc::Directional { color, intensity, .. }
+        | LightDesc::Rect { color, intensity, .. }
+        | LightDesc::Disk { color, intensity, .. }
+        | LightDesc::Sphere { color, intensity, .. }) = *self;
+        if !std::iter::once(intensity).chain(color).all(|x| x.is_finite() && x >= 0.0) {
+            bail!("light color and intensity must not be negative");
+        }
+        let nonzero = |name: &str, v: [f32; 3]| {
+            let v = Vec3::from(v);
+            if v.length_squared() == 0.0 {
+                bail!("light {name} must not be zero");
+            }
+            Ok(v.normalize())
+        };
+        let positive = |radius: f32| {
+            if !(radius.is_finite() && radius > 0.0) {
+                bail!("light radius must be positive");
+            }
+            Ok(radius)
+        };
+        let kind = match *self {
+            LightDesc::Point { position, .. } => LightKind::Point { position: Vec3::from(position) },
+            LightDesc::Spot { position, direction, inner_angle, outer_angle, .. } => {
+                if !(0.0 <= inner_angle && inner_angle <= outer_angle && outer_angle <= 180.0) {
+                    bail!("spot angles must satisfy 0 <= inner_angle <= outer_angle <= 180");
+                }
+                LightKind::Spot {
+                    position: Vec3::from(position),
+                    direction: nonzero("direction", direction)?,
+                    inner_angle: inner_angle.to_radians(),
+                    outer_angle: outer_angle.to_radians(),
+                }
+            }
+            LightDesc::Directional { direction, angular_radius, .. } => {
+                if !(0.0..90.0).contains(&angular_radius) {
+                    bail!("directional angular_radius must be in [0, 90) degrees");
+                }
+                LightKind::Directional {
+                    direction: nonzero("direction", direction)?,
+                    angular_radius: angular_radius.to_radians(),
+                }
+            }
+            LightDesc::Rect { corner, u, v, .. } => {
+                let (u, v) = (Vec3::from(u), Vec3::from(v));
+                if u.cross(v).length_squared() == 0.0 {
+                    bail!("light u and v must span an area");
+                }
+                LightKind::Rect { corner: Vec3::from(corner), u, v }
+            }
+            LightDesc::Disk { center, normal, radius, .. } => LightKind::Disk {
+                center: Vec3::from(center),
+                normal: nonzero("normal", normal)?,
+                radius: positive(radius)?,
+            },
+            LightDesc::Sphere { center, radius, .. } => {
+                LightKind::Sphere { center: Vec3::from(center), radius: positive(radius)? }
+            }
+        };
+        Ok(Light::new(kind, Vec3::from(color), intensity))
+    }
+}
+
+impl ObjectDesc {
+    /// Rejects analytic shapes that would be inside out or have no area.
+    fn validate(&self) -> Result<()> {
+        match *self {
+            ObjectDesc::Sphere { radius, .. } | ObjectDesc::Disk { radius, .. }
+                if !(radius.is_finite() && radius > 0.0) =>
+            {
+                bail!("radius must be positive")
+            }
+            ObjectDesc::Disk { normal, .. } if Vec3::from(normal).length_squared() == 0.0 => {
+                bail!("disk normal must not be zero")
+            }
+            ObjectDesc::Quad { u, v, .. } if Vec3::from(u).cross(Vec3::from(v)).length_squared() == 0.0 => {
+                bail!("quad u and v must span an area")
+            }
+            ObjectDesc::Box { min, max, .. } if !Vec3::from(max).cmpgt(Vec3::from(min)).all() => {
+                bail!("box max must exceed min on every axis")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SceneDesc {
+    fn build(self, base_dir: &Path) -> Result<Scene> {
+        let mut scene = Scene {
+            camera: self.camera.as_ref().map(CameraDesc::camera).transpose().context("invalid camera")?,
+            ..Scene::default()
+        };
+        if let Some(path) = self.camera.as_ref().and_then(|c| c.aperture_mask.as_ref()) {
+            let mask = HdrImage::load(base_dir.join(path)).context("failed to read aperture mask")?;
+            if mask.pixels.iter().all(|&p| environment::luminance(p) <= 0.0) {
+                bail!("aperture mask {path:?} is black");
+            }
+            scene.aperture_mask = Some(mask);
+        }
+
+        if self.media.len() > MAX_MEDIA {
+            bail!("at most {MAX_MEDIA} media are supported");
+        }
+        let mut media_by_name = HashMap::new();
+        for m in &self.media {
+            let mut medium = m.medium().with_context(|| format!("medium {:?}", m.name))?;
+            if let Some(d) = &m.density {
+                let grid = d.grid(base_dir).with_context(|| format!("medium {:?}", m.name))?;
+                medium.density = Some(scene.add_grid(grid));
+            }
+            let id = scene.add_medium(medium);
+            if media_by_name.insert(m.name.as_str(), id).is_some() {
+                bail!("duplicate medium {:?}", m.name);
+            }
+        }
+        let medium = |name: &Option<String>| -> Result<Option<u32>> {
+            name.as_deref()
+                .map(|n| media_by_name.get(n).copied().with_context(|| format!("unknown medium {n:?}")))
+                .transpose()
+        };
+        scene.atmosphere = medium(&self.atmosphere)?;
+
+        let mut by_name = HashMap::new();
+        for m in &self.materials {
+            let kind = m.kind().with_context(|| format!("material {:?}", m.name))?;
+            let default_albedo = if kind == MaterialKind::Diffuse { 0.8 } else { 1.0 };
+            let id = scene.add_material(Material {
+                albedo: m.albedo.map_or(Vec3::splat(default_albedo), Vec3::from),
+                emission: Vec3::from(m.emission),
+                kind,
+                textures: MaterialTextures::default(),
+                medium: medium(&m.medium).with_context(|| format!("material {:?}", m.name))?,
+            });
+            if by_name.insert(m.name.as_str(), id).is_some() {
+                bail!("duplicate material {:?}", m.name);
+            }
+        }
+        let lookup = |name: &str| {
+            by_name.get(name).copied().with_context(|| format!("unknown material {name:?}"))
+        };
+
+        let mut meshes_by_name = HashMap::new();
+        for m in &self.meshes {
+            let default_material = match &m.material {
+                Some(name) => lookup(name)?,
+                None => scene.add_material(Material::diffuse(Vec3::splat(0.8))),
+            };
+            let mesh = mesh::load_obj(&base_dir.join(&m.path), &mut scene, default_material)?;
+            let id = scene.add_mesh(mesh);
+            if meshes_by_name.insert(m.name.as_str(), id).is_some() {
+                bail!("duplicate mesh {:?}", m.name);
+            }
+        }
+
+        for (i, o) in self.objects.iter().enumerate() {
+            o.validate().with_context(|| format!("object {i}"))?;
+            match o {
+                ObjectDesc::Sphere { center, radius, material } => scene.primitives.push(Primitive {
+                    shape: Shape::Sphere { center: Vec3::from(*center), radius: *radius },
+                    material: lookup(material)?,
+                }),
+                ObjectDesc::Quad { corner, u, v, material } => scene.primitives.push(Primitive {
+                    shape: Shape::Quad { corner: Vec3::from(*corner), u: Vec3::from(*u), v: Vec3::from(*v) },
+                    material: lookup(material)?,
+                }),
+                ObjectDesc::Disk { center, normal, radius, material } => scene.primitives.push(Primitive {
+                    shape: Shape::Disk { center: Vec3::from(*center), normal: Vec3::from(*normal), radius: *radius },
+                    material: lookup(material)?,
+                }),
+                ObjectDesc::Box { min, max, material } => {
+                    let material = lookup(material)?;
+                    for shape in box_quads(Vec3::from(*min), Vec3::from(*max)) {
+                        scene.primitives.push(Primitive { shape, material });
+                    }
+                }
+                ObjectDesc::Mesh { path, material, translate, rotate, scale } => {
+                    let default_material = match material {
+                        Some(name) => lookup(name)?,
+                        None => scene.add_material(Material::diffuse(Vec3::splat(0.8))),
+                    };
+                    let mesh = mesh::load_obj(&base_dir.join(path), &mut scene, default_material)?;
+                    let id = scene.add_mesh(mesh);
+                    scene.add_instance(id, transform_matrix(*translate, *rotate, scale));
+                }
+                ObjectDesc::Gltf { path, translate, rotate, scale } => {
+                    let transform = transform_matrix(*translate, *rotate, scale);
+                    gltf_import::load_gltf(&base_dir.join(path), &mut scene, transform)?;
+                }
+                ObjectDesc::Instance { mesh, translate, rotate, scale } => {
+                    let id = meshes_by_name
+                        .get(mesh.as_str())
+                        .copied()
+                        .with_context(|| format!("unknown mesh {mesh:?}"))?;
+                    scene.add_instance(id, transform_matrix(*translate, *rotate, scale));
+                }
+            }
+        }
+
+        for (i, l) in self.lights.iter().enumerate() {
+            scene.add_light(l.light().with_context(|| format!("light {i}"))?);
+        }
+
+        if let Some(env) = &self.environment {
+            if !(env.intensity.is_finite() && env.intensity >= 0.0) {
+                bail!("environment intensity must not be negative");
+            }
+            scene.environment = Some(Environment {
+                rotation: env.rotation.to_radians(),
+                intensity: env.intensity,
+                ..Environment::load(base_dir.join(&env.path))?
+            });
+        }
+        if let Some(desc) = &self.sky {
+            if self.environment.is_some() {
+                bail!("a scene can have an environment or a sky, not both");
+            }
+            scene.set_sky(&desc.sky().context("invalid sky")?);
+        }
+
+        Ok(scene)
+    }
+}
+
+fn box_quads(min: Vec3, max: Vec3) -> [Shape; 6] {
+    let d = max - min;
+    let (dx, dy, dz) = (Vec3::new(d.x, 0.0, 0.0), Vec3::new(0.0, d.y, 0.0), Vec3::new(0.0, 0.0, d.z));
+    [
+        Shape::Quad { corner: min, u: dz, v: dy }, // -x
+        Shape::Quad { corner: min + dx, u: dy, v: dz }, // +x
+        Shape::Quad { corner: min, u: dx, v: dz }, // -y
+        Shape::Quad { corner: min + dy, u: dz, v: dx }, // +y
+        Shape::Quad { corner: min, u: dy, v: dx }, // -z
+        Shape::Quad { corner: min + dz, u: dx, v: dy }, // +z
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: &str = "[[materials]]\nname = \"white\"\n";
+
+    fn parse(src: &str) -> Result<Scene> {
+        Scene::parse(src, Path::new("."))
+    }
+
+    /// The error chain of a scene that must not load.
+    fn error(src: &str) -> String {
+        match parse(src) {
+            Ok(_) => panic!("accepted:\n{src}"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    fn assert_rejects(src: &str, message: &str) {
+        let e = error(src);
+        assert!(e.contains(message), "expected {message:?}, got {e:?}");
+    }
+
+    fn material(fields: &str) -> Result<MaterialKind> {
+        let scene = parse(&format!("[[materials]]\nname = \"m\"\n{fields}"))?;
+        Ok(scene.materials[0].kind)
+    }
+
+    fn camera(fields: &str) -> Result<Camera> {
+        let scene = parse(&format!("[camera]\nposition = [0.0, 1.0, 4.0]\nlook_at = [0.0, 1.0, 0.0]\n{fields}"))?;
+        Ok(scene.camera.unwrap())
+    }
+
+    #[test]
+    fn parses_objects_and_lights() {
+        let scene = parse(&format!(
+            "{WHITE}
+            [[objects]]
+            type = \"sphere\"
+            center = [0.0, 1.0, 0.0]
+            radius = 1.0
+            material = \"white\"
+
+            [[objects]]
+            type = \"box\"
+            min = [0.0, 0.0, 0.0]
+            max = [1.0, 1.0, 1.0]
+            material = \"white\"
+
+            [[lights]]
+            type = \"point\"
+            position = [0.0, 3.0, 0.0]
+            intensity = 5.0"
+        ))
+        .unwrap();
+        assert_eq!(scene.materials.len(), 1);
+        assert_eq!(scene.primitives.len(), 7);
+        assert!(scene.primitives.iter().all(|p| p.material == 0));
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.lights[0].intensity, 5.0);
+        assert_eq!(scene.lights[0].color, Vec3::ONE);
+        assert!(scene.camera.is_none());
+    }
+
+    #[test]
+    fn empty_scene_is_valid() {
+        let scene = parse("").unwrap();
+        assert!(scene.primitives.is_empty() && scene.materials.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_and_duplicate_names() {
+        assert_rejects(
+            "[[objects]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"nope\"",
+            "unknown material \"nope\"",
+        );
+        assert_rejects(&format!("{WHITE}{WHITE}"), "duplicate material \"white\"");
+        assert_rejects("atmosphere = \"fog\"", "unknown medium \"fog\"");
+        assert_rejects("[[media]]\nname = \"fog\"\n[[media]]\nname = \"fog\"", "duplicate medium \"fog\"");
+        assert_rejects("[[objects]]\ntype = \"instance\"\nmesh = \"teapot\"", "unknown mesh \"teapot\"");
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert_rejects("[[materials]]\nname = \"m\"\ncolour = [1.0, 0.0, 0.0]", "unknown field `colour`");
+        assert_rejects("[camera]\nposition = [0.0, 0.0, 0.0]\nlook_at = [0.0, 0.0, -1.0]\nzoom = 2.0", "unknown field `zoom`");
+        assert_rejects("[[objects]]\ntype = \"cone\"", "unknown variant `cone`");
+        assert_rejects("[[lights]]\ntype = \"point\"\nposition = [0.0, 0.0, 0.0]\nradius = 1.0", "unknown field `radius`");
+    }
+
+    #[test]
+    fn glass_dispersion_and_ior() {
+        assert!(matches!(material("kind = \"glass\"").unwrap(), MaterialKind::Glass { ior: 1.5, dispersion: Dispersion::None, .. }));
+        let MaterialKind::Glass { ior, dispersion, .. } = material("kind = \"glass\"\ndispersion = \"bk7\"").unwrap() else {
+            panic!("not glass")
+        };
+        assert!((ior - 1.5168).abs() < 1e-3, "{ior}");
+        assert_eq!(dispersion, Dispersion::glass("bk7").unwrap());
+        let MaterialKind::Glass { ior, dispersion, .. } = material("kind = \"glass\"\nior = 1.6\nabbe = 40.0").unwrap() else {
+            panic!("not glass")
+        };
+        assert_eq!(ior, 1.6);
+        assert_eq!(dispersion, Dispersion::from_abbe(1.6, 40.0));
+
+        let rejected = [
+            ("kind = \"glass\"\nabbe = 40.0\ncauchy = [1.5, 0.004]", "at most one of"),
+            ("kind = \"glass\"\nior = 1.5\nsellmeier = [1.0, 0.2, 1.0, 0.006, 0.02, 100.0]", "`ior` follows from"),
+            ("kind = \"glass\"\ndispersion = \"unobtainium\"", "unknown glass"),
+            ("kind = \"diffuse\"\nabbe = 40.0", "dispersion only applies to glass"),
+            ("kind = \"glass\"\nior = -1.0", "IOR must be positive"),
+        ];
+        for (fields, message) in rejected {
+            let e = format!("{:#}", material(fields).unwrap_err());
+            assert!(e.contains(message), "{fields:?}: expected {message:?}, got {e:?}");
+        }
+    }
+
+    #[test]
+    fn material_parameters_belong_to_their_kind() {
+        assert!(matches!(
+            material("kind = \"metal\"\nmetal = \"gold\"").unwrap(),
+            MaterialKind::Conductor { complex_ior: Some(_), .. }
+        ));
+        let MaterialKind::Principled(p) = material("kind = \"principled\"\nmetallic = 1.0").unwrap() else {
+            panic!("not principled")
+        };
+        assert_eq!((p.metallic, p.roughness), (1.0, 0.5));
+
+        let rejected = [
+            ("kind = \"glass\"\nmetal = \"gold\"", "only apply to conductors"),
+            ("kind = \"metal\"\nmetal = \"gold\"\neta = [0.2, 0.4, 1.4]", "give either `metal` or both"),
+            ("kind = \"diffuse\"\nclearcoat = 0.5", "only apply to principled"),
+            ("kind = \"principled\"\nsheen = 2.0", "sheen must be within [0, 1]"),
+            ("kind = \"glass\"\nroughness = -0.1", "roughness must be within [0, 1]"),
+        ];
+        for (fields, message) in rejected {
+            let e = format!("{:#}", material(fields).unwrap_err());
+            assert!(e.contains(message), "{fields:?}: expected {message:?}, got {e:?}");
+        }
+    }
+
+    #[test]
+    fn sky_is_placed_by_angles_or_by_date() {
+        let scene = parse("[sky]\nsun_elevation = 30.0\nsun_azimuth = 180.0").unwrap();
+        assert!(scene.environment.is_some());
+        let [sun] = scene.lights[..] else { panic!("{} lights", scene.lights.len()) };
+        let LightKind::Directional { direction, .. } = sun.kind else { panic!("sun is not directional") };
+        // the light travels down and north, away from the southern sun
+        assert!(direction.y < 0.0 && direction.z < 0.0, "{direction}");
+
+        let scene = parse("[sky]\ndate = \"2023-06-21\"\ntime = \"12:00\"\nlatitude = 51.5\nlongitude = 0.0").unwrap();
+        assert_eq!(scene.lights.len(), 1);
+        // no sun light at night
+        let scene = parse("[sky]\ndate = \"2023-06-21\"\ntime = \"00:00\"\nlatitude = 51.5\nlongitude = 0.0").unwrap();
+        assert!(scene.lights.is_empty());
+
+        assert_rejects("[sky]\nsun_elevation = 30.0", "place the sun with");
+        assert_rejects("[sky]\nsun_elevation = 30.0\nsun_azimuth = 0.0\ndate = \"2023-06-21\"", "place the sun with");
+        assert_rejects("[sky]\ndate = \"2023-02-30\"\ntime = \"12:00\"\nlatitude = 0.0\nlongitude = 0.0", "invalid date");
+        assert_rejects("[sky]\ndate = \"2023-02-03\"\ntime = \"25:00\"\nlatitude = 0.0\nlongitude = 0.0", "invalid time");
+        assert_rejects("[sky]\nsun_elevation = 30.0\nsun_azimuth = 0.0\nturbidity = 1.0", "turbidity");
+    }
+
+    #[test]
+    fn camera_projection_and_lens() {
+        let c = camera("fov = 60.0").unwrap();
+        assert_eq!(c.fov_y_radians, 60f32.to_radians());
+        assert_eq!(c.focus_distance, 4.0);
+        let c = camera("projection = \"fisheye\"\nfov = 270.0").unwrap();
+        assert_eq!(c.projection, Projection::Fisheye);
+        let c = camera("projection = \"orthographic\"\northo_height = 3.0").unwrap();
+        assert_eq!(c.ortho_height, 3.0);
+        let c = camera("[camera.physical]\nfocal_length = 35.0").unwrap();
+        assert_eq!(c.physical.unwrap().focal_length, 35.0);
+
+        let rejected = [
+            ("fov = 270.0", "fov must be in"),
+            ("fov = 0.0", "fov must be in"),
+            ("ortho_height = 3.0", "ortho_height needs projection"),
+            ("projection = \"orthographic\"\northo_height = 0.0", "ortho_height must be positive"),
+            ("fov = 40.0\n[camera.physical]\nf_stop = 2.8", "follow from the physical camera"),
+            ("[camera.physical]\niso = 0.0", "must be positive"),
+            ("focus_distance = -1.0", "focus_distance must be positive"),
+            ("aperture_radius = -0.1", "aperture_radius must not be negative"),
+        ];
+        for (fields, message) in rejected {
+            let e = format!("{:#}", camera(fields).unwrap_err());
+            assert!(e.contains(message), "{fields:?}: expected {message:?}, got {e:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_degenerate_shapes() {
+        let object = |fields: &str| format!("{WHITE}[[objects]]\nmaterial = \"white\"\n{fields}");
+        let light = |fields: &str| format!("[[lights]]\n{fields}");
+        let rejected = [
+            (object("type = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = -1.0"), "object 0: radius must be positive"),
+            (object("type = \"disk\"\ncenter = [0.0, 0.0, 0.0]\nnormal = [0.0, 1.0, 0.0]\nradius = 0.0"), "radius must be positive"),
+            (object("type = \"disk\"\ncenter = [0.0, 0.0, 0.0]\nnormal = [0.0, 0.0, 0.0]\nradius = 1.0"), "normal must not be zero"),
+            (object("type = \"quad\"\ncorner = [0.0, 0.0, 0.0]\nu = [1.0, 0.0, 0.0]\nv = [2.0, 0.0, 0.0]"), "u and v must span"),
+            (object("type = \"box\"\nmin = [0.0, 0.0, 0.0]\nmax = [1.0, 0.0, 1.0]"), "max must exceed min"),
+            (object("type = \"box\"\nmin = [1.0, 1.0, 1.0]\nmax = [0.0, 0.0, 0.0]"), "max must exceed min"),
+            (light("type = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = -0.5"), "light 0: light radius must be positive"),
+            (light("type = \"rect\"\ncorner = [0.0, 0.0, 0.0]\nu = [1.0, 0.0, 0.0]\nv = [0.0, 0.0, 0.0]"), "u and v must span"),
+        ];
+        for (src, message) in rejected {
+            assert_rejects(&src, message);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// Where the random numbers of each path come from (`SAMPLER_*` in
+/// compute.wgsl).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Independent PCG hashes: white noise, converging at the plain Monte
+    /// Carlo rate.
+    Independent,
+    /// Owen-scrambled Sobol points, stratified across the samples of a
+    /// pixel; converges faster for the same sample count.
+    #[default]
+    Sobol,
+    /// A rank-1 lattice shifted per pixel by blue noise: at a few samples
+    /// the error is spread evenly over the screen instead of clumping,
+    /// which suits interactive previews.
+    BlueNoise,
+}
+
+impl FromStr for SamplerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "independent" => Ok(Self::Independent),
+            "sobol" => Ok(Self::Sobol),
+            "blue-noise" => Ok(Self::BlueNoise),
+            _ => bail!("unknown sampler {s:?} (expected independent, sobol or blue-noise)"),
+        }
+    }
+}
+
+/// Per-frame tracing parameters uploaded alongside the camera.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    /// Samples per pixel traced in each frame (each `render` call).
+    pub spp: u32,
+    /// Maximum number of bounces after the primary hit.
+    pub max_bounce: u32,
+    /// Bounces every path takes before Russian roulette may end it; paths
+    /// carrying little light are then cut short without bias.
+    pub rr_depth: u32,
+    /// Caps each component of the path throughput. This trades fireflies
+    /// for bias (darker bright interreflections); `None` is unbiased.
+    pub clamp: Option<f32>,
+    pub sampler: SamplerKind,
+    /// Traces a few sampled wavelengths per path instead of RGB and
+    /// accumulates CIE XYZ, so dispersive glass splits light into colours.
+    pub spectral: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self { spp: 16, max_bounce: 16, rr_depth: 3, clamp: None, sampler: SamplerKind::default(), spectral: false }
+    }
+}
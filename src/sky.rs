@@ -0,0 +1,277 @@
+use glam::Vec3;
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use crate::environment::Environment;
+use crate::hdr_image::HdrImage;
+use crate::scene::{Light, LightKind};
+
+/// Angular radius of the sun seen from the earth (0.267 degrees).
+pub const SUN_ANGULAR_RADIUS: f32 = 4.65e-3;
+/// Illuminance of the sun above the atmosphere, in klx.
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+/// Size of the baked sky; it is smooth, the sun is a separate light.
+const SKY_WIDTH: u32 = 512;
+const SKY_HEIGHT: u32 = 256;
+
+/// Clear sky after Preetham, Shirley and Smits, "A Practical Analytic Model
+/// for Daylight" (1999), with the sun as a directional light of the sun's
+/// angular size. The sky is baked into an [`Environment`]; below the horizon
+/// it shows a diffuse ground lit by sun and sky.
+///
+/// Radiance is in kcd/m² times `intensity`. The model does not cover
+/// twilight: the sky fades out as the sun sinks 6 degrees below the horizon.
+#[derive(Clone, Copy, Debug)]
+pub struct Sky {
+    /// Unit vector towards the sun, see [`Sky::sun_direction`].
+    pub sun_direction: Vec3,
+    /// Haze: 2 is very clear, 3 a clear day, 6 and more hazy (valid up to ~10).
+    pub turbidity: f32,
+    /// Scene radiance per kcd/m²; the default puts a sunlit white surface
+    /// around 1.
+    pub intensity: f32,
+    pub ground_albedo: f32,
+}
+
+impl Sky {
+    pub fn new(sun_direction: Vec3) -> Self {
+        Self { sun_direction: sun_direction.normalize(), turbidity: 3.0, intensity: 0.05, ground_albedo: 0.2 }
+    }
+
+    /// Direction towards a sun at `elevation` above the horizon and
+    /// `azimuth` clockwise from north, both in radians. North is -Z and
+    /// east +X.
+    pub fn sun_direction(elevation: f32, azimuth: f32) -> Vec3 {
+        Vec3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos())
+    }
+
+    /// The sun as seen through the atmosphere, `None` once it has set.
+    pub fn sun(&self) -> Option<Light> {
+        let cos_theta = self.sun_direction.y;
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let color = self.sun_transmittance();
+        let kind = LightKind::Directional { direction: -self.sun_direction, angular_radius: SUN_ANGULAR_RADIUS };
+        Some(Light::new(kind, color, SOLAR_ILLUMINANCE * self.intensity))
+    }
+
+    /// Bakes the sky dome into an equirectangular environment map.
+    pub fn environment(&self) -> Environment {
+        let (w, h) = (SKY_WIDTH, SKY_HEIGHT);
+        let perez = PerezSky::new(self.turbidity, self.sun_direction.y.clamp(0.0, 1.0).acos());
+        let sun = self.sun_direction.with_y(self.sun_direction.y.max(0.0)).normalize_or(Vec3::X);
+        // fade out from sunset to civil dusk
+        let fade = (1.0 + self.sun_direction.y.asin().to_degrees() / 6.0).clamp(0.0, 1.0);
+
+        let mut pixels = vec![[0.0; 4]; (w * h) as usize];
+        let mut sky_irradiance = Vec3::ZERO;
+        for y in 0..h / 2 {
+            let theta = PI * (y as f32 + 0.5) / h as f32;
+            let d_omega = (2.0 * PI / w as f32) * (PI / h as f32) * theta.sin();
+            for x in 0..w {
+                let phi = 2.0 * PI * ((x as f32 + 0.5) / w as f32 - 0.5);
+                let d = Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos());
+                let rgb = perez.radiance(theta, d.dot(sun).clamp(-1.0, 1.0).acos()) * (self.intensity * fade);
+                sky_irradiance += rgb * theta.cos() * d_omega;
+                pixels[(y * w + x) as usize] = rgb.extend(1.0).to_array();
+            }
+        }
+
+        let sun_irradiance = self.sun().map_or(Vec3::ZERO, |s| s.color * s.intensity * self.sun_direction.y);
+        let ground = (sky_irradiance + sun_irradiance) * (self.ground_albedo / PI);
+        for p in &mut pixels[(w * (h / 2)) as usize..] {
+            *p = ground.extend(1.0).to_array();
+        }
+        Environment::new(HdrImage { width: w, height: h, pixels })
+    }
+
+    /// Rayleigh and aerosol extinction along the path of sunlight, from
+    /// Preetham's appendix at red, green and blue wavelengths (µm); ozone
+    /// and water vapour are left out.
+    fn sun_transmittance(&self) -> Vec3 {
+        let theta = self.sun_direction.y.clamp(0.0, 1.0).acos();
+        let air_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let tau = |lambda: f32| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+            rayleigh * aerosol
+        };
+        Vec3::new(tau(0.65), tau(0.55), tau(0.45))
+    }
+}
+
+/// Perez sky distribution for luminance and chromaticity, normalised to
+/// the zenith values.
+struct PerezSky {
+    coeffs: [[f32; 5]; 3],
+    /// Zenith Y (kcd/m²), x, y divided by the distribution at the zenith.
+    zenith: [f32; 3],
+}
+
+impl PerezSky {
+    fn new(t: f32, theta_s: f32) -> Self {
+        let coeffs = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let y_z = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let chroma = |m: [[f32; 4]; 3]| {
+            let th = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let row = |r: [f32; 4]| r.iter().zip(th).map(|(a, b)| a * b).sum::<f32>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let x_z = chroma([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let yy_z = chroma([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+        let mut zenith = [y_z, x_z, yy_z];
+        for (z, c) in zenith.iter_mut().zip(&coeffs) {
+            *z /= perez(c, 0.0, theta_s);
+        }
+        Self { coeffs, zenith }
+    }
+
+    /// Linear sRGB radiance at zenith angle `theta`, angle `gamma` from the sun.
+    fn radiance(&self, theta: f32, gamma: f32) -> Vec3 {
+        // the distribution diverges at the horizon
+        let theta = theta.min(FRAC_PI_2 - 1e-3);
+        let [lum, x, y] = [0, 1, 2].map(|i| self.zenith[i] * perez(&self.coeffs[i], theta, gamma));
+        if y <= 0.0 {
+            return Vec3::ZERO;
+        }
+        let xyz = Vec3::new(x * lum / y, lum, (1.0 - x - y) * lum / y);
+        let rgb = Vec3::new(
+            3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+            -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+            0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+        );
+        rgb.max(Vec3::ZERO)
+    }
+}
+
+fn perez([a, b, c, d, e]: &[f32; 5], theta: f32, gamma: f32) -> f32 {
+    (1.0 + a * (b / theta.cos()).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+/// Sun elevation and azimuth (radians, azimuth clockwise from north) at
+/// `latitude` and `longitude` (degrees, east positive) on day `day_of_year`
+/// (1 = January 1st) at `hours` local time, `utc_offset` hours ahead of UTC.
+/// Uses the NOAA fractional-year approximation, good to a fraction of a
+/// degree.
+pub fn solar_position(day_of_year: u32, hours: f32, utc_offset: f32, latitude: f32, longitude: f32) -> (f32, f32) {
+    let g = 2.0 * PI / 365.0 * (day_of_year as f32 - 1.0 + (hours - utc_offset - 12.0) / 24.0);
+    let eq_time = 229.18
+        * (0.000075 + 0.001868 * g.cos() - 0.032077 * g.sin() - 0.014615 * (2.0 * g).cos() - 0.040849 * (2.0 * g).sin());
+    let decl = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin() - 0.006758 * (2.0 * g).cos()
+        + 0.000907 * (2.0 * g).sin()
+        - 0.002697 * (3.0 * g).cos()
+        + 0.00148 * (3.0 * g).sin();
+    // true solar time in minutes, then the hour angle
+    let solar_minutes = hours * 60.0 + eq_time + 4.0 * longitude - 60.0 * utc_offset;
+    let ha = (solar_minutes / 4.0 - 180.0).to_radians();
+    let lat = latitude.to_radians();
+
+    let sin_elevation = lat.sin() * decl.sin() + lat.cos() * decl.cos() * ha.cos();
+    let elevation = sin_elevation.clamp(-1.0, 1.0).asin();
+    let azimuth = ha.sin().atan2(ha.cos() * lat.sin() - decl.tan() * lat.cos()) + PI;
+    (elevation, azimuth)
+}
+
+/// Day of the year (1 for January 1st) of a Gregorian date, `None` if the
+/// date does not exist.
+pub fn day_of_year(year: i32, month: u32, day: u32) -> Option<u32> {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let lengths = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    let len = *lengths.get(month.checked_sub(1)? as usize)?;
+    (1..=len).contains(&day).then(|| lengths[..month as usize - 1].iter().sum::<u32>() + day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREENWICH: (f32, f32) = (51.4769, 0.0);
+
+    fn position_degrees(day: u32, hours: f32, utc_offset: f32, (lat, lon): (f32, f32)) -> (f32, f32) {
+        let (elevation, azimuth) = solar_position(day, hours, utc_offset, lat, lon);
+        (elevation.to_degrees(), azimuth.to_degrees())
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "{actual} is not within {tolerance} of {expected}");
+    }
+
+    #[test]
+    fn solstice_noon_at_greenwich() {
+        // at noon the sun stands 90° - latitude ± 23.44° high; the equation
+        // of time (-1.7 min in June, +2.0 min in December) puts it just
+        // east, then just west of due south
+        let (elevation, azimuth) = position_degrees(172, 12.0, 0.0, GREENWICH);
+        assert_near(elevation, 61.96, 0.1);
+        assert_near(azimuth, 179.3, 0.3);
+        let (elevation, azimuth) = position_degrees(355, 12.0, 0.0, GREENWICH);
+        assert_near(elevation, 15.08, 0.1);
+        assert_near(azimuth, 180.5, 0.3);
+    }
+
+    #[test]
+    fn southern_summer_sun_is_high_and_north() {
+        // 13:00 daylight time in Sydney is 12:07 solar time, the sun 10.5°
+        // from the zenith
+        let (elevation, azimuth) = position_degrees(355, 13.0, 11.0, (-33.8688, 151.2093));
+        assert_near(elevation, 79.45, 0.1);
+        assert!(azimuth > 270.0, "{azimuth}");
+    }
+
+    #[test]
+    fn time_zones_and_longitude_shift_solar_noon() {
+        // in early November New York's sun crosses the meridian at 11:40
+        // EST, 16 minutes early by the equation of time and 4 by longitude
+        let new_york = (40.7128, -74.006);
+        let (_, before) = position_degrees(309, 11.6, -5.0, new_york);
+        let (_, after) = position_degrees(309, 11.75, -5.0, new_york);
+        assert!(before < 180.0 && after > 180.0, "{before} {after}");
+        // the sun rises in the east and sets in the west
+        let (elevation, _) = position_degrees(80, 0.0, 0.0, GREENWICH);
+        assert!(elevation < -30.0, "{elevation}");
+        let (_, azimuth) = position_degrees(80, 7.0, 0.0, GREENWICH);
+        assert!((60.0..120.0).contains(&azimuth), "{azimuth}");
+        let (_, azimuth) = position_degrees(80, 17.0, 0.0, GREENWICH);
+        assert!((240.0..300.0).contains(&azimuth), "{azimuth}");
+    }
+
+    #[test]
+    fn day_of_year_counts_from_january_first() {
+        assert_eq!(day_of_year(2023, 1, 1), Some(1));
+        assert_eq!(day_of_year(2023, 6, 21), Some(172));
+        assert_eq!(day_of_year(2023, 12, 31), Some(365));
+    }
+
+    #[test]
+    fn day_of_year_handles_leap_years() {
+        assert_eq!(day_of_year(2024, 2, 29), Some(60));
+        assert_eq!(day_of_year(2024, 3, 1), Some(61));
+        assert_eq!(day_of_year(2024, 12, 31), Some(366));
+        assert_eq!(day_of_year(2000, 2, 29), Some(60));
+        assert_eq!(day_of_year(2023, 3, 1), Some(60));
+    }
+
+    #[test]
+    fn day_of_year_rejects_invalid_dates() {
+        assert_eq!(day_of_year(2023, 2, 29), None);
+        assert_eq!(day_of_year(1900, 2, 29), None);
+        assert_eq!(day_of_year(2023, 4, 31), None);
+        assert_eq!(day_of_year(2023, 1, 0), None);
+        assert_eq!(day_of_year(2023, 0, 1), None);
+        assert_eq!(day_of_year(2023, 13, 1), None);
+    }
+}
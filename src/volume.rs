@@ -0,0 +1,250 @@
+use anyhow::{bail, Context, Result};
+use glam::Vec3;
+use std::path::Path;
+
+/// Value type of the voxels in a raw dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoxelFormat {
+    /// One byte per voxel, 0..=255 mapped to 0..=1.
+    U8,
+    /// Little-endian 32-bit floats.
+    F32,
+}
+
+/// Voxel grid of densities that scale a [`crate::Medium`]'s coefficients,
+/// filling the axis-aligned box from `min` to `max`. Densities are
+/// interpolated trilinearly between voxel centres and are zero outside the
+/// box.
+#[derive(Clone, Debug)]
+pub struct DensityGrid {
+    /// Voxels along x, y and z.
+    pub resolution: [u32; 3],
+    /// x varies fastest, then y, then z.
+    pub density: Vec<f32>,
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl DensityGrid {
+    /// Loads a headerless voxel dump of the given resolution, x varying
+    /// fastest, placed in the unit cube.
+    pub fn load_raw(path: impl AsRef<Path>, resolution: [u32; 3], format: VoxelFormat) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("failed to read density grid {}", path.display()))?;
+        let density = decode(&bytes, voxel_count(resolution)?, format).with_context(|| format!("invalid density grid {}", path.display()))?;
+        Self::new(resolution, density, Vec3::ZERO, Vec3::ONE)
+    }
+
+    /// Loads a single-channel Mitsuba `.vol` grid (float32 or uint8
+    /// voxels), placed by the bounding box in its header.
+    pub fn load_vol(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("failed to read density grid {}", path.display()))?;
+        Self::parse_vol(&bytes).with_context(|| format!("invalid density grid {}", path.display()))
+    }
+
+    fn parse_vol(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 48 || &bytes[..3] != b"VOL" || bytes[3] != 3 {
+            bail!("not a version 3 .vol file");
+        }
+        let word = |i: usize| <[u8; 4]>::try_from(&bytes[4 + 4 * i..8 + 4 * i]).expect("4 bytes");
+        let int = |i| i32::from_le_bytes(word(i));
+        let float = |i| f32::from_le_bytes(word(i));
+        let format = match int(0) {
+            1 => VoxelFormat::F32,
+            3 => VoxelFormat::U8,
+            e => bail!("unsupported voxel encoding {e} (expected 1 = float32 or 3 = uint8)"),
+        };
+        if int(4) != 1 {
+            bail!("expected 1 channel, got {}", int(4));
+        }
+        let resolution = [int(1), int(2), int(3)].map(|r| r.max(0) as u32);
+        let density = decode(&bytes[48..], voxel_count(resolution)?, format)?;
+        Self::new(resolution, density, Vec3::new(float(5), float(6), float(7)), Vec3::new(float(8), float(9), float(10)))
+    }
+
+    pub fn new(resolution: [u32; 3], density: Vec<f32>, min: Vec3, max: Vec3) -> Result<Self> {
+        if resolution.contains(&0) {
+            bail!("resolution must not be zero, got {resolution:?}");
+        }
+        if density.len() != voxel_count(resolution)? {
+            bail!("{} voxels do not match resolution {resolution:?}", density.len());
+        }
+        if !density.iter().all(|d| d.is_finite() && *d >= 0.0) {
+            bail!("densities must not be negative");
+        }
+        if !(min.is_finite() && max.is_finite() && min.cmplt(max).all()) {
+            bail!("bounds must satisfy min < max, got {min} and {max}");
+        }
+        Ok(Self { resolution, density, min, max })
+    }
+
+    /// Upper bounds of the interpolated density over `cells`³ equal cells
+    /// of the box, x varying fastest (`majorant_grids` in compute.wgsl).
+    pub(crate) fn majorants(&self, cells: u32) -> Vec<f32> {
+        let [rx, ry, rz] = self.resolution;
+        // voxels interpolated within the cell, with one to spare
+        let range = |c: u32, res: u32| {
+            let lo = (c as f32 / cells as f32 * res as f32 - 1.5).floor().max(0.0) as u32;
+            let hi = ((c + 1) as f32 / cells as f32 * res as f32 + 1.5).ceil() as u32;
+            lo.min(res - 1)..hi.clamp(lo + 1, res)
+        };
+        let mut out = Vec::with_capacity((cells * cells * cells) as usize);
+        for cz in 0..cells {
+            for cy in 0..cells {
+                for cx in 0..cells {
+                    let mut max = 0.0f32;
+                    for z in range(cz, rz) {
+                        for y in range(cy, ry) {
+                            let row = (z as usize * ry as usize + y as usize) * rx as usize;
+                            for x in range(cx, rx) {
+                                max = max.max(self.density[row + x as usize]);
+                            }
+                        }
+                    }
+                    out.push(max);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Number of voxels in a grid, failing where it would not fit in memory.
+fn voxel_count(resolution: [u32; 3]) -> Result<usize> {
+    resolution
+        .iter()
+        .try_fold(1usize, |n, &r| n.checked_mul(r as usize))
+        .with_context(|| format!("resolution {resolution:?} is too large"))
+}
+
+fn decode(bytes: &[u8], count: usize, format: VoxelFormat) -> Result<Vec<f32>> {
+    let size = match format {
+        VoxelFormat::U8 => 1,
+        VoxelFormat::F32 => 4,
+    };
+    let Some(expected) = count.checked_mul(size) else { bail!("{count} voxels are too many") };
+    if bytes.len() != expected {
+        bail!("expected {expected} bytes of voxels, got {}", bytes.len());
+    }
+    Ok(match format {
+        VoxelFormat::U8 => bytes.iter().map(|&b| b as f32 / 255.0).collect(),
+        VoxelFormat::F32 => bytes.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 3 `.vol` file with `encoding` and the voxel bytes `data`.
+    fn vol(encoding: i32, resolution: [i32; 3], min: [f32; 3], max: [f32; 3], data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VOL\x03".to_vec();
+        for i in [encoding, resolution[0], resolution[1], resolution[2], 1] {
+            bytes.extend(i.to_le_bytes());
+        }
+        for f in min.iter().chain(&max) {
+            bytes.extend(f.to_le_bytes());
+        }
+        bytes.extend(data);
+        bytes
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|f| f.to_le_bytes()).collect()
+    }
+
+    fn error(result: Result<DensityGrid>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    #[test]
+    fn parses_float_vol() {
+        let values = [0.0, 0.5, 1.0, 2.0, 0.25, 0.75];
+        let grid = DensityGrid::parse_vol(&vol(1, [3, 2, 1], [-1.0, 0.0, -1.0], [1.0, 2.0, 1.0], &floats(&values))).unwrap();
+        assert_eq!(grid.resolution, [3, 2, 1]);
+        assert_eq!(grid.density, values);
+        assert_eq!(grid.min, Vec3::new(-1.0, 0.0, -1.0));
+        assert_eq!(grid.max, Vec3::new(1.0, 2.0, 1.0));
+    }
+
+    #[test]
+    fn parses_byte_vol() {
+        let grid = DensityGrid::parse_vol(&vol(3, [2, 1, 1], [0.0; 3], [1.0; 3], &[0, 255])).unwrap();
+        assert_eq!(grid.density, [0.0, 1.0]);
+    }
+
+    #[test]
+    fn rejects_malformed_vol() {
+        let data = floats(&[1.0; 4]);
+        assert!(error(DensityGrid::parse_vol(b"VOL\x02")).contains("not a version 3"));
+        assert!(error(DensityGrid::parse_vol(&vol(1, [2, 2, 1], [0.0; 3], [1.0; 3], &data)[1..])).contains("not a version 3"));
+        assert!(error(DensityGrid::parse_vol(&vol(2, [2, 2, 1], [0.0; 3], [1.0; 3], &data))).contains("unsupported voxel encoding 2"));
+        assert!(error(DensityGrid::parse_vol(&vol(1, [2, 2, 2], [0.0; 3], [1.0; 3], &data))).contains("expected 32 bytes of voxels, got 16"));
+        assert!(error(DensityGrid::parse_vol(&vol(1, [2, 2, 1], [1.0; 3], [0.0; 3], &data))).contains("min < max"));
+        assert!(error(DensityGrid::parse_vol(&vol(1, [0, 2, 1], [0.0; 3], [1.0; 3], &[]))).contains("must not be zero"));
+        let negative = floats(&[1.0, -1.0, 1.0, 1.0]);
+        assert!(error(DensityGrid::parse_vol(&vol(1, [2, 2, 1], [0.0; 3], [1.0; 3], &negative))).contains("must not be negative"));
+
+        let mut channels = vol(1, [2, 2, 1], [0.0; 3], [1.0; 3], &floats(&[1.0; 12]));
+        channels[20..24].copy_from_slice(&3i32.to_le_bytes());
+        assert!(error(DensityGrid::parse_vol(&channels)).contains("expected 1 channel, got 3"));
+    }
+
+    #[test]
+    fn rejects_overflowing_resolution() {
+        let huge = 1 << 22;
+        assert!(error(DensityGrid::parse_vol(&vol(1, [huge; 3], [0.0; 3], [1.0; 3], &[]))).contains("too large"));
+        assert!(decode(&[], usize::MAX / 2, VoxelFormat::F32).is_err());
+    }
+
+    #[test]
+    fn decodes_raw_voxels() {
+        assert_eq!(decode(&[0, 51, 255], 3, VoxelFormat::U8).unwrap(), [0.0, 0.2, 1.0]);
+        assert_eq!(decode(&floats(&[1.5, 3.0]), 2, VoxelFormat::F32).unwrap(), [1.5, 3.0]);
+        assert!(decode(&[0; 7], 2, VoxelFormat::F32).is_err());
+    }
+
+    /// Density at `p` in the unit cube, interpolated like the shader does.
+    fn sample(grid: &DensityGrid, p: Vec3) -> f32 {
+        let [rx, ry, rz] = grid.resolution;
+        let axis = |t: f32, res: u32| {
+            let u = (t * res as f32 - 0.5).clamp(0.0, (res - 1) as f32);
+            let i = (u.floor() as u32).min(res - 1);
+            (i, (i + 1).min(res - 1), u - i as f32)
+        };
+        let (x0, x1, fx) = axis(p.x, rx);
+        let (y0, y1, fy) = axis(p.y, ry);
+        let (z0, z1, fz) = axis(p.z, rz);
+        let d = |x: u32, y: u32, z: u32| grid.density[((z * ry + y) * rx + x) as usize];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let plane = |z| lerp(lerp(d(x0, y0, z), d(x1, y0, z), fx), lerp(d(x0, y1, z), d(x1, y1, z), fx), fy);
+        lerp(plane(z0), plane(z1), fz)
+    }
+
+    #[test]
+    fn majorants_bound_the_interpolated_density() {
+        let resolution = [7, 5, 3];
+        // an uneven pattern with isolated peaks
+        let density = (0..7 * 5 * 3).map(|i: u32| ((i * 37 + 11) % 17) as f32 / 4.0).collect();
+        let grid = DensityGrid::new(resolution, density, Vec3::ZERO, Vec3::ONE).unwrap();
+        let cells = 4;
+        let majorants = grid.majorants(cells);
+        assert_eq!(majorants.len(), 64);
+
+        let steps = 6;
+        for (i, &majorant) in majorants.iter().enumerate() {
+            let i = i as u32;
+            let cell = Vec3::new((i % cells) as f32, (i / cells % cells) as f32, (i / (cells * cells)) as f32);
+            for s in 0..=steps * steps * steps {
+                let t = Vec3::new((s % (steps + 1)) as f32, (s / (steps + 1) % (steps + 1)) as f32, (s / ((steps + 1) * (steps + 1))) as f32);
+                let p = (cell + t / steps as f32) / cells as f32;
+                if p.cmple(Vec3::ONE).all() {
+                    let d = sample(&grid, p);
+                    assert!(majorant >= d, "cell {cell} majorant {majorant} is below {d} at {p}");
+                }
+            }
+        }
+        assert_eq!(majorants.iter().copied().fold(0.0, f32::max), grid.density.iter().copied().fold(0.0, f32::max));
+    }
+}
@@ -0,0 +1,28 @@
+//! Renders the Cornell box offscreen on a software adapter, the way the
+//! `render` command does on a machine without a GPU or display.
+
+use photonforge_rt::{RenderSettings, Renderer, Scene};
+
+#[test]
+fn renders_cornell_box_on_fallback_adapter() {
+    let (width, height) = (48, 32);
+    let mut renderer = match pollster::block_on(Renderer::new_headless(width, height, true)) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            eprintln!("skipping: {e}");
+            return;
+        }
+    };
+    let scene = Scene::load(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/cornell.toml")).unwrap();
+    renderer.upload_scene(&scene).unwrap();
+    renderer.set_settings(RenderSettings { spp: 2, ..RenderSettings::default() });
+    renderer.set_camera(scene.camera.unwrap_or_default());
+    renderer.accumulate(4, |_| {}).unwrap();
+
+    let image = renderer.read_image().unwrap();
+    assert_eq!((image.width, image.height), (width, height));
+    assert_eq!(image.pixels.len(), (width * height) as usize);
+    assert!(image.pixels.iter().flatten().all(|c| c.is_finite()));
+    // the light and the lit walls must show up
+    assert!(image.pixels.iter().any(|p| p[..3].iter().any(|&c| c > 0.0)));
+}